@@ -0,0 +1,19 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use vault::table;
+
+fuzz_target!(|data: &[u8]| {
+    // the parsers must never panic, and whatever the record table parser
+    // accepts has to survive a round trip through the serializer
+    let _ = table::parse_table(data, data.len());
+
+    if let Ok(entries) = table::parse_record_table(data, 0..usize::MAX) {
+        let mut rebuilt = data[..table::MAGIC_LEN].to_vec();
+        rebuilt.extend_from_slice(&table::encode_u64(entries.len()));
+        for e in entries.iter() {
+            rebuilt.extend_from_slice(&e.serialize());
+        }
+
+        assert_eq!(table::parse_record_table(&rebuilt, 0..usize::MAX).as_ref(), Ok(&entries));
+    }
+});
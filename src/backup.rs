@@ -0,0 +1,77 @@
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+
+use crate::error::VaultError;
+
+
+/// How many old copies of an archive are kept when it is rewritten, unless
+/// told otherwise
+pub const DEFAULT_BACKUPS: usize = 1;
+
+
+/// returns the path of the given backup generation of `path`. Generation 1
+/// is the newest
+pub fn backup_path(path: &str, generation: usize) -> String {
+    match generation {
+        1 => format!("{}.bak", path),
+        _ => format!("{}.bak.{}", path, generation)
+    }
+}
+
+/// creates a temp file next to `path` to write its replacement into. It is
+/// created exclusively, so a stale or planted file is never written through
+pub fn create_temp(path: &str) -> Result<(String, File), VaultError> {
+    let tmp = format!("{}.{}.tmp", path, std::process::id());
+    let _ = std::fs::remove_file(&tmp);
+    let f = OpenOptions::new().write(true).create_new(true).open(&tmp)?;
+    Ok((tmp, f))
+}
+
+/// shifts the backups of `path` back a generation, dropping the oldest, and
+/// keeps the current file as the newest. The current file is hard linked
+/// rather than moved, so `path` never stops existing
+pub fn rotate(path: &str, generations: usize) -> Result<(), VaultError> {
+    if generations == 0 || !Path::new(path).exists() {
+        return Ok(());
+    }
+
+    for gen in (1..generations).rev() {
+        let from = backup_path(path, gen);
+        if Path::new(&from).exists() {
+            std::fs::rename(&from, backup_path(path, gen + 1))?;
+        }
+    }
+
+    let newest = backup_path(path, 1);
+    let _ = std::fs::remove_file(&newest);
+    if std::fs::hard_link(path, &newest).is_err() {
+        // not every filesystem has hard links
+        std::fs::copy(path, &newest)?;
+    }
+    Ok(())
+}
+
+/// moves a fully written and synced temp file over `path`, keeping the old
+/// file as a backup and making sure the rename itself reaches the disk
+pub fn replace(tmp: &str, path: &str, generations: usize) -> Result<(), VaultError> {
+    rotate(path, generations)?;
+    std::fs::rename(tmp, path)?;
+    sync_dir(path)
+}
+
+/// flushes the directory holding `path`, so renames and new files in it
+/// survive a crash
+#[cfg(unix)]
+pub fn sync_dir(path: &str) -> Result<(), VaultError> {
+    let dir = match Path::new(path).parent() {
+        Some(a) if !a.as_os_str().is_empty() => a,
+        _ => Path::new(".")
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn sync_dir(_path: &str) -> Result<(), VaultError> {
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use openssl::rand::rand_bytes;
+use openssl::symm::{Cipher, decrypt_aead, encrypt_aead};
+
+use crate::error::VaultError;
+
+
+/// Length of the nonce stored in each archive header
+pub const NONCE_LEN: usize = 12;
+
+/// Length of the authentication tag appended to the ciphertext
+pub const TAG_LEN: usize = 16;
+
+
+////////////////////////// DEFINITIONS /////////////////////////////////////
+/// The authenticated ciphers an archive can be sealed with
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CipherKind {
+    #[default]
+    Aes256Gcm,
+    ChaCha20Poly1305
+}
+
+
+/////////////////////////// DISPLAY IMPL ////////////////////////////////////
+impl std::fmt::Display for CipherKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CipherKind::Aes256Gcm => write!(f, "aes-256-gcm"),
+            CipherKind::ChaCha20Poly1305 => write!(f, "chacha20-poly1305")
+        }
+    }
+}
+
+
+////////////////////////// CIPHERKIND FUNCTIONS //////////////////////////
+impl CipherKind {
+    /// returns the cipher with the given name
+    pub fn from_name(name: &str) -> Result<Self, VaultError> {
+        match name {
+            "aes-256-gcm" => Ok(CipherKind::Aes256Gcm),
+            "chacha20-poly1305" => Ok(CipherKind::ChaCha20Poly1305),
+            _ => Err(VaultError::Unsupported(format!("unknown cipher '{}'", name)))
+        }
+    }
+
+    /// returns the id we store in the archive header
+    pub fn id(&self) -> u8 {
+        match self {
+            CipherKind::Aes256Gcm => 1,
+            CipherKind::ChaCha20Poly1305 => 2
+        }
+    }
+
+    /// returns the cipher for an id read from an archive header
+    pub fn from_id(id: u8) -> Result<Self, VaultError> {
+        match id {
+            1 => Ok(CipherKind::Aes256Gcm),
+            2 => Ok(CipherKind::ChaCha20Poly1305),
+            _ => Err(VaultError::Unsupported(format!("unknown cipher id {}", id)))
+        }
+    }
+
+    fn openssl_cipher(&self) -> Cipher {
+        match self {
+            CipherKind::Aes256Gcm => Cipher::aes_256_gcm(),
+            CipherKind::ChaCha20Poly1305 => Cipher::chacha20_poly1305()
+        }
+    }
+
+    /// encrypts and authenticates `data`, binding `aad` to it. The tag is
+    /// appended to the returned ciphertext
+    pub fn seal(&self, key: &[u8; 32], nonce: &[u8; NONCE_LEN], aad: &[u8], data: &[u8]) -> Result<Vec<u8>, VaultError> {
+        let mut tag = [0u8; TAG_LEN];
+        let mut out = encrypt_aead(self.openssl_cipher(), key, Some(nonce), aad, data, &mut tag)?;
+        out.extend_from_slice(&tag);
+        Ok(out)
+    }
+
+    /// checks and decrypts data produced by `seal`
+    pub fn open(&self, key: &[u8; 32], nonce: &[u8; NONCE_LEN], aad: &[u8], data: &[u8]) -> Result<Vec<u8>, VaultError> {
+        if data.len() < TAG_LEN {
+            return Err(VaultError::Authentication);
+        }
+        let (cyp, tag) = data.split_at(data.len() - TAG_LEN);
+        match decrypt_aead(self.openssl_cipher(), key, Some(nonce), aad, cyp, tag) {
+            Ok(a) => Ok(a),
+            Err(_) => Err(VaultError::Authentication)
+        }
+    }
+}
+
+/// generates a fresh random nonce
+pub fn new_nonce() -> Result<[u8; NONCE_LEN], VaultError> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand_bytes(&mut nonce)?;
+    Ok(nonce)
+}
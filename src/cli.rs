@@ -0,0 +1,917 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+use colored::Colorize;
+use std::io::prelude::*;
+
+use vault::{Capture, CipherKind, Codec, Datafile, EncFile, EntryKind, ExtractOptions, KdfParams, Overwrite, Restore, VaultError};
+use vault::backup::DEFAULT_BACKUPS;
+use vault::extract;
+use vault::password::PasswordSource;
+use vault::path;
+use vault::tree::{self, Filter, Summary};
+use vault::verify::{self, Problem};
+
+
+/// Exit code for bad arguments, or names that don't exist in the archive
+pub const EXIT_USAGE: i32 = 2;
+/// Exit code for a wrong password or an archive that failed authentication
+pub const EXIT_AUTH: i32 = 3;
+/// Exit code for archives that are corrupt or in a format we can't read
+pub const EXIT_CORRUPT: i32 = 4;
+/// Exit code for failures reading or writing files
+pub const EXIT_IO: i32 = 5;
+/// Exit code for archives another process has open, or changed under us
+pub const EXIT_BUSY: i32 = 6;
+
+/// Describes the exit codes in `--help`
+const EXIT_HELP: &str = "EXIT CODES:
+    0    success
+    1    any other failure
+    2    bad arguments, or a name that is missing from or already in the archive
+    3    wrong password, or the archive failed authentication
+    4    the archive is corrupt, verify found a problem, recover lost entries, or
+         the archive uses a format this build can't read
+    5    reading or writing a file failed
+    6    the archive is open in another process, or changed while in use";
+
+
+/// returns the arguments that pick the crypto settings for a new archive
+pub fn crypto_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("kdf")
+            .long("kdf")
+            .value_name("KDF")
+            .takes_value(true)
+            .possible_values(&["argon2id", "scrypt"])
+            .help("Key derivation function used for a new archive"),
+        Arg::with_name("cipher")
+            .long("cipher")
+            .value_name("CIPHER")
+            .takes_value(true)
+            .possible_values(&["aes-256-gcm", "chacha20-poly1305"])
+            .help("Authenticated cipher used for a new archive"),
+        Arg::with_name("calibrate")
+            .long("calibrate")
+            .value_name("MILLIS")
+            .takes_value(true)
+            .help("Tunes the KDF cost of a new archive to take about MILLIS to unlock on this machine")
+    ]
+}
+
+/// figures out the KDF and cipher picked by the arguments from `crypto_args`
+pub fn crypto_settings(matches: &ArgMatches) -> Result<(KdfParams, CipherKind), String> {
+    let mut kdf = match KdfParams::from_name(matches.value_of("kdf").unwrap_or("argon2id")) {
+        Ok(a) => a,
+        Err(e) => return Err(format!("Invalid KDF: {}", e))
+    };
+    let cipher = match CipherKind::from_name(matches.value_of("cipher").unwrap_or("aes-256-gcm")) {
+        Ok(a) => a,
+        Err(e) => return Err(format!("Invalid cipher: {}", e))
+    };
+
+    if let Some(millis) = matches.value_of("calibrate") {
+        let millis = match millis.parse::<u64>() {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Invalid calibration time: {}", e))
+        };
+        eprintln!("{}", "[ ] Calibrating key derivation...".yellow());
+        kdf = match kdf.calibrate(std::time::Duration::from_millis(millis)) {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Failed to calibrate KDF: {}", e))
+        };
+        eprintln!("{}: {}", "[+] Using".green(), kdf);
+    }
+
+    Ok((kdf, cipher))
+}
+
+/// returns the arguments that pick where the archive's password comes from
+pub fn password_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("password-fd")
+            .long("password-fd")
+            .value_name("N")
+            .takes_value(true)
+            .global(true)
+            .help("Reads the password from file descriptor N"),
+        Arg::with_name("password-file")
+            .long("password-file")
+            .value_name("PATH")
+            .takes_value(true)
+            .global(true)
+            .help("Reads the password from the first line of PATH"),
+        Arg::with_name("password-env")
+            .long("password-env")
+            .value_name("VAR")
+            .takes_value(true)
+            .global(true)
+            .help("Reads the password from the environment variable VAR"),
+        Arg::with_name("password-command")
+            .long("password-command")
+            .value_name("CMD")
+            .takes_value(true)
+            .global(true)
+            .help("Runs CMD with sh and uses the first line it prints as the password")
+    ]
+}
+
+/// returns the arguments that pick where `passwd` gets the new password
+/// from, so it can be changed without a terminal
+fn new_password_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("new-password-fd")
+            .long("new-password-fd")
+            .value_name("N")
+            .takes_value(true)
+            .help("Reads the new password from file descriptor N"),
+        Arg::with_name("new-password-file")
+            .long("new-password-file")
+            .value_name("PATH")
+            .takes_value(true)
+            .help("Reads the new password from the first line of PATH"),
+        Arg::with_name("new-password-env")
+            .long("new-password-env")
+            .value_name("VAR")
+            .takes_value(true)
+            .help("Reads the new password from the environment variable VAR"),
+        Arg::with_name("new-password-command")
+            .long("new-password-command")
+            .value_name("CMD")
+            .takes_value(true)
+            .help("Runs CMD with sh and uses the first line it prints as the new password")
+    ]
+}
+
+/// figures out where the password comes from, warning about insecure sources
+pub fn password_source(matches: &ArgMatches) -> Result<PasswordSource, String> {
+    match given_source(matches, "password")? {
+        Some(a) => Ok(a),
+        None => Ok(PasswordSource::Prompt(format!("{}", "[ ] Enter password > ".green())))
+    }
+}
+
+/// figures out where `passwd` gets the new password from, if not a prompt.
+/// It can't be a descriptor `current` has already used up
+fn new_password_source(matches: &ArgMatches, current: &PasswordSource) -> Result<Option<PasswordSource>, String> {
+    let source = given_source(matches, "new-password")?;
+    match (&source, current) {
+        (Some(PasswordSource::Fd(a)), PasswordSource::Fd(b)) if a == b && !current.can_read_again() => {
+            Err(format!("File descriptor {} is closed once the current password is read from it, so it can't give the new one too", a))
+        },
+        _ => Ok(source)
+    }
+}
+
+/// returns the source picked by the `--<prefix>-*` options, if any, warning
+/// about insecure ones
+fn given_source(matches: &ArgMatches, prefix: &str) -> Result<Option<PasswordSource>, String> {
+    let mut sources: Vec<PasswordSource> = Vec::new();
+    if let Some(fd) = matches.value_of(format!("{}-fd", prefix)) {
+        match fd.parse::<i32>() {
+            Ok(a) => sources.push(PasswordSource::Fd(a)),
+            Err(e) => return Err(format!("Invalid file descriptor: {}", e))
+        };
+    }
+    if let Some(path) = matches.value_of(format!("{}-file", prefix)) {
+        sources.push(PasswordSource::File(path.into()));
+    }
+    if let Some(var) = matches.value_of(format!("{}-env", prefix)) {
+        sources.push(PasswordSource::Env(var.to_string()));
+    }
+    if let Some(cmd) = matches.value_of(format!("{}-command", prefix)) {
+        sources.push(PasswordSource::Command(cmd.to_string()));
+    }
+
+    let source = match sources.len() {
+        0 => return Ok(None),
+        1 => sources.remove(0),
+        _ => return Err(format!("Only one --{}-* option can be used at a time", prefix))
+    };
+
+    for warning in source.warnings() {
+        eprintln!("{} {}", "[!]".yellow(), warning);
+    }
+    Ok(Some(source))
+}
+
+/// returns the arguments that control how files are stored and the archive is saved
+pub fn save_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("backups")
+            .long("backups")
+            .value_name("N")
+            .takes_value(true)
+            .global(true)
+            .help("Keeps N old copies of the archive as .bak files when it is rewritten (default 1)"),
+        Arg::with_name("compress")
+            .long("compress")
+            .value_name("CODEC")
+            .takes_value(true)
+            .global(true)
+            .possible_values(&["none", "zstd", "deflate"])
+            .help("Compresses added files with CODEC, unless they don't get smaller (default none)")
+    ]
+}
+
+/// figures out how many backups the arguments from `save_args` ask for
+pub fn backups(matches: &ArgMatches) -> Result<usize, String> {
+    match matches.value_of("backups") {
+        Some(a) => match a.parse::<usize>() {
+            Ok(a) => Ok(a),
+            Err(e) => Err(format!("Invalid backup count: {}", e))
+        },
+        None => Ok(DEFAULT_BACKUPS)
+    }
+}
+
+/// figures out the codec the arguments from `save_args` pick for added files
+pub fn codec(matches: &ArgMatches) -> Result<Codec, String> {
+    match Codec::from_name(matches.value_of("compress").unwrap_or("none")) {
+        Ok(a) => Ok(a),
+        Err(e) => Err(format!("Invalid codec: {}", e))
+    }
+}
+
+/// returns the arguments that pick which files of a tree get added or
+/// extracted
+fn filter_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("include")
+            .long("include")
+            .value_name("GLOB")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Only takes files matching GLOB. Can be given more than once"),
+        Arg::with_name("exclude")
+            .long("exclude")
+            .value_name("GLOB")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Leaves out files and directories matching GLOB. Can be given more than once")
+    ]
+}
+
+/// builds the filter the arguments from `filter_args` describe
+fn filter(matches: &ArgMatches) -> Result<Filter, VaultError> {
+    let include: Vec<&str> = matches.values_of("include").into_iter().flatten().collect();
+    let exclude: Vec<&str> = matches.values_of("exclude").into_iter().flatten().collect();
+    Filter::new(&include, &exclude)
+}
+
+/// returns the arguments that pick which optional metadata gets recorded
+/// for files added from disk
+fn capture_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("xattrs")
+            .long("xattrs")
+            .help("Also records extended attributes"),
+        Arg::with_name("acls")
+            .long("acls")
+            .help("Also records POSIX ACLs")
+    ]
+}
+
+/// figures out what the arguments from `capture_args` ask to record
+fn capture(matches: &ArgMatches) -> Capture {
+    Capture{xattrs: matches.is_present("xattrs"), acls: matches.is_present("acls")}
+}
+
+/// returns the arguments that pick which metadata gets put back on
+/// extracted files
+fn restore_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("preserve")
+            .short("p")
+            .long("preserve")
+            .help("Restores permissions and modification and access times"),
+        Arg::with_name("same-owner")
+            .long("same-owner")
+            .help("Restores the owner and group, by name if this machine has it and by id if not. Usually needs root"),
+        Arg::with_name("xattrs")
+            .long("xattrs")
+            .help("Restores extended attributes"),
+        Arg::with_name("acls")
+            .long("acls")
+            .help("Restores POSIX ACLs")
+    ]
+}
+
+/// figures out what the arguments from `restore_args` ask to put back
+fn restore(matches: &ArgMatches) -> Restore {
+    Restore{
+        permissions: matches.is_present("preserve"),
+        owner: matches.is_present("same-owner"),
+        times: matches.is_present("preserve"),
+        xattrs: matches.is_present("xattrs"),
+        acls: matches.is_present("acls")
+    }
+}
+
+/// returns the argument that picks what happens to files already where
+/// something gets extracted to
+fn overwrite_arg() -> Arg<'static, 'static> {
+    Arg::with_name("overwrite")
+        .long("overwrite")
+        .value_name("POLICY")
+        .takes_value(true)
+        .possible_values(&["never", "ask", "always", "newer"])
+        .help("Whether to replace files that are already there: never, ask, always, or newer for only when the archived one was modified later (default never)")
+}
+
+/// figures out how extracted entries get written from the arguments from
+/// `restore_args` and `overwrite_arg`
+fn extract_options(matches: &ArgMatches) -> ExtractOptions {
+    ExtractOptions{
+        restore: restore(matches),
+        unsafe_links: matches.is_present("unsafe-links"),
+        overwrite: matches.value_of("overwrite").and_then(Overwrite::from_name).unwrap_or_default(),
+        ask: ask_overwrite
+    }
+}
+
+/// copies `reader` into a new file at `out` the way `get -o` writes one:
+/// what is there is only replaced if `opts.overwrite` says so, and a symlink
+/// there is never followed
+fn write_new<R: Read>(reader: &mut R, out: &str, opts: &ExtractOptions, mtime: Option<vault::meta::Timestamp>) -> Result<(), VaultError> {
+    let path = std::path::Path::new(out);
+    if !extract::make_room(path, opts, mtime)? {
+        return Err(VaultError::AlreadyExists(out.to_string()));
+    }
+    let mut f = extract::create_new(path, None, &opts.restore)?;
+    // a file we created but couldn't fill is of no use to anyone
+    if let Err(e) = std::io::copy(reader, &mut f) {
+        drop(f);
+        let _ = std::fs::remove_file(path);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// asks on the terminal whether to replace what is at `path`. Anything but
+/// yes, including not getting an answer, is a no
+pub fn ask_overwrite(path: &std::path::Path) -> bool {
+    eprint!("{} {} already exists. Replace it? [y/N] ", "[?]".yellow(), path.display());
+    let _ = std::io::stderr().flush();
+    let mut line = String::new();
+    match std::io::stdin().read_line(&mut line) {
+        Ok(_) => matches!(line.trim(), "y" | "Y" | "yes"),
+        Err(_) => false
+    }
+}
+
+/// describes an entry for `ls`. The long form puts the SHA-256 of its data
+/// in front, which for hard links is the digest of the file they point at
+fn listing(dfile: &Datafile, f: &EncFile, long: bool) -> String {
+    if !long {
+        return f.to_string();
+    }
+    match dfile.recorded_digest(f) {
+        Some(a) => format!("{}  {}", verify::hex(&a), f),
+        None => format!("{:<64}  {}", "-", f)
+    }
+}
+
+/// prints how far along adding or extracting a tree is
+pub fn progress(done: usize, total: usize, name: &[u8]) {
+    eprintln!("{} {}", format!("[{}/{}]", done, total).green(), String::from_utf8_lossy(name));
+}
+
+/// warns about anything a tree had that couldn't be added
+pub fn warn_skipped(summary: &Summary) {
+    for (p, reason) in summary.skipped.iter() {
+        eprintln!("{} Skipped {}: {}", "[!]".yellow(), p.display(), reason);
+    }
+}
+
+/// warns if the last save to the archive never finished
+pub fn warn_torn(dfile: &Datafile) {
+    if dfile.torn_tail() > 0 {
+        eprintln!("{} Ignoring {} bytes left by an interrupted save", "[!]".yellow(), dfile.torn_tail());
+    }
+}
+
+/// returns the non-interactive subcommands
+pub fn subcommands() -> Vec<App<'static, 'static>> {
+    let archive = || Arg::with_name("ARCHIVE")
+        .required(true)
+        .help("Path to the archive");
+
+    vec![
+        SubCommand::with_name("create")
+            .about("Creates a new, empty archive")
+            .arg(archive())
+            .args(&crypto_args()),
+        SubCommand::with_name("ls")
+            .about("Lists all the files in an archive, or the ones in a directory of it")
+            .arg(archive())
+            .arg(Arg::with_name("DIR")
+                .help("Directory in the archive to list"))
+            .arg(Arg::with_name("long")
+                .short("l")
+                .long("long")
+                .help("Also shows the SHA-256 of each file, or - for files saved before digests were recorded")),
+        SubCommand::with_name("add")
+            .about("Adds files to an archive")
+            .arg(archive())
+            .arg(Arg::with_name("FILE")
+                .required(true)
+                .multiple(true)
+                .help("Files to add, stored under their file name"))
+            .arg(Arg::with_name("dir")
+                .long("dir")
+                .value_name("DIR")
+                .takes_value(true)
+                .help("Directory in the archive to add the files to"))
+            .arg(Arg::with_name("recursive")
+                .short("r")
+                .long("recursive")
+                .help("Adds directories along with everything in them"))
+            .arg(Arg::with_name("dereference")
+                .short("L")
+                .long("dereference")
+                .help("Adds what symlinks point at instead of the links themselves"))
+            .args(&filter_args())
+            .args(&capture_args()),
+        SubCommand::with_name("extract")
+            .about("Extracts a directory of an archive, or all of it, along with everything in it")
+            .arg(archive())
+            .arg(Arg::with_name("PREFIX")
+                .help("File or directory in the archive to extract (defaults to everything)"))
+            .arg(Arg::with_name("dest")
+                .short("C")
+                .long("directory")
+                .value_name("DEST")
+                .takes_value(true)
+                .help("Directory to extract into (defaults to the current one)"))
+            .arg(Arg::with_name("unsafe-links")
+                .long("unsafe-links")
+                .help("Also creates symlinks that are absolute or go through .., which are skipped otherwise"))
+            .arg(overwrite_arg())
+            .args(&filter_args())
+            .args(&restore_args()),
+        SubCommand::with_name("mkdir")
+            .about("Creates directories in an archive")
+            .arg(archive())
+            .arg(Arg::with_name("DIR")
+                .required(true)
+                .multiple(true)
+                .help("Paths of the directories to create")),
+        SubCommand::with_name("get")
+            .about("Fetches a file from an archive")
+            .arg(archive())
+            .arg(Arg::with_name("NAME")
+                .required(true)
+                .help("Name of the file in the archive"))
+            .arg(Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("PATH")
+                .takes_value(true)
+                .help("Where to write the file (defaults to stdout)"))
+            .arg(overwrite_arg())
+            .args(&restore_args()),
+        SubCommand::with_name("cat")
+            .about("Prints some or all of a file from an archive, without extracting the rest")
+            .arg(archive())
+            .arg(Arg::with_name("NAME")
+                .required(true)
+                .help("Name of the file in the archive"))
+            .arg(Arg::with_name("range")
+                .short("r")
+                .long("range")
+                .value_name("START:END")
+                .takes_value(true)
+                .help("Byte range to print. Either end can be left out, and END is exclusive"))
+            .arg(Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("PATH")
+                .takes_value(true)
+                .help("Where to write the bytes (defaults to stdout)"))
+            .arg(overwrite_arg()),
+        SubCommand::with_name("rm")
+            .about("Removes files from an archive")
+            .arg(archive())
+            .arg(Arg::with_name("NAME")
+                .required(true)
+                .multiple(true)
+                .help("Names of the files to remove")),
+        SubCommand::with_name("mv")
+            .about("Renames a file in an archive")
+            .arg(archive())
+            .arg(Arg::with_name("NAME")
+                .required(true)
+                .help("Current name of the file"))
+            .arg(Arg::with_name("NEW_NAME")
+                .required(true)
+                .help("New name for the file")),
+        SubCommand::with_name("stats")
+            .about("Shows how much space an archive's files take up, and how much deduplication and compression saved")
+            .arg(archive()),
+        SubCommand::with_name("verify")
+            .alias("fsck")
+            .about("Checks that every record of an archive authenticates and every file's data is intact, printing a report with one tab separated line per problem")
+            .arg(archive())
+            .arg(Arg::with_name("against")
+                .long("against")
+                .value_name("DIR")
+                .takes_value(true)
+                .help("Compares the archive with DIR instead, as if it had been extracted there")),
+        SubCommand::with_name("recover")
+            .about("Salvages what is intact of a damaged archive into a new one, printing a tab separated report of what was lost")
+            .arg(archive())
+            .arg(Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("PATH")
+                .takes_value(true)
+                .required(true)
+                .help("Where to write the new archive, which mustn't exist yet")),
+        SubCommand::with_name("manifest")
+            .about("Prints the SHA-256 of every file in an archive in the format of sha256sum, so `sha256sum -c` can check an extracted copy")
+            .arg(archive()),
+        SubCommand::with_name("compact")
+            .about("Rewrites an archive without the space left over from removed and replaced files")
+            .arg(archive()),
+        SubCommand::with_name("passwd")
+            .about("Changes the password of an archive, asking for the new one twice unless a --new-password-* option says where it comes from")
+            .arg(archive())
+            .args(&new_password_args())
+    ].into_iter().map(|a| a.after_help(EXIT_HELP)).collect()
+}
+
+/// parses a byte range written as START:END, where either end can be left out
+pub fn parse_range(range: &str) -> Result<(u64, Option<u64>), String> {
+    let (start, end) = match range.find(':') {
+        Some(a) => (&range[..a], &range[a+1..]),
+        None => return Err(format!("Invalid range '{}': expected START:END", range))
+    };
+    let start = match start.trim() {
+        "" => 0,
+        a => match a.parse::<u64>() {
+            Ok(b) => b,
+            Err(e) => return Err(format!("Invalid range start '{}': {}", a, e))
+        }
+    };
+    let end = match end.trim() {
+        "" => None,
+        a => match a.parse::<u64>() {
+            Ok(b) => Some(b),
+            Err(e) => return Err(format!("Invalid range end '{}': {}", a, e))
+        }
+    };
+    if let Some(end) = end {
+        if end < start {
+            return Err(format!("Invalid range '{}': END comes before START", range));
+        }
+    }
+    Ok((start, end))
+}
+
+/// asks for a password on stderr, so prompts never mix with file data on stdout
+fn ask_pass(msg: &str) -> Result<String, VaultError> {
+    PasswordSource::Prompt(format!("{}", msg.green())).read()
+}
+
+/// turns a path given on the command line into a path in the archive. It
+/// starts at the root whether or not it has a leading `/`
+fn archive_path(name: &str) -> Result<Vec<u8>, VaultError> {
+    path::resolve(&[], name.as_bytes())
+}
+
+/// maps a library error to the exit code we report for it
+fn exit_code(e: &VaultError) -> i32 {
+    match e {
+        VaultError::WrongPassword | VaultError::Authentication => EXIT_AUTH,
+        VaultError::Corrupt(_) | VaultError::Table(_) | VaultError::Unsupported(_) => EXIT_CORRUPT,
+        VaultError::NotFound(_) | VaultError::AlreadyExists(_) | VaultError::InvalidName(_) |
+        VaultError::IsDirectory(_) | VaultError::NotADirectory(_) | VaultError::NotAFile(_) | VaultError::NotEmpty(_) |
+        VaultError::InvalidPattern(_) => EXIT_USAGE,
+        VaultError::Io(_) => EXIT_IO,
+        VaultError::Locked(_) | VaultError::ReadOnly | VaultError::Changed(_) => EXIT_BUSY,
+        VaultError::Kdf(_) | VaultError::Crypto(_) => 1
+    }
+}
+
+/// prints an error and returns its exit code
+fn fail(what: &str, e: VaultError) -> i32 {
+    eprintln!("{}: {}", format!("[-] {}", what).red(), e);
+    exit_code(&e)
+}
+
+/// runs a subcommand to completion, saving the archive if it changed, and
+/// returns the process exit code
+pub fn run(name: &str, matches: &ArgMatches) -> i32 {
+    let path = matches.value_of("ARCHIVE").unwrap_or_default().to_string();
+    let (new_source, source) = match password_source(matches).and_then(|a| Ok((new_password_source(matches, &a)?, a))) {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("{} {}", "[-]".red(), e);
+            return EXIT_USAGE;
+        }
+    };
+    let (backups, codec) = match backups(matches).and_then(|b| Ok((b, codec(matches)?))) {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("{} {}", "[-]".red(), e);
+            return EXIT_USAGE;
+        }
+    };
+
+    // creating an archive is the only command that doesn't need to open one
+    if name == "create" {
+        let (kdf, cipher) = match crypto_settings(matches) {
+            Ok(a) => a,
+            Err(e) => {
+                eprintln!("{} {}", "[-]".red(), e);
+                return EXIT_USAGE;
+            }
+        };
+        return match source.read().and_then(|pass| Datafile::setup_new(pass, path, kdf, cipher)) {
+            Ok(_) => 0,
+            Err(e) => fail("Failed to create new data file", e)
+        };
+    }
+
+    // recovering reads the archive record by record, so it can't be opened
+    // the usual way
+    if name == "recover" {
+        let out = matches.value_of("output").unwrap_or_default().to_string();
+        return match source.read().and_then(|pass| Datafile::recover(path, pass, out.clone())) {
+            Ok(report) => {
+                println!("{}", report);
+                eprintln!("{} {} entries into {}", "[+] Recovered".green(), report.recovered, out);
+                match report.is_complete() {
+                    true => 0,
+                    false => EXIT_CORRUPT
+                }
+            },
+            Err(e) => fail("Failed to recover archive", e)
+        };
+    }
+
+    // commands that only read can run while someone else has the archive open
+    let open = match name {
+        "ls" | "get" | "cat" | "stats" | "extract" | "verify" | "manifest" => Datafile::checked_new_read_only,
+        _ => Datafile::checked_new
+    };
+    let mut dfile = match source.read().and_then(|pass| open(path, pass)) {
+        Ok(a) => a,
+        // an archive that won't open is a problem too, as far as the report
+        // goes
+        Err(e) if name == "verify" => {
+            println!("{}\nstatus\tfailed", Problem{offset: None, entry: None, message: e.to_string()});
+            return fail("Failed to read data file", e);
+        },
+        Err(e) => return fail("Failed to read data file", e)
+    };
+    dfile.set_backups(backups);
+    dfile.set_codec(codec);
+    dfile.set_capture(capture(matches));
+    warn_torn(&dfile);
+
+    let changed = match name {
+        "ls" => {
+            let long = matches.is_present("long");
+            match matches.value_of("DIR") {
+                Some(dir) => archive_path(dir).and_then(|dir| {
+                    for entry in dfile.read_dir(&dir)? {
+                        match entry.file {
+                            Some(f) => println!("{}", listing(&dfile, f, long)),
+                            None if long => println!("{:<64}  {}/", "-", String::from_utf8_lossy(&path::join(&dir, &entry.name))),
+                            None => println!("{}/", String::from_utf8_lossy(&path::join(&dir, &entry.name)))
+                        };
+                    }
+                    Ok(false)
+                }),
+                None => {
+                    for file in dfile.files() {
+                        println!("{}", listing(&dfile, file, long));
+                    }
+                    Ok(false)
+                }
+            }
+        },
+        "add" => {
+            let dir = match archive_path(matches.value_of("dir").unwrap_or_default()) {
+                Ok(a) => a,
+                Err(e) => return fail("Invalid directory", e)
+            };
+            let filter = match filter(matches) {
+                Ok(a) => a,
+                Err(e) => return fail("add failed", e)
+            };
+            let follow = matches.is_present("dereference");
+            let mut res = Ok(true);
+            for file in matches.values_of("FILE").into_iter().flatten() {
+                let src = std::path::Path::new(file);
+                let is_dir = match follow {
+                    true => src.is_dir(),
+                    false => src.symlink_metadata().map(|m| m.is_dir()).unwrap_or(false)
+                };
+                if is_dir {
+                    if !matches.is_present("recursive") {
+                        eprintln!("{} {} is a directory, pass -r to add it along with everything in it", "[-]".red(), file);
+                        return EXIT_USAGE;
+                    }
+                    match tree::add_tree(&mut dfile, src, &dir, &filter, follow, &mut progress) {
+                        Ok(summary) => warn_skipped(&summary),
+                        Err(e) => {
+                            res = Err(e);
+                            break;
+                        }
+                    };
+                    continue;
+                }
+                let fname = match std::path::Path::new(file).file_name() {
+                    Some(a) => path::join(&dir, a.to_string_lossy().as_bytes()),
+                    None => {
+                        res = Err(VaultError::InvalidName(file.to_string()));
+                        break;
+                    }
+                };
+                if let Err(e) = tree::add_one(&mut dfile, src, fname, follow) {
+                    res = Err(e);
+                    break;
+                }
+            }
+            res
+        },
+        "extract" => {
+            let res = archive_path(matches.value_of("PREFIX").unwrap_or_default()).and_then(|prefix| {
+                let dest = std::path::Path::new(matches.value_of("dest").unwrap_or("."));
+                tree::extract_tree(&dfile, &prefix, dest, &filter(matches)?, &extract_options(matches), &mut progress)
+            });
+            return match res {
+                Ok(summary) => {
+                    warn_skipped(&summary);
+                    eprintln!("{} {} files", "[+] Extracted".green(), summary.files);
+                    0
+                },
+                Err(e) => fail("extract failed", e)
+            };
+        },
+        "mkdir" => {
+            let mut res = Ok(true);
+            for dir in matches.values_of("DIR").into_iter().flatten() {
+                if let Err(e) = archive_path(dir).and_then(|a| dfile.mkdir(a)) {
+                    res = Err(e);
+                    break;
+                }
+            }
+            res
+        },
+        "get" => {
+            let fname = match archive_path(matches.value_of("NAME").unwrap_or_default()) {
+                Ok(a) => a,
+                Err(e) => return fail("get failed", e)
+            };
+            match dfile.find(&fname) {
+                Some(file) => match matches.value_of("output") {
+                    Some(out) if out != "-" => dfile.save_to_file(file, out, &extract_options(matches)),
+                    _ => {
+                        let stdout = std::io::stdout();
+                        let mut handle = stdout.lock();
+                        match dfile.write_entry(file, &mut handle) {
+                            Ok(_) => handle.flush().map_err(VaultError::Io),
+                            Err(e) => Err(e)
+                        }
+                    }
+                }.map(|_| false),
+                None => Err(VaultError::NotFound(String::from_utf8_lossy(&fname).to_string()))
+            }
+        },
+        "cat" => {
+            let fname = match archive_path(matches.value_of("NAME").unwrap_or_default()) {
+                Ok(a) => a,
+                Err(e) => return fail("cat failed", e)
+            };
+            let (start, end) = match parse_range(matches.value_of("range").unwrap_or(":")) {
+                Ok(a) => a,
+                Err(e) => {
+                    eprintln!("{} {}", "[-]".red(), e);
+                    return EXIT_USAGE;
+                }
+            };
+            let mut reader = match dfile.open_entry(&fname) {
+                Ok(a) => a,
+                Err(e) => return fail("cat failed", e)
+            };
+            if start > reader.len() {
+                eprintln!("{} Range starts past the end of the file ({} bytes)", "[-]".red(), reader.len());
+                return EXIT_USAGE;
+            }
+            let len = std::cmp::min(end.unwrap_or(u64::MAX), reader.len()) - start;
+            let mtime = dfile.find(&fname).and_then(|f| f.get_metadata()).map(|m| m.mtime);
+
+            match reader.seek(std::io::SeekFrom::Start(start)) {
+                Ok(_) => match matches.value_of("output") {
+                    Some(out) if out != "-" => write_new(&mut reader.take(len), out, &extract_options(matches), mtime),
+                    _ => {
+                        let stdout = std::io::stdout();
+                        let mut handle = stdout.lock();
+                        std::io::copy(&mut reader.take(len), &mut handle)
+                            .and_then(|_| handle.flush())
+                            .map_err(VaultError::from)
+                    }
+                },
+                Err(e) => Err(e.into())
+            }.map(|_| false)
+        },
+        "rm" => {
+            let mut res = Ok(true);
+            for fname in matches.values_of("NAME").into_iter().flatten() {
+                if let Err(e) = archive_path(fname).and_then(|a| dfile.remove(&a)) {
+                    res = Err(e);
+                    break;
+                }
+            }
+            res
+        },
+        "mv" => {
+            let old = matches.value_of("NAME").unwrap_or_default();
+            let new = matches.value_of("NEW_NAME").unwrap_or_default();
+            archive_path(old).and_then(|old| dfile.rename(&old, archive_path(new)?)).map(|_| true)
+        },
+        "stats" => {
+            return match dfile.stats() {
+                Ok(stats) => {
+                    println!("{}", stats);
+                    0
+                },
+                Err(e) => fail("Failed to read archive stats", e)
+            };
+        },
+        "verify" => {
+            let res = match matches.value_of("against") {
+                Some(dir) => tree::compare_tree(&dfile, std::path::Path::new(dir)),
+                None => dfile.verify()
+            };
+            return match res {
+                Ok(report) => {
+                    println!("{}", report);
+                    match report.is_ok() {
+                        true => 0,
+                        false => EXIT_CORRUPT
+                    }
+                },
+                Err(e) => fail("Failed to verify archive", e)
+            };
+        },
+        "manifest" => {
+            // hard links are files of their own once extracted
+            let stdout = std::io::stdout();
+            let mut handle = stdout.lock();
+            let mut res = Ok(false);
+            for file in dfile.files().iter().filter(|f| f.is_file() || f.get_kind() == EntryKind::Hardlink) {
+                let line = dfile.digest_of(file)
+                    .and_then(|d| Ok(writeln!(handle, "{}", verify::manifest_line(&d, &file.get_fname()))?));
+                if let Err(e) = line {
+                    res = Err(e);
+                    break;
+                }
+            }
+            res
+        },
+        "compact" => {
+            // compacting writes the archive itself, so there is nothing left to save
+            return match dfile.compact() {
+                Ok(freed) => {
+                    println!("Freed {} bytes", freed);
+                    0
+                },
+                Err(e) => fail("Failed to compact archive", e)
+            };
+        },
+        "passwd" => {
+            // a script says what it means, so there is nothing to repeat
+            if let Some(new) = new_source {
+                new.read().and_then(|a| dfile.update_pass(a)).map(|_| true)
+            } else {
+                match (ask_pass("[ ] Enter new password > "), ask_pass("[ ] Repeat new password > ")) {
+                    (Ok(a), Ok(b)) if a == b => dfile.update_pass(a).map(|_| true),
+                    (Ok(_), Ok(_)) => {
+                        eprintln!("{}", "[-] Passwords do not match".red());
+                        return EXIT_USAGE;
+                    },
+                    (Err(e), _) | (_, Err(e)) => Err(e)
+                }
+            }
+        },
+        _ => {
+            eprintln!("{}: {}", "[-] Unknown command".red(), name);
+            return EXIT_USAGE;
+        }
+    };
+
+    // only write the archive back out if the command changed something
+    match changed {
+        Ok(true) => match dfile.save_in_place() {
+            Ok(_) => 0,
+            Err(e) => fail("Failed to save file", e)
+        },
+        Ok(false) => 0,
+        Err(e) => fail(&format!("{} failed", name), e)
+    }
+}
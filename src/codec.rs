@@ -0,0 +1,178 @@
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom, Write};
+
+use crate::error::VaultError;
+
+
+////////////////////////// DEFINITIONS /////////////////////////////////////
+/// The codecs an entry's data can be compressed with before it is sealed
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Codec {
+    #[default]
+    None,
+    Zstd,
+    Deflate
+}
+
+/// A decompressor over the stored data of an entry
+enum Decoder<R: Read> {
+    Plain(R),
+    Zstd(zstd::stream::read::Decoder<'static, BufReader<R>>),
+    Deflate(flate2::read::DeflateDecoder<R>)
+}
+
+/// Reads the original data of a compressed entry. Compressed data can only
+/// be read front to back, so seeking forward decompresses and throws away
+/// everything in between, and seeking backward starts over
+pub struct DecodeReader<R: Read + Seek> {
+    codec: Codec,
+    inner: Option<Decoder<R>>,
+    pos: u64
+}
+
+
+/////////////////////////// DISPLAY IMPL ////////////////////////////////////
+impl std::fmt::Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Codec::None => write!(f, "none"),
+            Codec::Zstd => write!(f, "zstd"),
+            Codec::Deflate => write!(f, "deflate")
+        }
+    }
+}
+
+
+////////////////////////// CODEC FUNCTIONS //////////////////////////
+impl Codec {
+    /// returns the codec with the given name
+    pub fn from_name(name: &str) -> Result<Self, VaultError> {
+        match name {
+            "none" => Ok(Codec::None),
+            "zstd" => Ok(Codec::Zstd),
+            "deflate" => Ok(Codec::Deflate),
+            _ => Err(VaultError::Unsupported(format!("unknown codec '{}'", name)))
+        }
+    }
+
+    /// returns the id we store in the file table
+    pub fn id(&self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Zstd => 1,
+            Codec::Deflate => 2
+        }
+    }
+
+    /// returns the codec for an id read from the file table
+    pub fn from_id(id: u8) -> Result<Self, VaultError> {
+        match id {
+            0 => Ok(Codec::None),
+            1 => Ok(Codec::Zstd),
+            2 => Ok(Codec::Deflate),
+            _ => Err(VaultError::Unsupported(format!("unknown codec id {}", id)))
+        }
+    }
+
+    /// compresses everything in `input` into `out`, returning the writer
+    /// along with how many bytes were read
+    pub fn compress<R: Read, W: Write>(&self, input: &mut R, mut out: W) -> Result<(W, u64), VaultError> {
+        match self {
+            Codec::None => {
+                let read = std::io::copy(input, &mut out)?;
+                Ok((out, read))
+            },
+            Codec::Zstd => {
+                let mut enc = zstd::stream::write::Encoder::new(out, 0)?;
+                let read = std::io::copy(input, &mut enc)?;
+                Ok((enc.finish()?, read))
+            },
+            Codec::Deflate => {
+                let mut enc = flate2::write::DeflateEncoder::new(out, flate2::Compression::default());
+                let read = std::io::copy(input, &mut enc)?;
+                Ok((enc.finish()?, read))
+            }
+        }
+    }
+
+    /// decompresses `data`, which should come to `len` bytes. Never makes
+    /// more than a byte past that, so a bad length can't eat all our memory
+    pub fn decompress(&self, data: &[u8], len: usize) -> Result<Vec<u8>, VaultError> {
+        let mut out: Vec<u8> = Vec::with_capacity(len);
+        DecodeReader::new(*self, Cursor::new(data))?.take(len as u64 + 1).read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    /// returns true if compressing `len` bytes down to `compressed` saves
+    /// enough to be worth decompressing it every time it's read
+    pub fn worth_it(len: u64, compressed: u64) -> bool {
+        compressed + len / 16 < len
+    }
+}
+
+
+////////////////////////// DECODEREADER FUNCTIONS //////////////////////////
+impl<R: Read + Seek> DecodeReader<R> {
+    /// starts decompressing the stored data `inner` holds
+    pub fn new(codec: Codec, mut inner: R) -> Result<Self, VaultError> {
+        inner.seek(SeekFrom::Start(0))?;
+        Ok(DecodeReader{codec, inner: Some(DecodeReader::decoder(codec, inner)?), pos: 0})
+    }
+
+    /// wraps `inner` in a decompressor for `codec`
+    fn decoder(codec: Codec, inner: R) -> std::io::Result<Decoder<R>> {
+        Ok(match codec {
+            Codec::None => Decoder::Plain(inner),
+            Codec::Zstd => Decoder::Zstd(zstd::stream::read::Decoder::new(inner)?),
+            Codec::Deflate => Decoder::Deflate(flate2::read::DeflateDecoder::new(inner))
+        })
+    }
+
+    /// goes back to the start of the data
+    fn restart(&mut self) -> std::io::Result<()> {
+        let mut inner = match self.inner.take() {
+            Some(Decoder::Plain(a)) => a,
+            Some(Decoder::Zstd(a)) => a.finish().into_inner(),
+            Some(Decoder::Deflate(a)) => a.into_inner(),
+            None => return Err(std::io::Error::other("decoder was lost after an earlier error"))
+        };
+        inner.seek(SeekFrom::Start(0))?;
+        self.inner = Some(DecodeReader::decoder(self.codec, inner)?);
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for DecodeReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        let got = match &mut self.inner {
+            Some(Decoder::Plain(a)) => a.read(out)?,
+            Some(Decoder::Zstd(a)) => a.read(out)?,
+            Some(Decoder::Deflate(a)) => a.read(out)?,
+            None => return Err(std::io::Error::other("decoder was lost after an earlier error"))
+        };
+        self.pos += got as u64;
+        Ok(got)
+    }
+}
+
+impl<R: Read + Seek> Seek for DecodeReader<R> {
+    fn seek(&mut self, to: SeekFrom) -> std::io::Result<u64> {
+        let pos = match to {
+            SeekFrom::Start(a) => Some(a),
+            SeekFrom::Current(a) => self.pos.checked_add_signed(a),
+            // we don't know where the data ends without decompressing it all
+            SeekFrom::End(_) => return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "can't seek from the end of compressed data"))
+        };
+        let pos = match pos {
+            Some(a) => a,
+            None => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek to a negative position"))
+        };
+
+        if pos < self.pos {
+            self.restart()?;
+        }
+        let skip = pos - self.pos;
+        std::io::copy(&mut self.by_ref().take(skip), &mut std::io::sink())?;
+        Ok(self.pos)
+    }
+}
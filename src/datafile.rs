@@ -1,18 +1,41 @@
-use std::io::Read;
-use std::io::Write;
-use std::str;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 
+use openssl::rand::rand_bytes;
 use openssl::symm::*;
 use hmac_sha256::Hash;
 
-const IV: &'static [u8] = b"1234567890ABCDEF";
+use crate::backup;
+use crate::cipher::{CipherKind, NONCE_LEN, new_nonce};
+use crate::codec::{Codec, DecodeReader};
+use crate::dedup::{ChunkIndex, ChunkedReader, Chunker, Stats, chunk_id, chunk_key};
+use crate::entry::{EntryReader, ReadSeek};
+use crate::error::VaultError;
+use crate::extract::{self, ExtractOptions};
+use crate::header::{Header, FORMAT_VERSION, HEADER_LEN};
+use crate::kdf::{KdfParams, new_salt};
+use crate::lock::{self, Identity};
+use crate::meta::{Capture, Metadata};
+use crate::path;
+use crate::record::{RECORD_HEADER_LEN, TRAILER_LEN, RecordKind, Span, begin_record, find_trailer, open_record, scan, trailer};
+use crate::recover::{LOST_AND_FOUND, Recovery};
+use crate::stream::{ChunkReader, ChunkWriter, sealed_len};
+use crate::table::{ATTR_CHUNKS, ATTR_COMPRESSION, ATTR_DEVICE, ATTR_DIGEST, ATTR_KIND, ATTR_METADATA, ATTR_TARGET, ATTR_XATTRS, ChunkRef, DIGEST_LEN, KIND_BLOCK_DEVICE, KIND_CHAR_DEVICE, KIND_DIRECTORY, KIND_FIFO, KIND_HARDLINK, KIND_SYMLINK, MAGIC_LEN, TableEntry, encode_u64, parse_record_table, parse_table, read_table};
+use crate::verify::{self, Report};
+
+/// The fixed CBC IV used by archives written before the plaintext header existed
+const IV: &[u8] = b"1234567890ABCDEF";
 
 
 /// The magic bytes that lead a decrypted file
-const MAGIC_BYTES: &[u8; 16] = b"\x2b\xa4\x81\xab\x2b\xa4\x81\xab\x1b\x1b\x19\x0b\x56\xc2\xe7\xff";
+const MAGIC_BYTES: &[u8; MAGIC_LEN] = b"\x2b\xa4\x81\xab\x2b\xa4\x81\xab\x1b\x1b\x19\x0b\x56\xc2\xe7\xff";
 
 
-/// Helper function to transform a password into a hashed format thats useful for AES
+/// Helper function to transform a password into a hashed format thats useful for AES.
+/// Only used to open v0 archives written before the plaintext header existed
 pub fn pass_to_hash(pass: String) -> [u8; 32] {
     Hash::hash(pass.as_bytes())
 }
@@ -20,25 +43,153 @@ pub fn pass_to_hash(pass: String) -> [u8; 32] {
 
 
 ////////////////////////// DEFINITIONS /////////////////////////////////////
-/// Enum that says if a file is currently stored in the Datafile or in its own buffer
+/// Enum that says if a file is currently stored in the Datafile, in its own
+/// buffer, still out on disk waiting to be streamed in on save, or sealed
+/// in a spool file after being read from a stream
 enum StorageLocation {
     DatFile,
-    OwnMem
+    OwnMem,
+    External(PathBuf),
+    Spooled(Spool)
 }
+
+/// Data read from a stream that has nowhere to wait until the archive is
+/// saved. It is sealed under a throwaway key in an unnamed temporary file,
+/// so neither memory nor the disk ever holds more than a chunk of plaintext
+struct Spool {
+    file: File,
+    cipher: CipherKind,
+    key: [u8; 32],
+    nonce: [u8; NONCE_LEN]
+}
+/// What an entry is
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum EntryKind {
+    #[default]
+    File,
+    Directory,
+    /// a symbolic link, which points wherever its target says
+    Symlink,
+    /// another name for a regular file in the archive, sharing its data
+    Hardlink,
+    /// a named pipe
+    Fifo,
+    CharDevice,
+    BlockDevice
+}
+
 /// Our struct that defines files in a datafile
 pub struct EncFile {
+    /// the entry's path, with its components split by `/`
     name: Vec<u8>,
+    kind: EntryKind,
     size: usize,
     offset: usize,
     fdat: Vec<u8>,
-    location: StorageLocation
+    location: StorageLocation,
+    /// how the data is compressed
+    codec: Codec,
+    /// how many bytes the data takes up once compressed
+    stored: usize,
+    /// the chunks the data is split into, for entries saved since chunking
+    /// was added
+    chunks: Option<Vec<ChunkRef>>,
+    /// where the entry sits in the archive's latest table
+    loaded: Option<usize>,
+    /// what the file looked like on disk, if it came from there
+    meta: Option<Metadata>,
+    /// where a symlink or hard link points
+    target: Vec<u8>,
+    /// the device number of a device
+    rdev: u64,
+    /// the SHA-256 of the original data, for regular files written since
+    /// digests were added
+    digest: Option<[u8; DIGEST_LEN]>
 }
 
+/// The archive on disk that `StorageLocation::DatFile` entries live in, along
+/// with the key and header it was sealed with
+struct Source {
+    path: String,
+    key: [u8; 32],
+    header: Header,
+    /// where the latest table record starts
+    table_offset: u64,
+    /// where the trailer pointing at that table ends
+    end: u64
+}
+
+/// A reader over a decrypted record (or a v2 payload) of an archive on disk
+type PayloadReader = ChunkReader<BufReader<std::fs::File>>;
+
 /// Our struct that defines a datafile
 pub struct Datafile {
-    file_data: Vec<u8>,
+    path: String,
     aes_pass: [u8; 32],
-    files: Vec<EncFile>
+    header: Header,
+    source: Option<Source>,
+    files: Vec<EncFile>,
+    /// how many old copies to keep when the archive is rewritten
+    backups: usize,
+    /// what files added from disk get compressed with
+    codec: Codec,
+    /// which optional metadata gets recorded for files added from disk
+    capture: Capture,
+    /// how many bytes an interrupted save left after the last trailer
+    torn: u64,
+    /// the archive we hold the lock on, if any
+    lock: Option<File>,
+    /// whether the archive was opened without a lock, so it can't be saved
+    read_only: bool,
+    /// what the archive looked like on disk when we last read or wrote it
+    identity: Option<Identity>,
+    /// the name of every entry in the archive's latest table
+    base: Vec<Vec<u8>>,
+    /// every chunk the saved entries point at
+    chunks: ChunkIndex
+}
+
+/// One entry of a directory listing. Directories that only exist because
+/// there are entries below them have no EncFile of their own
+pub struct DirEntry<'a> {
+    /// the entry's name inside the directory
+    pub name: Vec<u8>,
+    pub file: Option<&'a EncFile>
+}
+
+/// Where `write_records` put an entry: the offset of its record, or the
+/// chunks it was split into, along with the digest of its data
+type Placement = (usize, Option<Vec<ChunkRef>>, Option<[u8; DIGEST_LEN]>);
+
+
+////////////////////////// ENTRYKIND FUNCTIONS //////////////////////////
+impl EntryKind {
+    /// maps a `KIND_` byte from the table to the kind it stands for
+    fn from_byte(kind: u8) -> Option<Self> {
+        match kind {
+            KIND_DIRECTORY => Some(EntryKind::Directory),
+            KIND_SYMLINK => Some(EntryKind::Symlink),
+            KIND_HARDLINK => Some(EntryKind::Hardlink),
+            KIND_FIFO => Some(EntryKind::Fifo),
+            KIND_CHAR_DEVICE => Some(EntryKind::CharDevice),
+            KIND_BLOCK_DEVICE => Some(EntryKind::BlockDevice),
+            _ => None
+        }
+    }
+
+    /// returns the `KIND_` byte the table stores the kind as, which regular
+    /// files have none of
+    fn to_byte(self) -> Option<u8> {
+        match self {
+            EntryKind::File => None,
+            EntryKind::Directory => Some(KIND_DIRECTORY),
+            EntryKind::Symlink => Some(KIND_SYMLINK),
+            EntryKind::Hardlink => Some(KIND_HARDLINK),
+            EntryKind::Fifo => Some(KIND_FIFO),
+            EntryKind::CharDevice => Some(KIND_CHAR_DEVICE),
+            EntryKind::BlockDevice => Some(KIND_BLOCK_DEVICE)
+        }
+    }
 }
 
 
@@ -57,11 +208,11 @@ impl PartialEq for EncFile {
 /// implement print formatting for EncFile
 impl std::fmt::Display for EncFile {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let tmpbf = match str::from_utf8(&self.name) {
-            Ok(a) => a,
-            Err(e) => panic!("Unable to convert filename to string: {}", e)
-        };
-        write!(f, "{} ({} bytes)", tmpbf, self.size)
+        let tmpbf = String::from_utf8_lossy(&self.name);
+        match self.kind {
+            EntryKind::Directory => write!(f, "{}/", tmpbf),
+            _ => write!(f, "{} {}", tmpbf, self.details())
+        }
     }
 }
 
@@ -74,42 +225,95 @@ impl std::fmt::Display for Datafile {
 
 ////////////////////////// ENCFILE FUNCTIONS //////////////////////////
 impl EncFile {
-    #[allow(dead_code)]
-    fn new(name: Vec<u8>, size: usize, offset: usize, load_self: Option<&Vec<u8>>) -> Self {
+    fn new(name: Vec<u8>, size: usize, offset: usize, load_self: Option<Vec<u8>>) -> Self {
         // see if we are gonna load the file from
-        let location: StorageLocation; 
-        let fdat = match load_self {
-            Some(a) => {
-                let mut thing = vec![0u8;size];
-                thing.copy_from_slice(&a[..]);
-                location = StorageLocation::OwnMem;
-                thing
+        match load_self {
+            Some(fdat) => EncFile{name, size: fdat.len(), offset, stored: fdat.len(), fdat, location: StorageLocation::OwnMem, codec: Codec::None, chunks: None, loaded: None, kind: EntryKind::File, meta: None, target: Vec::new(), rdev: 0, digest: None},
+            None => EncFile{name, size, offset, fdat: Vec::new(), location: StorageLocation::DatFile, codec: Codec::None, stored: size, chunks: None, loaded: None, kind: EntryKind::File, meta: None, target: Vec::new(), rdev: 0, digest: None}
+        }
+    }
+
+    /// creates a file from an entry of a record table
+    fn from_entry(e: TableEntry) -> Result<Self, VaultError> {
+        let chunks = e.chunk_refs(None)?;
+        let meta = e.metadata(None)?;
+        let kind = match e.kind(None)? {
+            Some(a) => match EntryKind::from_byte(a) {
+                Some(b) => b,
+                None => return Err(VaultError::Unsupported(format!("unknown kind of entry {}", String::from_utf8_lossy(&e.name))))
             },
-            None => {
-                location = StorageLocation::DatFile;
-                Vec::new()
-            }
+            None => EntryKind::File
         };
-        EncFile{name, size, offset, fdat, location}
+        let target = e.target(None)?.unwrap_or_default().to_vec();
+        let rdev = e.device(None)?.unwrap_or_default();
+        let digest = e.digest(None)?;
+        let mut encf = EncFile::new(e.name, e.size, e.offset, None);
+        encf.meta = meta;
+        encf.kind = kind;
+        encf.target = target;
+        encf.rdev = rdev;
+        encf.digest = digest;
+        if let Some(refs) = chunks {
+            encf.set_chunks(refs);
+        }
+        for (tag, value) in e.attrs {
+            match tag {
+                ATTR_COMPRESSION if value.len() == 9 => {
+                    let mut size = [0u8; 8];
+                    size.copy_from_slice(&value[1..]);
+                    encf.codec = Codec::from_id(value[0])?;
+                    encf.size = match usize::try_from(u64::from_le_bytes(size)) {
+                        Ok(a) => a,
+                        Err(_) => return Err(VaultError::Unsupported(format!("{} is too big for this machine", encf)))
+                    };
+                },
+                ATTR_COMPRESSION => return Err(VaultError::Corrupt(format!("compression attribute of {} is malformed", encf))),
+                ATTR_CHUNKS => (),
+                ATTR_KIND | ATTR_TARGET | ATTR_DEVICE | ATTR_METADATA | ATTR_XATTRS | ATTR_DIGEST => (),
+                _ => return Err(VaultError::Unsupported(format!("unknown attribute {} on {}", tag, encf)))
+            };
+        }
+        Ok(encf)
     }
 
-    /// serializes an EncFile for the datafile
-    fn serialize(&self) -> Vec<u8> {
-        let name_size = self.name.len();
-        let mut data_buff: Vec<u8> = vec![0u8; name_size]; 
-
-        // now that we have the buffer prepared, write the data to it
-        data_buff.copy_from_slice(&self.name[..]);
-        data_buff.push(0u8); // add in the null-term for reading it later
-        for byte in self.size.to_ne_bytes() {
-            data_buff.push(byte);
+    /// builds the file's entry in a record table, with its data where
+    /// `write_records` put it
+    fn table_entry(&self, placed: &Placement) -> TableEntry {
+        let mut e = match placed {
+            // only regular files have data
+            _ if self.kind != EntryKind::File => {
+                let mut attrs: Vec<(u8, Vec<u8>)> = self.kind.to_byte().map(|k| (ATTR_KIND, vec![k])).into_iter().collect();
+                match self.kind {
+                    EntryKind::Symlink | EntryKind::Hardlink => attrs.push((ATTR_TARGET, self.target.clone())),
+                    EntryKind::CharDevice | EntryKind::BlockDevice => attrs.push((ATTR_DEVICE, self.rdev.to_le_bytes().to_vec())),
+                    _ => ()
+                };
+                TableEntry{name: self.name.clone(), size: 0, offset: 0, attrs}
+            },
+            (_, Some(refs), _) => {
+                let value: Vec<u8> = refs.iter().flat_map(|r| r.serialize()).collect();
+                TableEntry{name: self.name.clone(), size: self.size, offset: 0, attrs: vec![(ATTR_CHUNKS, value)]}
+            },
+            (offset, None, _) => {
+                let mut attrs: Vec<(u8, Vec<u8>)> = Vec::new();
+                if self.codec != Codec::None {
+                    let mut value = vec![self.codec.id()];
+                    value.extend_from_slice(&(self.size as u64).to_le_bytes());
+                    attrs.push((ATTR_COMPRESSION, value));
+                }
+                TableEntry{name: self.name.clone(), size: self.stored, offset: *offset, attrs}
+            }
+        };
+        if let (EntryKind::File, (_, _, Some(digest))) = (self.kind, placed) {
+            e.attrs.push((ATTR_DIGEST, digest.to_vec()));
         }
-        for byte in self.offset.to_ne_bytes() {
-            data_buff.push(byte);
+        if let Some(meta) = &self.meta {
+            e.attrs.push((ATTR_METADATA, meta.serialize()));
+            if !meta.xattrs.is_empty() {
+                e.attrs.push((ATTR_XATTRS, meta.serialize_xattrs()));
+            }
         }
-        
-        // return the serialized data
-        data_buff
+        e
     }
 
     /// gets the file's name (cloned already)
@@ -117,61 +321,114 @@ impl EncFile {
         self.name.clone()
     }
 
+    /// describes the file's size, and how much space it takes up if that
+    /// is different
+    pub fn details(&self) -> String {
+        let target = String::from_utf8_lossy(&self.target);
+        match self.codec {
+            _ if self.kind == EntryKind::Directory => "(directory)".to_string(),
+            _ if self.kind == EntryKind::Symlink => format!("-> {}", target),
+            _ if self.kind == EntryKind::Hardlink => format!("(hard link to {})", target),
+            _ if self.kind == EntryKind::Fifo => "(fifo)".to_string(),
+            _ if self.kind == EntryKind::CharDevice => "(character device)".to_string(),
+            _ if self.kind == EntryKind::BlockDevice => "(block device)".to_string(),
+            Codec::None if self.stored == self.size => format!("({} bytes)", self.size),
+            Codec::None => format!("({} bytes, {} stored)", self.size, self.stored),
+            _ => format!("({} bytes, {} stored with {})", self.size, self.stored, self.codec)
+        }
+    }
+
+    /// gets the SHA-256 of the file's data, if it was recorded when the file
+    /// was saved
+    pub fn get_digest(&self) -> Option<[u8; DIGEST_LEN]> {
+        self.digest
+    }
+
+    /// gets what kind of entry this is
+    pub fn get_kind(&self) -> EntryKind {
+        self.kind
+    }
+
+    /// returns true if the entry is a directory
+    pub fn is_dir(&self) -> bool {
+        self.kind == EntryKind::Directory
+    }
+
+    /// returns true if the entry is a regular file, which is the only kind
+    /// that has data of its own
+    pub fn is_file(&self) -> bool {
+        self.kind == EntryKind::File
+    }
+
+    /// gets where a symlink or hard link points
+    pub fn get_target(&self) -> Option<&[u8]> {
+        match self.kind {
+            EntryKind::Symlink | EntryKind::Hardlink => Some(&self.target),
+            _ => None
+        }
+    }
+
+    /// gets the device number of a device
+    pub fn get_device(&self) -> Option<u64> {
+        match self.kind {
+            EntryKind::CharDevice | EntryKind::BlockDevice => Some(self.rdev),
+            _ => None
+        }
+    }
+
+    /// gets what the file looked like on disk when it was added, if it
+    /// came from there
+    pub fn get_metadata(&self) -> Option<&Metadata> {
+        self.meta.as_ref()
+    }
+
     /// gets the file's size
-    #[allow(dead_code)]
     pub fn get_fsize(&self) -> usize {
         self.size
     }
 
     /// gets the file's start offset
-    #[allow(dead_code)]
     pub fn get_foffset(&self) -> usize {
         self.offset
     }
 
-    /// loads the data of the file into memory
-    #[allow(dead_code)]
-    pub fn set_file_data(&mut self, path: String) -> Result<(), String> {
-        // try to open the new file
-        let mut f = match std::fs::File::open(path) {
-            Ok(a) => a,
-            Err(e) => return Err(e.to_string())
-        };
+    /// gets how many bytes the file's data takes up in the archive
+    pub fn get_stored_size(&self) -> usize {
+        self.stored
+    }
 
-        let mut data: Vec<u8> = Vec::new();
-        
-        // copy the file's data to the data vector
-        let newfsize = match f.read_to_end(&mut data){
-            Ok(a) => a,
-            Err(e) => return Err(format!("Failed to read data: {}", e))
-        };
+    /// gets the codec the file's data is compressed with
+    pub fn get_codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// points the file's data at a file on disk, which is only read when the
+    /// archive is saved or the entry is fetched
+    pub fn set_file_data<P: AsRef<Path>>(&mut self, path: P) -> Result<(), VaultError> {
+        // make sure we can open the new file before we rely on it
+        let f = std::fs::File::open(&path)?;
+        self.size = f.metadata()?.len() as usize;
+        self.stored = self.size;
+        self.codec = Codec::None;
+        self.chunks = None;
 
         // update the variables we have in this file
-        self.size = newfsize;
         self.fdat.clear();
-        self.fdat.append(&mut data);
-        self.update_location(StorageLocation::OwnMem);
+        self.update_location(StorageLocation::External(path.as_ref().to_path_buf()));
 
         Ok(())
     }
 
-    /// sets the direct contents of the file data
-    fn set_file_vec(&mut self, dat: &mut Vec<u8>) -> Result<(), String> {
-        // update the variables we have in this file
-        self.fdat.clear();
-        self.fdat.append(dat);
-
-        // update the marker for data storage location 
-        self.update_location(StorageLocation::OwnMem);
-
-        Ok(())
+    /// points the file at the chunks its data was split into
+    fn set_chunks(&mut self, refs: Vec<ChunkRef>) {
+        self.stored = refs.iter().map(|r| r.stored).sum();
+        self.codec = Codec::None;
+        self.chunks = Some(refs);
     }
 
-    fn get_fdat(&self) -> Result<&Vec<u8>, String> {
-        match &self.location {
-            StorageLocation::DatFile => return Err("Data is stored in Datafile".to_string()),
-            StorageLocation::OwnMem => return Ok(&self.fdat)
-        }
+    /// gets the chunks the file's data is split into, if it is saved that way
+    pub fn get_chunks(&self) -> Option<&[ChunkRef]> {
+        self.chunks.as_deref()
     }
 
     fn update_location(&mut self, loc: StorageLocation) {
@@ -183,375 +440,1359 @@ impl EncFile {
 /////////////////////////// DATAFILE FUNCTIONS //////////////////////////
 impl Datafile {
     /// creates a new Datafile, and creates a new file for it
-    pub fn setup_new(aes_pass: String, filepath: String) -> Result<Self, String> {
-        let pass = pass_to_hash(aes_pass.clone());
-    
-        let mut f = match std::fs::File::create(filepath.clone()) {
-            Ok(a) => a,
-            Err(e) => panic!("Failed to create file: {}",e)
-        };
+    pub fn setup_new(aes_pass: String, filepath: String, kdf: KdfParams, cipher: CipherKind) -> Result<Self, VaultError> {
+        let salt = new_salt()?;
+        let pass = kdf.derive_key(&aes_pass, &salt)?;
 
-        // set up our checker vector
-        let mut fvec = vec![0u8; MAGIC_BYTES.len()];
-        fvec.copy_from_slice(MAGIC_BYTES);
-        for _ in 0..4 {
-            fvec.push(0);
+        // we already hold the key, so let `save` write out the empty archive
+        // rather than deriving the key a second time
+        let mut df = Datafile::new(filepath.clone(), pass, Header::new(cipher, kdf, salt, new_nonce()?));
+        df.save(filepath)?;
+
+        Ok(df)
+    }
+
+    /// reads the whole of an archive file into memory. Only used to migrate
+    /// old archives, which can't be read any other way
+    fn read_archive(filepath: String) -> Result<Vec<u8>, VaultError> {
+        let mut file_handle = std::fs::File::open(filepath)?;
+        // try to read the file's data
+        let fsize = file_handle.metadata()?.len();
+        let mut data: Vec<u8> = Vec::new();
+        // read the data from the file into the vector
+        let datread = file_handle.read_to_end(&mut data)?;
+        // make sure metadata and read data lengths match
+        if datread as u64 != fsize {
+            return Err(VaultError::Corrupt("archive changed size while it was read".to_string()));
         }
-        println!("Length of data: {}", fvec.len());
 
-        // set up the cipher and try to encrypt
-        let t = Cipher::aes_256_cbc();
-        let cyp = encrypt(t, &pass, Some(IV), &fvec[..]).unwrap();
+        Ok(data)
+    }
 
-        let mut written = match f.write(&cyp[0..cyp.len()]) {
-            Ok(a) => a,
-            Err(e) => panic!("{}", e)
-        };
+    /// creates a new Datafile, checking to make sure it can successfully decrypt the data.
+    /// The archive stays locked until the Datafile is dropped
+    pub fn checked_new(filepath: String, aes_pass: String) -> Result<Self, VaultError> {
+        let f = lock::open_locked(&filepath)?;
+        Datafile::open_file(filepath, aes_pass, f, false)
+    }
 
-        while written != cyp.len() {
-            written += match f.write(&cyp[written..cyp.len()]) {
-                Ok(a) => a,
-                Err(e) => panic!("{}", e)
-            };
+    /// creates a new Datafile without locking the archive, for when another
+    /// process has it open. It can be read, but not saved back
+    pub fn checked_new_read_only(filepath: String, aes_pass: String) -> Result<Self, VaultError> {
+        let f = File::open(&filepath)?;
+        Datafile::open_file(filepath, aes_pass, f, true)
+    }
+
+    /// reads the header of an opened archive and derives its key
+    fn open_file(filepath: String, aes_pass: String, mut f: File, read_only: bool) -> Result<Self, VaultError> {
+        // the header carries everything we need to derive the key and
+        // authenticate the rest of the file
+        let header = Header::read(&mut f)?;
+        if header.version != FORMAT_VERSION {
+            return Err(VaultError::Unsupported(format!("format v{} archives have to be converted with --migrate first", header.version)));
+        }
+        let pass = header.kdf.derive_key(&aes_pass, &header.salt)?;
+        Datafile::open_with_key(filepath, f, header, pass, read_only)
+    }
+
+    /// reads the file table of an opened archive with a key we already have
+    fn open_with_key(filepath: String, f: File, header: Header, pass: [u8; 32], read_only: bool) -> Result<Self, VaultError> {
+        // only the latest table record gets decrypted here, entries are
+        // decrypted when something reads them
+        let mut df = Datafile::new(filepath.clone(), pass, header);
+        df.source = Some(Source{path: filepath, key: pass, header, table_offset: 0, end: 0});
+        df.parse_filetable()?;
+        df.read_only = read_only;
+        if !read_only {
+            df.lock = Some(f);
         }
-    
-        println!("Successfully created new file");
 
-        Datafile::checked_new(filepath, aes_pass)
+        Ok(df)
     }
 
-    /// creates a new Datafile, checking to make sure it can successfully decrypt the data
-    pub fn checked_new(filepath: String, aes_pass: String) -> Result<Self, String> {
-        let mut file_handle = match std::fs::File::open(filepath) {
-            Ok(a) => a,
-            Err(e) => panic!("Failed to open file: {}", e)
-        };
-        // try to read the file's data
-        let fsize = file_handle.metadata().unwrap().len();
-        let mut data: Vec<u8> = Vec::new();
-        // read the data from the file into the vector
-        let datread = match file_handle.read_to_end(&mut data) {
-            Ok(a) => a,
-            Err(e) => panic!("Failed to read file data ({})", e)
-        };
-        // make sure metadata and read data lengths match
-        assert_eq!(datread, fsize as usize, "Data lengths mismatch");
-        
-        
-    
-        let pass = pass_to_hash(aes_pass.clone());
-        
-        let t = Cipher::aes_256_cbc();
-        let out = match decrypt(t, &pass, Some(IV), &data[..]){
-            Ok(a) => a,
-            Err(e) => return Err(format!("{}", e))
-        };
-        
-        // assert that the data begins with the magic bytes 
-        if &out[..16] != MAGIC_BYTES{ 
-            println!("Failed");
-            println!("Current: {:?}", out);
-            println!("Magic: {:?}", MAGIC_BYTES);
-            return Err("Magic bytes not found".to_string());
+    /// opens an archive in an older format (a v0 headerless CBC blob keyed
+    /// with a bare hash of the password, a v1 single AEAD blob or a v2 single
+    /// chunked stream) and rewrites it at the same path in the current format
+    pub fn migrate(filepath: String, aes_pass: String, kdf: KdfParams, cipher: CipherKind) -> Result<Self, VaultError> {
+        let salt = new_salt()?;
+        let mut df = Datafile::new(filepath.clone(), [0u8; 32], Header::new(cipher, kdf, salt, new_nonce()?));
+
+        // hold the lock until the new archive has replaced the old one
+        let mut f = lock::open_locked(&filepath)?;
+        // only a missing signature means v0. Anything else wrong with the
+        // header, like a version we don't know, is passed on as it is
+        match Header::read_if_signed(&mut f)? {
+            Some(h) if h.version == FORMAT_VERSION => {
+                return Err(VaultError::Unsupported("archive is already in the current format".to_string()));
+            },
+            // v2 streams can be read in place, so its entries are copied
+            // straight over when the new archive is written
+            Some(h) if h.version == 2 => {
+                let pass = h.kdf.derive_key(&aes_pass, &h.salt)?;
+                df.source = Some(Source{path: filepath.clone(), key: pass, header: h, table_offset: 0, end: 0});
+                df.parse_filetable()?;
+            },
+            // v1 sealed the whole payload in one go, with the header as AAD
+            Some(h) => {
+                let data = Datafile::read_archive(filepath.clone())?;
+                let pass = h.kdf.derive_key(&aes_pass, &h.salt)?;
+                let out = h.cipher.open(&pass, &h.nonce, &data[..HEADER_LEN], &data[HEADER_LEN..])?;
+                df.load_payload(out)?;
+            },
+            None => {
+                // CBC has no tag, so a padding error is the only sign of a bad key
+                let data = Datafile::read_archive(filepath.clone())?;
+                let t = Cipher::aes_256_cbc();
+                let out = match decrypt(t, &pass_to_hash(aes_pass.clone()), Some(IV), &data[..]){
+                    Ok(a) => a,
+                    Err(_) => return Err(VaultError::WrongPassword)
+                };
+                if !out.starts_with(MAGIC_BYTES) {
+                    return Err(VaultError::WrongPassword);
+                }
+                df.load_payload(out)?;
+            }
         };
-        
-        println!("Decryption successful");
-        
-        let mut df = Datafile::new(out, pass_to_hash(aes_pass));
-        df.parse_filetable()?;
+
+        // rekey the archive with a salted KDF before writing it back out
+        df.aes_pass = kdf.derive_key(&aes_pass, &salt)?;
+        df.save(filepath)?;
 
         Ok(df)
     }
 
+    /// loads the entries of a decrypted v0 or v1 payload into memory
+    fn load_payload(&mut self, out: Vec<u8>) -> Result<(), VaultError> {
+        if !out.starts_with(MAGIC_BYTES) {
+            return Err(VaultError::Corrupt("magic bytes not found".to_string()));
+        }
+        for e in parse_table(&out, out.len())? {
+            let dat = out[e.offset..e.offset+e.size].to_vec();
+            self.files.push(EncFile::new(e.name, e.size, e.offset, Some(dat)));
+        }
+        Ok(())
+    }
+
     /// creates a new Datafile
-    fn new(file_data: Vec<u8>, aes_pass: [u8; 32]) -> Self {
+    fn new(path: String, aes_pass: [u8; 32], header: Header) -> Self {
         let files: Vec<EncFile> = Vec::new();
-        Datafile{file_data, aes_pass, files}
+        Datafile{
+            path, aes_pass, header, files,
+            source: None,
+            backups: backup::DEFAULT_BACKUPS,
+            codec: Codec::None,
+            capture: Capture::default(),
+            torn: 0,
+            lock: None,
+            read_only: false,
+            identity: None,
+            base: Vec::new(),
+            chunks: ChunkIndex::default()
+        }
+    }
+
+    /// creates a new archive at `path` with the default cipher and KDF settings
+    pub fn create<P: AsRef<Path>>(path: P, pass: &str) -> Result<Self, VaultError> {
+        let path = path.as_ref().to_string_lossy().to_string();
+        Datafile::setup_new(pass.to_string(), path, KdfParams::default(), CipherKind::default())
+    }
+
+    /// opens the archive at `path`, locking it until the Datafile is dropped
+    pub fn open<P: AsRef<Path>>(path: P, pass: &str) -> Result<Self, VaultError> {
+        let path = path.as_ref().to_string_lossy().to_string();
+        Datafile::checked_new(path, pass.to_string())
+    }
+
+    /// opens the archive at `path` without locking it, so it can't be saved
+    pub fn open_read_only<P: AsRef<Path>>(path: P, pass: &str) -> Result<Self, VaultError> {
+        let path = path.as_ref().to_string_lossy().to_string();
+        Datafile::checked_new_read_only(path, pass.to_string())
+    }
+
+    /// returns the path the archive was opened from or last saved to
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// returns the archive's plaintext header
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// sets how many old copies of the archive are kept as `.bak` files when
+    /// it is rewritten. Zero keeps none
+    pub fn set_backups(&mut self, backups: usize) {
+        self.backups = backups;
+    }
+
+    /// returns how many old copies of the archive are kept when it is rewritten
+    pub fn backups(&self) -> usize {
+        self.backups
+    }
+
+    /// sets the codec files added from disk get compressed with. Files that
+    /// don't compress well are stored as they are
+    pub fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+
+    /// returns the codec files added from disk get compressed with
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// sets which optional metadata, like extended attributes, gets
+    /// recorded for files added from disk
+    pub fn set_capture(&mut self, capture: Capture) {
+        self.capture = capture;
+    }
+
+    /// returns which optional metadata gets recorded for files added from disk
+    pub fn capture(&self) -> Capture {
+        self.capture
+    }
+
+    /// returns true if the archive was opened without a lock and can't be
+    /// saved back
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// returns how many bytes an interrupted save left at the end of the
+    /// archive. They are ignored, and the next save writes over them
+    pub fn torn_tail(&self) -> u64 {
+        self.torn
+    }
+
+    /// returns the archive our `DatFile` entries live in
+    fn source(&self) -> Result<&Source, VaultError> {
+        match &self.source {
+            Some(a) => Ok(a),
+            None => Err(VaultError::NotFound("archive on disk".to_string()))
+        }
+    }
+
+    /// opens the payload of a v2 archive being migrated. Only the chunks
+    /// that are actually read get decrypted
+    fn open_payload(&self) -> Result<PayloadReader, VaultError> {
+        let source = self.source()?;
+        let mut f = std::fs::File::open(&source.path)?;
+        let len = f.metadata()?.len();
+        if len < HEADER_LEN as u64 {
+            return Err(VaultError::Corrupt("archive header is truncated".to_string()));
+        }
+        f.seek(SeekFrom::Start(HEADER_LEN as u64))?;
+
+        let h = &source.header;
+        ChunkReader::new(BufReader::new(f), len - HEADER_LEN as u64, h.cipher, source.key, h.nonce, h.serialize())
+    }
+
+    /// opens the record of the given kind at `offset` in the archive our
+    /// `DatFile` entries live in
+    fn open_source_record(&self, offset: u64, kind: RecordKind) -> Result<PayloadReader, VaultError> {
+        let source = self.source()?;
+        let f = BufReader::new(std::fs::File::open(&source.path)?);
+        open_record(f, offset, &source.header, source.key, kind)
+    }
+
+    /// opens the data of an entry that still lives in the archive on disk,
+    /// returning the reader along with where the entry starts in it
+    fn open_stored(&self, file: &EncFile) -> Result<(PayloadReader, u64), VaultError> {
+        if self.source()?.header.version < FORMAT_VERSION {
+            return Ok((self.open_payload()?, file.offset as u64));
+        }
+
+        let reader = self.open_source_record(file.offset as u64, RecordKind::Data)?;
+        if reader.plaintext_len() != file.stored as u64 {
+            return Err(VaultError::Corrupt(format!("record for {} holds {} bytes", file, reader.plaintext_len())));
+        }
+        Ok((reader, 0))
     }
 
     /// loads a new database from a file
-    pub fn load_new(&mut self, path: String, passwd: String) -> Result<(), String> {
-        let mut file_handle = match std::fs::File::open(path) {
-            Ok(a) => a,
-            Err(e) => panic!("Failed to open file: {}", e)
-        };
-        // try to read the file's data
-        let fsize = file_handle.metadata().unwrap().len();
-        let mut data: Vec<u8> = Vec::new();
-        // read the data from the file into the vector
-        let datread = match file_handle.read_to_end(&mut data) {
-            Ok(a) => a,
-            Err(e) => panic!("Failed to read file data ({})", e)
-        };
-        // make sure metadata and read data lengths match
-        assert_eq!(datread, fsize as usize, "Data lengths mismatch");
-        
-        
-    
-        let pass = pass_to_hash(passwd.clone());
-        
-        let t = Cipher::aes_256_cbc();
-        let mut out = decrypt(t, &pass, Some(IV), &data[..]).unwrap();
-        
-        // assert that the data begins with the magic bytes 
-        if &out[..16] != MAGIC_BYTES{ 
-            println!("Failed");
-            println!("Current: {:?}", out);
-            println!("Magic: {:?}", MAGIC_BYTES);
-            return Err("Magic bytes not found".to_string());
+    pub fn load_new(&mut self, path: String, passwd: String) -> Result<(), VaultError> {
+        let backups = self.backups;
+        *self = Datafile::checked_new(path, passwd)?;
+        self.backups = backups;
+        Ok(())
+    }
+
+
+    /// writes the data contained in self back to the file it came from
+    pub fn save_in_place(&mut self) -> Result<(), VaultError> {
+        self.save(self.path.clone())
+    }
+
+    /// writes the data contained in self to a file. Saving back to the
+    /// archive we came from only appends the entries that are new and a new
+    /// table. Anything else, like a new path or password, rewrites it
+    pub fn save(&mut self, path: String) -> Result<(), VaultError> {
+        self.check_writable(&path)?;
+        let append = match &self.source {
+            Some(a) => a.path == path && a.key == self.aes_pass && a.header == self.header,
+            None => false
         };
-        
-        println!("Decryption successful");
-        
-        self.file_data.clear();
-        self.file_data.append(&mut out);
-        self.aes_pass = pass_to_hash(passwd.clone());
-        self.files.clear();
+        if append {
+            self.append()
+        } else {
+            self.rewrite(path)
+        }
+    }
 
-        self.parse_filetable()?;
+    /// makes sure saving to `path` won't throw away changes someone else
+    /// made to it since we opened it
+    fn check_writable(&self, path: &str) -> Result<(), VaultError> {
+        if self.read_only && path == self.path {
+            return Err(VaultError::ReadOnly);
+        }
+        let source = match &self.source {
+            Some(a) if a.path == path => a,
+            _ => return Ok(())
+        };
+        match (self.identity, Identity::read(path, source.table_offset)) {
+            (Some(a), Ok(b)) if a != b => Err(VaultError::Changed(path.to_string())),
+            (Some(_), Err(_)) => Err(VaultError::Changed(path.to_string())),
+            _ => Ok(())
+        }
+    }
 
+    /// folds the changes made since the archive was opened or last saved
+    /// into what is on disk now, so saving keeps whatever another process
+    /// saved in the meantime. Entries we added, removed or renamed are
+    /// added, removed or renamed on disk too, and everything else is taken
+    /// from the disk
+    pub fn merge_changes(&mut self) -> Result<(), VaultError> {
+        if self.read_only {
+            return Err(VaultError::ReadOnly);
+        }
+        let source = self.source()?;
+        let mut f = lock::open_locked(&source.path)?;
+        let header = Header::read(&mut f)?;
+        // we only hold the key, so the password has to be the same
+        let same_key = header.version == FORMAT_VERSION && header.cipher == source.header.cipher &&
+                       header.kdf == source.header.kdf && header.salt == source.header.salt;
+        if !same_key {
+            return Err(VaultError::Unsupported("archive was rekeyed or converted since it was opened, so it has to be reopened".to_string()));
+        }
+        let theirs = Datafile::open_with_key(source.path.clone(), f, header, source.key, false)?;
+        let rekeyed = self.aes_pass != source.key;
+
+        let files = self.merged_files(theirs.files)?;
+        self.files = files;
+        self.source = theirs.source;
+        self.lock = theirs.lock;
+        self.identity = theirs.identity;
+        self.base = theirs.base;
+        self.torn = theirs.torn;
+        self.index_chunks();
+        // a password change we haven't saved yet brings its own header
+        if !rekeyed {
+            self.header = theirs.header;
+        }
         Ok(())
-
     }
 
+    /// applies the changes we made since loading `base` on top of the
+    /// entries that are on disk now
+    fn merged_files(&mut self, mut theirs: Vec<EncFile>) -> Result<Vec<EncFile>, VaultError> {
+        // entries we removed or renamed. Each of our saved entries remembers
+        // where it sat in the table, which tells us what it became
+        for (idx, name) in self.base.iter().enumerate() {
+            let ours = self.files.iter().find(|f| f.loaded == Some(idx));
+            match ours {
+                Some(f) if f.name == *name => (),
+                Some(f) => {
+                    if theirs.iter().any(|t| t.name == f.name) {
+                        return Err(VaultError::AlreadyExists(String::from_utf8_lossy(&f.name).to_string()));
+                    }
+                    if let Some(t) = theirs.iter_mut().find(|t| t.name == *name) {
+                        t.name = f.name.clone();
+                    }
+                },
+                None => theirs.retain(|t| t.name != *name)
+            };
+        }
+
+        // entries we added
+        for f in self.files.iter().filter(|f| !matches!(f.location, StorageLocation::DatFile)) {
+            if theirs.iter().any(|t| t.name == f.name) {
+                return Err(VaultError::AlreadyExists(String::from_utf8_lossy(&f.name).to_string()));
+            }
+        }
+        let added = std::mem::take(&mut self.files).into_iter().filter(|f| !matches!(f.location, StorageLocation::DatFile));
+        theirs.extend(added);
+
+        // a file on one side may have become a directory on the other, and
+        // the file a hard link points at may be gone
+        let kinds: HashMap<&[u8], EntryKind> = theirs.iter().map(|f| (&f.name[..], f.kind)).collect();
+        for f in theirs.iter() {
+            if let Some(dir) = path::ancestors(&f.name).find(|a| matches!(kinds.get(a), Some(k) if *k != EntryKind::Directory)) {
+                return Err(VaultError::NotADirectory(String::from_utf8_lossy(dir).to_string()));
+            }
+            if f.kind == EntryKind::Hardlink && kinds.get(&f.target[..]) != Some(&EntryKind::File) {
+                return Err(VaultError::NotFound(String::from_utf8_lossy(&f.target).to_string()));
+            }
+        }
+        Ok(theirs)
+    }
 
-    /// writes the data contained in self to a file
-    pub fn save(&mut self, path: String) -> Result<(), String> {
-        let write_buffer = match self.get_file_content() {
+    /// appends new entries and a new table to the end of the archive. The
+    /// records are synced to disk before the trailer that makes them live is
+    /// written, so a crash part way through leaves the last save intact
+    fn append(&mut self) -> Result<(), VaultError> {
+        let file = OpenOptions::new().write(true).open(&self.path)?;
+        let start = self.source()?.end;
+
+        let res = (|| -> Result<_, VaultError> {
+            // drop whatever an interrupted save left behind
+            file.set_len(start)?;
+            (&file).seek(SeekFrom::Start(start))?;
+            let mut out = BufWriter::new(&file);
+            let (offsets, table_offset, end) = self.write_records(&mut out, start, true)?;
+            out.flush()?;
+            file.sync_data()?;
+
+            out.write_all(&trailer(table_offset))?;
+            out.flush()?;
+            file.sync_data()?;
+            Ok((offsets, table_offset, end + TRAILER_LEN as u64))
+        })();
+
+        // don't leave half a record behind if something went wrong
+        let (offsets, table_offset, end) = match res {
             Ok(a) => a,
-            Err(e) => return Err(e)
+            Err(e) => {
+                let _ = file.set_len(start);
+                return Err(e);
+            }
         };
-        // encrypt the data
-        let t = Cipher::aes_256_cbc();
-        let cyp = encrypt(t, &self.aes_pass, Some(IV), &write_buffer[..]).unwrap();
+        self.stored_at(offsets, Source{path: self.path.clone(), key: self.aes_pass, header: self.header, table_offset, end})?;
+        self.torn = 0;
+
+        Ok(())
+    }
 
-        // try to open the file for writing
-        let mut f = match std::fs::File::create(path) {
+    /// writes a fresh archive holding only the current entries to `path`
+    fn rewrite(&mut self, path: String) -> Result<(), VaultError> {
+        // records are bound to the header, so give the new archive a fresh
+        // nonce. Entries may be streamed out of the archive we are replacing,
+        // so write everything to a temp file and only then move it into place
+        self.header.nonce = new_nonce()?;
+        let (tmp, f) = backup::create_temp(&path)?;
+        lock::lock_new(&f);
+        let res = (|| -> Result<_, VaultError> {
+            let mut out = BufWriter::new(&f);
+            out.write_all(&self.header.serialize())?;
+            let (offsets, table_offset, end) = self.write_records(&mut out, HEADER_LEN as u64, false)?;
+            out.write_all(&trailer(table_offset))?;
+            out.flush()?;
+            f.sync_all()?;
+            Ok((offsets, table_offset, end + TRAILER_LEN as u64))
+        })();
+        let (offsets, table_offset, end) = match res {
             Ok(a) => a,
-            Err(e) => return Err(e.to_string())
+            Err(e) => {
+                let _ = std::fs::remove_file(&tmp);
+                return Err(e);
+            }
         };
+        if let Err(e) = backup::replace(&tmp, &path, self.backups) {
+            let _ = std::fs::remove_file(&tmp);
+            return Err(e);
+        }
 
-        // write the data to the file
-        let mut written = match f.write(&cyp[0..cyp.len()]) {
-            Ok(a) => a,
-            Err(e) => panic!("{}", e)
+        // the lock moves over to the file that is now at `path`
+        self.lock = Some(f);
+        self.read_only = false;
+        self.stored_at(offsets, Source{path: path.clone(), key: self.aes_pass, header: self.header, table_offset, end})?;
+        self.path = path;
+        self.torn = 0;
+
+        Ok(())
+    }
+
+    /// writes the data records for the entries, then the table, to `out`
+    /// starting at byte `pos` of the archive. Entries that already live in
+    /// the archive are skipped if `only_new` is set. Returns where each
+    /// entry's data was put along with the table's offset, and where the
+    /// table ends
+    fn write_records<W: Write>(&self, out: &mut W, mut pos: u64, only_new: bool) -> Result<(Vec<Placement>, u64, u64), VaultError> {
+        let mut placements: Vec<Option<Placement>> = vec![None; self.files.len()];
+        // chunks we have written (or that are already in the archive when
+        // appending) by id, so each one is only stored once
+        let mut known: HashMap<[u8; 32], ChunkRef> = HashMap::new();
+        let id_key = chunk_key(&self.aes_pass);
+        let rekeyed = match &self.source {
+            Some(a) => a.key != self.aes_pass,
+            None => true
         };
 
-        while written != cyp.len() {
-            written += match f.write(&cyp[written..cyp.len()]) {
-                Ok(a) => a,
-                Err(e) => panic!("{}", e)
+        // saved entries first, so new entries can share the chunks they copy
+        let mut copied: HashMap<usize, ChunkRef> = HashMap::new();
+        for (encf, placed) in self.files.iter().zip(placements.iter_mut()) {
+            // only regular files have data to write
+            if !encf.is_file() {
+                *placed = Some((0, None, None));
+                continue;
+            }
+            if !matches!(encf.location, StorageLocation::DatFile) {
+                continue;
+            }
+            if only_new {
+                *placed = Some((encf.offset, encf.chunks.clone(), encf.digest));
+                continue;
+            }
+            // entries saved before chunking get split up when rewritten
+            let refs = match &encf.chunks {
+                Some(a) => a,
+                None => continue
             };
+
+            let mut new_refs: Vec<ChunkRef> = Vec::with_capacity(refs.len());
+            for chunk in refs.iter() {
+                if let Some(a) = copied.get(&chunk.offset) {
+                    new_refs.push(*a);
+                    continue;
+                }
+                // the chunk is copied over as it is, still compressed
+                let mut reader = self.open_source_record(chunk.offset as u64, RecordKind::Data)?;
+                if reader.plaintext_len() != chunk.stored as u64 {
+                    return Err(VaultError::Corrupt(format!("chunk record of {} holds {} bytes", encf, reader.plaintext_len())));
+                }
+                let mut stored: Vec<u8> = Vec::with_capacity(chunk.stored);
+                copy_exact(&mut reader, chunk.stored as u64, &mut stored, encf)?;
+                // chunk ids come from the key, so a new key means new ids
+                let hash = match rekeyed {
+                    true => chunk_id(&id_key, &Codec::from_id(chunk.codec)?.decompress(&stored, chunk.size)?),
+                    false => chunk.hash
+                };
+                let new_ref = write_chunk(&mut *out, &mut pos, &self.header, self.aes_pass, &stored, ChunkRef{offset: 0, hash, ..*chunk})?;
+                copied.insert(chunk.offset, new_ref);
+                known.insert(new_ref.hash, new_ref);
+                new_refs.push(new_ref);
+            }
+            *placed = Some((0, Some(new_refs), encf.digest));
         }
 
+        // then everything else gets split into chunks, which are looked up
+        // by id and only written if nothing has them yet
+        for (encf, placed) in self.files.iter().zip(placements.iter_mut()) {
+            if placed.is_some() {
+                continue;
+            }
+            // entries that were compressed before chunking stay compressed
+            let codec = match encf.codec {
+                Codec::None => self.codec,
+                a => a
+            };
+            let mut chunker = Chunker::new(self.entry_reader(encf)?);
+            let mut refs: Vec<ChunkRef> = Vec::new();
+            let mut total: usize = 0;
+            let mut digest = Hash::new();
+            while let Some(data) = chunker.next_chunk()? {
+                total += data.len();
+                digest.update(&data);
+                let hash = chunk_id(&id_key, &data);
+                let found = match known.get(&hash) {
+                    Some(a) => Some(*a),
+                    None if only_new => self.chunks.find(&hash),
+                    None => None
+                };
+                if let Some(a) = found {
+                    refs.push(a);
+                    continue;
+                }
+
+                let chunk = ChunkRef{offset: 0, stored: data.len(), size: data.len(), codec: Codec::None.id(), hash};
+                let new_ref = match codec {
+                    Codec::None => write_chunk(&mut *out, &mut pos, &self.header, self.aes_pass, &data, chunk)?,
+                    codec => {
+                        let (compressed, _) = codec.compress(&mut &data[..], Vec::new())?;
+                        if Codec::worth_it(data.len() as u64, compressed.len() as u64) {
+                            let chunk = ChunkRef{stored: compressed.len(), codec: codec.id(), ..chunk};
+                            write_chunk(&mut *out, &mut pos, &self.header, self.aes_pass, &compressed, chunk)?
+                        } else {
+                            write_chunk(&mut *out, &mut pos, &self.header, self.aes_pass, &data, chunk)?
+                        }
+                    }
+                };
+                known.insert(hash, new_ref);
+                refs.push(new_ref);
+            }
+            if total != encf.size {
+                return Err(VaultError::Io(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!("data for {} ended early", encf)
+                )));
+            }
+            *placed = Some((0, Some(refs), Some(digest.finalize())));
+        }
+        let placements: Vec<Placement> = placements.into_iter().flatten().collect();
+
+        // the table lists every entry, so it replaces all the tables before it
+        let mut table: Vec<u8> = MAGIC_BYTES.to_vec();
+        table.extend_from_slice(&encode_u64(self.num_files()));
+        for (encf, placed) in self.files.iter().zip(placements.iter()) {
+            table.extend_from_slice(&encf.table_entry(placed).serialize());
+        }
+        let mut rec = begin_record(&mut *out, &self.header, self.aes_pass, RecordKind::Table, table.len() as u64)?;
+        rec.write_all(&table)?;
+        rec.finish()?;
+
+        let end = pos + RECORD_HEADER_LEN as u64 + sealed_len(table.len() as u64);
+        Ok((placements, pos, end))
+    }
+
+    /// marks every entry as living in the archive that was just written
+    fn stored_at(&mut self, placements: Vec<Placement>, source: Source) -> Result<(), VaultError> {
+        for (idx, (encf, (offset, refs, digest))) in self.files.iter_mut().zip(placements).enumerate() {
+            encf.offset = offset;
+            encf.digest = digest;
+            if let Some(a) = refs {
+                encf.set_chunks(a);
+            }
+            encf.loaded = Some(idx);
+            encf.fdat = Vec::new();
+            encf.update_location(StorageLocation::DatFile);
+        }
+        self.base = self.files.iter().map(|f| f.name.clone()).collect();
+        self.index_chunks();
+        self.identity = Some(Identity::read(&source.path, source.table_offset)?);
+        self.source = Some(source);
         Ok(())
     }
 
+    /// rebuilds the chunk index from the chunks the saved entries point at
+    fn index_chunks(&mut self) {
+        self.chunks = ChunkIndex::default();
+        for encf in self.files.iter() {
+            for chunk in encf.chunks.iter().flatten() {
+                self.chunks.retain(chunk);
+            }
+        }
+    }
+
+    /// rewrites the archive with only the records its table still uses,
+    /// returning how many bytes that freed
+    pub fn compact(&mut self) -> Result<u64, VaultError> {
+        self.check_writable(&self.path)?;
+        let before = std::fs::metadata(&self.path)?.len();
+        self.rewrite(self.path.clone())?;
+        let after = std::fs::metadata(&self.path)?.len();
+        Ok(before.saturating_sub(after))
+    }
+
+    /// reads the decrypted contents of the latest table record
+    fn read_table_record(&self) -> Result<Vec<u8>, VaultError> {
+        let mut reader = self.open_source_record(self.source()?.table_offset, RecordKind::Table)?;
+        let mut table: Vec<u8> = Vec::new();
+        reader.read_to_end(&mut table)?;
+        Ok(table)
+    }
 
     /// attempt to parse the file table
-    pub fn parse_filetable(&mut self) -> Result<(), String> {
-        // get the number of available files from the data
-        let mut bytes = [0u8; 4];
-        bytes.copy_from_slice(&self.file_data[16..20]);
-        let num_files = u32::from_ne_bytes(bytes);
-        println!("Number of files in the store: {}", num_files);
-
-        // now that we know the number of files in the thing, try to read the 
-        // file's information from the table
-        let mut read_bytes = 16+8; // we have read the magic bytes and the number of files
-        for _ in 0..num_files {
-            let mut name_vec: Vec<u8> = Vec::new();
-            
-            // read until we find the nullterm of the file's name
-            while self.file_data[read_bytes] != 0 {
-                name_vec.push(self.file_data[read_bytes]);
-                read_bytes += 1;
-            }
-            
-            // we are now at the end of the string, so add one and find the file's size and offset values
-            read_bytes += 1;
-            let mut size_buf = [0u8; 8];
-            let mut offset_buf = [0u8; 8];
-            size_buf.copy_from_slice(&self.file_data[read_bytes..read_bytes+8]);
-            offset_buf.copy_from_slice(&self.file_data[read_bytes+8..read_bytes+16]);
-            let size = usize::from_ne_bytes(size_buf);
-            let offset = usize::from_ne_bytes(offset_buf);
-
-            // create a new file and append it to the structure
-            self.files.push(EncFile::new(name_vec, size, offset, None));
-
-            // update our read pointer
-            read_bytes += 16;
+    pub fn parse_filetable(&mut self) -> Result<(), VaultError> {
+        let entries = if self.source()?.header.version < FORMAT_VERSION {
+            // v2 archives keep the table at the front of one long stream
+            let mut reader = self.open_payload()?;
+            let payload_len = reader.plaintext_len() as usize;
+            let table = read_table(&mut reader, payload_len)?;
+            if !table.starts_with(MAGIC_BYTES) {
+                return Err(VaultError::Corrupt("magic bytes not found".to_string()));
+            }
+            parse_table(&table, payload_len)?
+        } else {
+            // the trailer points at the latest table, and every record it
+            // lists has to come before it
+            let mut f = std::fs::File::open(&self.source()?.path)?;
+            let (table_offset, end) = find_trailer(&mut f)?;
+            self.torn = f.metadata()?.len() - end;
+            if let Some(a) = self.source.as_mut() {
+                a.table_offset = table_offset;
+                a.end = end;
+            }
+            self.identity = Some(Identity::read(&self.source()?.path, table_offset)?);
+            let table = self.read_table_record()?;
+            if !table.starts_with(MAGIC_BYTES) {
+                return Err(VaultError::Corrupt("magic bytes not found".to_string()));
+            }
+            parse_record_table(&table, HEADER_LEN..table_offset as usize)?
+        };
+
+        // create a new file for each entry and append it to the structure
+        let mut files: Vec<EncFile> = Vec::with_capacity(entries.len());
+        for (idx, e) in entries.into_iter().enumerate() {
+            let mut encf = EncFile::from_entry(e)?;
+            encf.loaded = Some(idx);
+            files.push(encf);
         }
+        self.base = files.iter().map(|f| f.name.clone()).collect();
+        self.files = files;
+        self.index_chunks();
 
         Ok(())
     } 
 
     /// attempts to add a file to the store
-    pub fn add_file(&mut self, name: Vec<u8>, path: String) -> Result<(), String> {
-        // calculate the offset the file will have
-        let last_bytes = match self.files.last() {
-            Some(a) => a.offset + a.size, // find the offset of last file and add its size
-            None => 0 // there are no files, so offset is default
-        };
+    pub fn add_file<P: AsRef<Path>>(&mut self, name: Vec<u8>, path: P) -> Result<(), VaultError> {
+        self.add_path(name, path)
+    }
 
-        // try to read the data into a vec
-        let mut f = match std::fs::File::open(path) {
-            Ok(a) => a,
-            Err(e) => return Err(e.to_string())
-        };
-        let fsize = f.metadata().unwrap().len();
-        let mut dvec: Vec<u8> = Vec::new();
-        match f.read_to_end(&mut dvec) {
-            Ok(_) => (),
-            Err(e) => return Err(e.to_string())
-        };
+    /// adds a file to the store without reading it yet. Its contents are
+    /// streamed in from `path` when the archive is saved, but its metadata
+    /// is recorded now
+    fn add_path<P: AsRef<Path>>(&mut self, name: Vec<u8>, path: P) -> Result<(), VaultError> {
+        self.check_new_name(&name)?;
+        let mut encf = EncFile::new(name, 0, 0, None);
+        encf.meta = Some(Metadata::read(path.as_ref(), self.capture)?);
+        encf.set_file_data(path)?;
+        self.files.push(encf);
+        Ok(())
+    }
 
-        // try to push the file to the datafile and update its data content
-        self.files.push(EncFile::new(name, fsize as usize, last_bytes, Some(&dvec)));
+    /// adds a file to the store, reading its contents from `reader` a piece
+    /// at a time into a spool that is streamed in on save
+    pub fn add_reader<R: Read>(&mut self, name: Vec<u8>, mut reader: R) -> Result<(), VaultError> {
+        self.check_new_name(&name)?;
+        let mut key = [0u8; 32];
+        rand_bytes(&mut key)?;
+        let spool = Spool{file: tempfile::tempfile()?, cipher: self.header.cipher, key, nonce: new_nonce()?};
+
+        let mut writer = ChunkWriter::new(&spool.file, spool.cipher, spool.key, spool.nonce, Vec::new());
+        let size = std::io::copy(&mut reader, &mut writer)?;
+        writer.finish()?;
+
+        let mut encf = EncFile::new(name, size as usize, 0, None);
+        encf.update_location(StorageLocation::Spooled(spool));
+        self.files.push(encf);
         Ok(())
     }
 
-    /// returns the number of files stored in the structure
-    pub fn num_files(&self) -> usize {
-        self.files.len()
+    /// adds a file to the store from memory
+    pub fn add_bytes(&mut self, name: Vec<u8>, data: Vec<u8>) -> Result<(), VaultError> {
+        self.check_new_name(&name)?;
+
+        // the offset is only known once the archive is saved
+        self.files.push(EncFile::new(name, data.len(), 0, Some(data)));
+        Ok(())
     }
 
-    /// returns a vector of all parsed EncFiles
-    pub fn files(&self) -> &Vec<EncFile> {
-        &self.files
+    /// makes sure `name` can be used for a new entry
+    fn check_new_name(&self, name: &[u8]) -> Result<(), VaultError> {
+        // names are NUL terminated in the table, and the table can't hold two
+        // files under the same name
+        if !path::is_valid(name) {
+            return Err(VaultError::InvalidName(String::from_utf8_lossy(name).to_string()));
+        }
+        if self.find(name).is_some() || self.is_dir(name) {
+            return Err(VaultError::AlreadyExists(String::from_utf8_lossy(name).to_string()));
+        }
+        // and everything above it has to be a directory
+        for dir in path::ancestors(name) {
+            if let Some(f) = self.find(dir) {
+                if !f.is_dir() {
+                    return Err(VaultError::NotADirectory(String::from_utf8_lossy(dir).to_string()));
+                }
+            }
+        }
+        Ok(())
     }
 
-    /// returns a mutable vector of all parsed EncFiles 
-    pub fn files_mut(&mut self) -> &mut Vec<EncFile> {
-        &mut self.files
+    /// creates an empty directory. The directories above it don't need
+    /// entries of their own, since they exist as long as something is in them
+    pub fn mkdir(&mut self, name: Vec<u8>) -> Result<(), VaultError> {
+        self.check_new_name(&name)?;
+        let mut encf = EncFile::new(name, 0, 0, Some(Vec::new()));
+        encf.kind = EntryKind::Directory;
+        self.files.push(encf);
+        Ok(())
     }
 
-    /// dumps the decrypted data from the database to a file
-    pub fn dump_self(&mut self) {
-        let mut f = match std::fs::File::create("Dump.bin") {
-            Ok(a) => a,
-            Err(e) => {
-                println!("Failed to open file: {}", e);
-                return;
+    /// sets the metadata recorded for the entry stored under `name`
+    pub fn set_metadata(&mut self, name: &[u8], meta: Option<Metadata>) -> Result<(), VaultError> {
+        match self.files.iter_mut().find(|f| f.name == name) {
+            Some(a) => {
+                a.meta = meta;
+                Ok(())
+            },
+            None => Err(VaultError::NotFound(String::from_utf8_lossy(name).to_string()))
+        }
+    }
+
+    /// adds a symbolic link pointing at `target`, which is kept as it is
+    pub fn add_symlink(&mut self, name: Vec<u8>, target: Vec<u8>) -> Result<(), VaultError> {
+        if target.is_empty() || target.contains(&0) {
+            return Err(VaultError::InvalidName(String::from_utf8_lossy(&target).to_string()));
+        }
+        self.add_kind(name, EntryKind::Symlink, target, 0)
+    }
+
+    /// adds another name for the regular file stored under `target`, which
+    /// shares its data
+    pub fn add_hardlink(&mut self, name: Vec<u8>, target: Vec<u8>) -> Result<(), VaultError> {
+        if !self.find_or_err(&target)?.is_file() {
+            return Err(VaultError::NotAFile(String::from_utf8_lossy(&target).to_string()));
+        }
+        self.add_kind(name, EntryKind::Hardlink, target, 0)
+    }
+
+    /// adds a named pipe
+    pub fn add_fifo(&mut self, name: Vec<u8>) -> Result<(), VaultError> {
+        self.add_kind(name, EntryKind::Fifo, Vec::new(), 0)
+    }
+
+    /// adds a block device, or a character device if `block` isn't set,
+    /// with the device number `rdev`
+    pub fn add_device(&mut self, name: Vec<u8>, block: bool, rdev: u64) -> Result<(), VaultError> {
+        let kind = if block { EntryKind::BlockDevice } else { EntryKind::CharDevice };
+        self.add_kind(name, kind, Vec::new(), rdev)
+    }
+
+    /// adds an entry that has no data of its own
+    fn add_kind(&mut self, name: Vec<u8>, kind: EntryKind, target: Vec<u8>, rdev: u64) -> Result<(), VaultError> {
+        self.check_new_name(&name)?;
+        let mut encf = EncFile::new(name, 0, 0, Some(Vec::new()));
+        encf.kind = kind;
+        encf.target = target;
+        encf.rdev = rdev;
+        self.files.push(encf);
+        Ok(())
+    }
+
+    /// returns true if `name` is a directory, either because it has an entry
+    /// of its own or because there are entries below it. The root is the
+    /// empty name
+    pub fn is_dir(&self, name: &[u8]) -> bool {
+        name.is_empty() || self.files.iter().any(|f| (f.name == name && f.is_dir()) || path::is_within(&f.name, name))
+    }
+
+    /// lists what is directly inside the directory `dir`, sorted by name
+    pub fn read_dir(&self, dir: &[u8]) -> Result<Vec<DirEntry<'_>>, VaultError> {
+        if !self.is_dir(dir) {
+            return match self.find(dir) {
+                Some(_) => Err(VaultError::NotADirectory(String::from_utf8_lossy(dir).to_string())),
+                None => Err(VaultError::NotFound(String::from_utf8_lossy(dir).to_string()))
+            };
+        }
+
+        let mut children: BTreeMap<Vec<u8>, Option<&EncFile>> = BTreeMap::new();
+        for f in self.files.iter().filter(|f| path::is_within(&f.name, dir)) {
+            let rest = match dir.is_empty() {
+                true => &f.name[..],
+                false => &f.name[dir.len()+1..]
+            };
+            match rest.iter().position(|&b| b == path::SEPARATOR) {
+                Some(a) => {
+                    children.entry(rest[..a].to_vec()).or_insert(None);
+                },
+                None => {
+                    children.insert(rest.to_vec(), Some(f));
+                }
+            };
+        }
+        Ok(children.into_iter().map(|(name, file)| DirEntry{name, file}).collect())
+    }
+
+    /// returns the file stored under `name`
+    pub fn find(&self, name: &[u8]) -> Option<&EncFile> {
+        self.files.iter().find(|f| f.name == name)
+    }
+
+    /// returns the file stored under `name`, or a NotFound error
+    fn find_or_err(&self, name: &[u8]) -> Result<&EncFile, VaultError> {
+        match self.find(name) {
+            Some(a) => Ok(a),
+            None => Err(VaultError::NotFound(String::from_utf8_lossy(name).to_string()))
+        }
+    }
+
+    /// renames the file stored under `old` to `new`. Renaming a directory
+    /// moves everything below it too
+    pub fn rename(&mut self, old: &[u8], new: Vec<u8>) -> Result<(), VaultError> {
+        if old.is_empty() || !self.is_dir(old) {
+            self.find_or_err(old)?;
+        }
+        self.check_new_name(&new)?;
+        if path::is_within(&new, old) {
+            return Err(VaultError::InvalidName(String::from_utf8_lossy(&new).to_string()));
+        }
+        let moved = |name: &[u8]| name == old || path::is_within(name, old);
+        for f in self.files.iter_mut() {
+            if moved(&f.name) {
+                let mut name = new.clone();
+                name.extend_from_slice(&f.name[old.len()..]);
+                f.name = name;
+            }
+            // hard links follow the file they point at
+            if f.kind == EntryKind::Hardlink && moved(&f.target) {
+                let mut target = new.clone();
+                target.extend_from_slice(&f.target[old.len()..]);
+                f.target = target;
             }
+        }
+        Ok(())
+    }
+
+    /// removes the file stored under `name`. Directories have to be empty
+    pub fn remove(&mut self, name: &[u8]) -> Result<(), VaultError> {
+        if self.files.iter().any(|f| path::is_within(&f.name, name)) {
+            return Err(VaultError::NotEmpty(String::from_utf8_lossy(name).to_string()));
+        }
+        let index = match self.files.iter().position(|f| f.name == name) {
+            Some(a) => a,
+            None => return Err(VaultError::NotFound(String::from_utf8_lossy(name).to_string()))
         };
+        self.remove_file_idx(index)
+    }
 
-        f.write(&self.file_data[..]).unwrap();
+    /// reads the contents of the file stored under `name` into memory
+    pub fn read_entry(&self, name: &[u8]) -> Result<Vec<u8>, VaultError> {
+        let mut out: Vec<u8> = Vec::new();
+        self.write_entry(self.find_or_err(name)?, &mut out)?;
+        Ok(out)
     }
 
-    /// returns the serialized content of the database
-    fn get_file_content(&mut self) -> Result<Vec<u8>, String> {
-        let mut write_buffer: Vec<u8> = Vec::new();
+    /// opens a handle to the contents of the file stored under `name`
+    pub fn open_entry(&self, name: &[u8]) -> Result<EntryReader<'_>, VaultError> {
+        self.entry_reader(self.find_or_err(name)?)
+    }
 
-        // write the magic data
-        for byte in MAGIC_BYTES {
-            write_buffer.push(*byte);
+    /// opens a handle to the contents of an EncFile that can be read and
+    /// seeked through without pulling the whole file into memory
+    pub fn entry_reader<'a>(&'a self, file: &'a EncFile) -> Result<EntryReader<'a>, VaultError> {
+        let file = self.data_of(file)?;
+        if let Some(refs) = &file.chunks {
+            let source = self.source()?;
+            let f = File::open(&source.path)?;
+            let reader = ChunkedReader::new(f, source.header, source.key, refs.clone());
+            return Ok(EntryReader::new(Box::new(reader), 0, file.size as u64));
         }
-        
-        // write the number of files available (as u32)
-        for byte in self.num_files().to_ne_bytes() {
-            write_buffer.push(byte);
+        let (inner, start) = self.stored_reader(file)?;
+        if file.codec == Codec::None {
+            return Ok(EntryReader::new(inner, start, file.size as u64));
         }
+        // compressed entries always start at the front of their record
+        Ok(EntryReader::new(Box::new(DecodeReader::new(file.codec, inner)?), 0, file.size as u64))
+    }
 
-        let mut offset_ctr = self.get_table_size() + 24;
+    /// returns the regular file holding `file`'s data, which is the file
+    /// itself unless it is a hard link
+    fn data_of<'a>(&'a self, file: &'a EncFile) -> Result<&'a EncFile, VaultError> {
+        match file.kind {
+            EntryKind::File => Ok(file),
+            EntryKind::Hardlink => self.find_or_err(&file.target),
+            EntryKind::Directory => Err(VaultError::IsDirectory(String::from_utf8_lossy(&file.name).to_string())),
+            _ => Err(VaultError::NotAFile(String::from_utf8_lossy(&file.name).to_string()))
+        }
+    }
 
-        // calculate and update the offsets of each file, as well as write the data
-        for encf in self.files.iter_mut() {
-            // Make sure the EncFile has its data stored locally 
-            match &encf.location {
-                StorageLocation::DatFile => {
-                    let mut  dat = vec![0u8; encf.size];
-                    let data = &self.file_data[encf.offset..encf.offset+encf.size];
-                    dat.copy_from_slice(data);
-                    match encf.set_file_vec(&mut dat) {
-                        Ok(_) => (),
-                        Err(e) => return Err(e)
-                    };
-                },
-                StorageLocation::OwnMem => ()
+    /// opens the data of an EncFile the way it is stored, so still
+    /// compressed, returning the reader along with where the data starts
+    fn stored_reader<'a>(&'a self, file: &'a EncFile) -> Result<(Box<dyn ReadSeek + 'a>, u64), VaultError> {
+        Ok(match &file.location {
+            StorageLocation::OwnMem => (Box::new(Cursor::new(&file.fdat[..])), 0),
+            StorageLocation::External(path) => (Box::new(std::fs::File::open(path)?), 0),
+            StorageLocation::Spooled(spool) => {
+                let mut f = &spool.file;
+                f.seek(SeekFrom::Start(0))?;
+                let reader = ChunkReader::new(f, sealed_len(file.size as u64), spool.cipher, spool.key, spool.nonce, Vec::new())?;
+                (Box::new(reader), 0)
+            },
+            // the data is still in the archive, so only its chunks get decrypted
+            StorageLocation::DatFile => {
+                let (reader, start) = self.open_stored(file)?;
+                (Box::new(reader), start)
+            }
+        })
+    }
+
+    /// writes the contents of an EncFile to `out`
+    pub fn write_entry<W: Write>(&self, file: &EncFile, out: &mut W) -> Result<(), VaultError> {
+        let file = self.data_of(file)?;
+        let mut reader = self.entry_reader(file)?;
+        copy_exact(&mut reader, file.size as u64, out, file)
+    }
+
+    /// works out how much space the saved entries take up in the archive,
+    /// and how much of it chunk sharing and compression saved
+    pub fn stats(&self) -> Result<Stats, VaultError> {
+        let source = self.source()?;
+        let mut stats = Stats{archive: std::fs::metadata(&source.path)?.len(), ..Stats::default()};
+        let record_len = |stored: usize| RECORD_HEADER_LEN as u64 + sealed_len(stored as u64);
+
+        for encf in self.files.iter().filter(|f| f.is_file()) {
+            if !matches!(encf.location, StorageLocation::DatFile) {
+                stats.unsaved += 1;
+                continue;
+            }
+            stats.entries += 1;
+            stats.logical += encf.size as u64;
+            match &encf.chunks {
+                Some(a) => stats.chunk_refs += a.len(),
+                // entries saved before chunking have a record to themselves
+                None => {
+                    stats.unique += encf.size as u64;
+                    stats.stored += record_len(encf.stored);
+                }
             };
+        }
+        for (chunk, _) in self.chunks.chunks() {
+            stats.chunks += 1;
+            stats.unique += chunk.size as u64;
+            stats.stored += record_len(chunk.stored);
+        }
 
-            // update our counters
-            encf.offset = offset_ctr;
-            offset_ctr += encf.size;
-            
+        // everything else but the header, the latest table and its trailer
+        // is dead space
+        let live = HEADER_LEN as u64 + stats.stored + (source.end - source.table_offset);
+        stats.reclaimable = stats.archive.saturating_sub(live);
+        Ok(stats)
+    }
+
+    /// checks the whole archive: that its header is still the one we opened,
+    /// that every record authenticates and nothing else lies between them,
+    /// that every saved entry's data is made of whole data records of the
+    /// right size, and that the data still matches its digest. Problems go
+    /// in the report rather than stopping the check
+    pub fn verify(&self) -> Result<Report, VaultError> {
+        let source = self.source()?;
+        let mut report = Report{torn: self.torn, ..Report::default()};
+        let mut f = BufReader::new(File::open(&source.path)?);
+        match Header::read(&mut f) {
+            Ok(a) if a == source.header => (),
+            Ok(_) => report.at(0, "header changed since the archive was opened".to_string()),
+            Err(e) => report.at(0, format!("header is unreadable: {}", e))
+        };
+
+        // every record, whether the latest table still uses it or not
+        let mut records: HashMap<u64, (RecordKind, u64)> = HashMap::new();
+        for span in scan(&mut f, HEADER_LEN as u64, source.end, &source.header, source.key)? {
+            match span {
+                Span::Record{offset, kind, len} => {
+                    report.records += 1;
+                    records.insert(offset, (kind, len));
+                },
+                Span::Damaged{offset, len, reason} => report.at(offset, format!("{} bytes that fail to authenticate as a record: {}", len, reason)),
+                Span::Gap{offset, len} => report.at(offset, format!("{} bytes that are not part of any record", len)),
+                Span::Trailer{..} => ()
+            };
+        }
+        if !matches!(records.get(&source.table_offset), Some((RecordKind::Table, _))) {
+            report.at(source.table_offset, "latest table record is missing".to_string());
+        }
+        // an interrupted save leaves this, but so does damage that hides the
+        // latest trailer, and either way the next save drops it
+        if self.torn > 0 {
+            report.at(source.end, format!("{} bytes after the last complete save, left by an interrupted save or damage", self.torn));
+        }
 
-            // write the serialized table entry
-            for byte in encf.serialize() {
-                write_buffer.push(byte);
+        for encf in self.files.iter().filter(|f| f.is_file() && matches!(f.location, StorageLocation::DatFile)) {
+            report.entries += 1;
+            let spans: Vec<(usize, usize)> = match &encf.chunks {
+                Some(refs) => refs.iter().map(|c| (c.offset, c.stored)).collect(),
+                None => vec![(encf.offset, encf.stored)]
+            };
+            let mut intact = true;
+            for (offset, stored) in spans {
+                let problem = match records.get(&(offset as u64)) {
+                    Some((RecordKind::Data, len)) if *len == stored as u64 => continue,
+                    Some((RecordKind::Data, len)) => format!("record holds {} bytes, where {} were expected", len, stored),
+                    Some((kind, _)) => format!("points at a {} record instead of a data record", kind),
+                    None => "points at no record that authenticates".to_string()
+                };
+                report.entry(&encf.name, Some(offset as u64), problem);
+                intact = false;
+            }
+            if !intact {
+                continue;
             }
+
+            // reading it back also catches data that won't decompress
+            let res = self.entry_reader(encf).and_then(|reader| Ok(verify::digest(reader)?));
+            match (res, encf.digest) {
+                (Err(e), _) => report.entry(&encf.name, None, format!("data is unreadable: {}", e)),
+                (Ok((digest, len)), recorded) => {
+                    report.bytes += len;
+                    match recorded {
+                        Some(a) if a != digest => report.entry(&encf.name, None, "data doesn't match its digest".to_string()),
+                        Some(_) => (),
+                        None => report.undigested += 1
+                    };
+                }
+            };
         }
+        Ok(report)
+    }
 
-        // now write all the file's data sequentially
-        for encf in self.files.iter() {
-            // write the data
-            let fdat = match encf.get_fdat() {
+    /// salvages what it can of the damaged archive at `path` into a new
+    /// archive at `out`, which mustn't exist yet. Every record is checked,
+    /// the entries come from the newest table that still reads, and only
+    /// entries whose data is all in records that authenticate are kept.
+    /// Data records no table uses are saved in `lost+found`. The new archive
+    /// has the same password and settings
+    pub fn recover(path: String, aes_pass: String, out: String) -> Result<Recovery, VaultError> {
+        if std::fs::symlink_metadata(&out).is_ok() {
+            return Err(VaultError::AlreadyExists(out));
+        }
+        let mut f = BufReader::new(File::open(&path)?);
+        let header = Header::read(&mut f)?;
+        if header.version != FORMAT_VERSION {
+            return Err(VaultError::Unsupported(format!("only format v{} archives can be recovered", FORMAT_VERSION)));
+        }
+        let key = header.kdf.derive_key(&aes_pass, &header.salt)?;
+        let len = f.get_ref().metadata()?.len();
+
+        // the torn tail too, since an interrupted save may have left whole
+        // records behind
+        let mut report = Recovery::default();
+        let mut records: HashMap<u64, (RecordKind, u64)> = HashMap::new();
+        for span in scan(&mut f, HEADER_LEN as u64, len, &header, key)? {
+            match span {
+                Span::Record{offset, kind, len} => {
+                    records.insert(offset, (kind, len));
+                },
+                Span::Damaged{offset, len, reason} => report.damaged.push((offset, len, reason)),
+                Span::Gap{offset, len} => report.damaged.push((offset, len, "not part of any record".to_string())),
+                Span::Trailer{..} => ()
+            };
+        }
+        // with the wrong key nothing authenticates, which is no reason to
+        // write out an empty archive
+        if records.is_empty() && !report.damaged.is_empty() {
+            return Err(VaultError::Authentication);
+        }
+
+        let mut df = Datafile::new(out.clone(), key, header);
+        df.source = Some(Source{path, key, header, table_offset: 0, end: len});
+        df.backups = 0;
+
+        // every table that reads, newest first. The newest one gives the
+        // entries, and all of them say which data isn't lost+found
+        let mut tables: Vec<u64> = records.iter().filter(|(_, (kind, _))| *kind == RecordKind::Table).map(|(offset, _)| *offset).collect();
+        tables.sort_unstable_by(|a, b| b.cmp(a));
+        let mut used: HashSet<u64> = HashSet::new();
+        let mut files: Option<Vec<EncFile>> = None;
+        for offset in tables {
+            if let Some(a) = df.source.as_mut() {
+                a.table_offset = offset;
+            }
+            let res = df.read_table_record().and_then(|table| {
+                if !table.starts_with(MAGIC_BYTES) {
+                    return Err(VaultError::Corrupt("magic bytes not found".to_string()));
+                }
+                parse_record_table(&table, HEADER_LEN..offset as usize)?.into_iter().map(EncFile::from_entry).collect::<Result<Vec<EncFile>, VaultError>>()
+            });
+            let entries = match res {
                 Ok(a) => a,
-                Err(e) => return Err(e)
+                Err(e) if files.is_none() => {
+                    report.unusable_tables.push((offset, e.to_string()));
+                    continue;
+                },
+                Err(_) => continue
+            };
+            for encf in entries.iter().filter(|f| f.is_file()) {
+                match &encf.chunks {
+                    Some(refs) => used.extend(refs.iter().map(|c| c.offset as u64)),
+                    None => {
+                        used.insert(encf.offset as u64);
+                    }
+                };
+            }
+            if files.is_none() {
+                report.table = Some(offset);
+                files = Some(entries);
+            }
+        }
+
+        // only files whose every byte is in a record that authenticated
+        let intact = |offset: usize, stored: usize| matches!(records.get(&(offset as u64)), Some((RecordKind::Data, len)) if *len == stored as u64);
+        let mut kept: Vec<EncFile> = Vec::new();
+        let mut gone: HashSet<Vec<u8>> = HashSet::new();
+        let files = files.unwrap_or_default();
+        for encf in files.iter().filter(|f| f.is_file()) {
+            let mut missing: Vec<String> = Vec::new();
+            match &encf.chunks {
+                Some(refs) => {
+                    let mut pos: usize = 0;
+                    for c in refs.iter() {
+                        if !intact(c.offset, c.stored) {
+                            missing.push(format!("bytes {}..{} (record at byte {})", pos, pos + c.size, c.offset));
+                        }
+                        pos += c.size;
+                    }
+                },
+                None if !intact(encf.offset, encf.stored) => missing.push(format!("all {} bytes (record at byte {})", encf.size, encf.offset)),
+                None => ()
             };
-            for byte in fdat.iter() {
-                write_buffer.push(*byte);
+            if !missing.is_empty() {
+                report.lost.push((encf.name.clone(), format!("damaged or missing: {}", missing.join(", "))));
+                gone.insert(encf.name.clone());
+            }
+        }
+        for mut encf in files {
+            if gone.contains(&encf.name) {
+                continue;
             }
+            if encf.kind == EntryKind::Hardlink && gone.contains(&encf.target) {
+                report.lost.push((encf.name.clone(), format!("the file it links to, {}, was lost", String::from_utf8_lossy(&encf.target))));
+                continue;
+            }
+            encf.loaded = None;
+            kept.push(encf);
+        }
+        report.recovered = kept.len();
+        df.files = kept;
+
+        // data records nothing points at, most likely from a save whose
+        // table was lost. They are kept as they are stored, so possibly
+        // still compressed
+        let mut orphans: Vec<u64> = records.iter().filter(|(offset, (kind, _))| *kind == RecordKind::Data && !used.contains(offset)).map(|(offset, _)| *offset).collect();
+        orphans.sort_unstable();
+        for offset in orphans {
+            let name = path::join(LOST_AND_FOUND, offset.to_string().as_bytes());
+            let mut data: Vec<u8> = Vec::new();
+            let res = df.open_source_record(offset, RecordKind::Data)
+                .and_then(|mut reader| Ok(reader.read_to_end(&mut data)?))
+                .and_then(|_| df.add_bytes(name.clone(), data));
+            match res {
+                Ok(_) => report.found.push((name, records[&offset].1)),
+                Err(e) => report.lost.push((name, e.to_string()))
+            };
         }
 
-        Ok(write_buffer)
+        df.save(out)?;
+        Ok(report)
+    }
 
+    /// returns the SHA-256 recorded for a file's data, or for the data of the
+    /// file a hard link points at, without reading anything
+    pub fn recorded_digest(&self, file: &EncFile) -> Option<[u8; DIGEST_LEN]> {
+        self.data_of(file).ok().and_then(|f| f.digest)
     }
 
-    /// returns the size of the table structure as it stands right now
-    fn get_table_size(&self) -> usize {
-        let mut size = 0;
-        // loop over each file and get its serialized size
-        for file in self.files.iter() {
-            size += file.serialize().len();
+    /// returns the SHA-256 of a file's data, or of the file a hard link
+    /// points at. That is the digest recorded when it was saved, or for files
+    /// saved before digests were, worked out by reading the data back
+    pub fn digest_of(&self, file: &EncFile) -> Result<[u8; DIGEST_LEN], VaultError> {
+        let data = self.data_of(file)?;
+        match data.digest {
+            Some(a) => Ok(a),
+            None => Ok(verify::digest(self.entry_reader(data)?)?.0)
         }
+    }
 
-        // return the size
-        size
+    /// returns the number of files stored in the structure
+    pub fn num_files(&self) -> usize {
+        self.files.len()
     }
 
-    /// updates the AES passphrase for the database
-    pub fn update_pass(&mut self, pass: String) {
-        self.aes_pass = pass_to_hash(pass)
+    /// returns a vector of all parsed EncFiles
+    pub fn files(&self) -> &Vec<EncFile> {
+        &self.files
     }
 
-    /// saves the decoded EncFile to a path 
-    pub fn save_to_file(&self, file: &EncFile, path: String) -> Result<(), String> {
-        let mut file_handle = match std::fs::File::create(path) {
-            Ok(a) => a,
-            Err(e) => panic!("Failed to open file: {}", e)
-        };
-        let size = file.size;
+    /// returns a mutable vector of all parsed EncFiles 
+    pub fn files_mut(&mut self) -> &mut Vec<EncFile> {
+        &mut self.files
+    }
 
-        let mut retvec: Vec<u8> = vec![0u8; size];
-                
-        let data = match file.get_fdat() {
-            Ok(a) => a,
-            Err(_) => { 
-                // we know that the data is not stored in the file structure
-                // itself, so we manually fetch it internally
-                let offset = file.offset;
-                retvec.copy_from_slice(&self.file_data[offset..offset+size]);
-                &retvec
-            }
-        };
+    /// dumps the decrypted file table from the database to a file
+    pub fn dump_self(&mut self) -> Result<(), VaultError> {
+        let table = self.read_table_record()?;
+        let mut f = std::fs::File::create("Dump.bin")?;
+        f.write_all(&table)?;
+        Ok(())
+    }
 
-        file_handle.write_all(data).unwrap();
-        
+    /// updates the AES passphrase for the database, rekeying it with a fresh salt
+    pub fn update_pass(&mut self, pass: String) -> Result<(), VaultError> {
+        let salt = new_salt()?;
+        self.aes_pass = self.header.kdf.derive_key(&pass, &salt)?;
+        self.header.salt = salt;
         Ok(())
     }
 
+    /// saves the decoded EncFile to a new file at a path, then puts back the
+    /// parts of its metadata `opts` asks for. What is already at the path is
+    /// only replaced if `opts.overwrite` says so, and symlinks there are
+    /// never followed
+    pub fn save_to_file<P: AsRef<Path>>(&self, file: &EncFile, path: P, opts: &ExtractOptions) -> Result<(), VaultError> {
+        let data = self.data_of(file)?;
+        let out = path.as_ref();
+        let meta = file.meta.as_ref();
+        if !extract::make_room(out, opts, meta.map(|m| m.mtime))? {
+            return Err(VaultError::AlreadyExists(out.to_string_lossy().to_string()));
+        }
+        let mut file_handle = extract::create_new(out, meta, &opts.restore)?;
+        // a file we created but couldn't fill is of no use to anyone
+        if let Err(e) = self.write_entry(data, &mut file_handle) {
+            drop(file_handle);
+            let _ = std::fs::remove_file(out);
+            return Err(e);
+        }
+        drop(file_handle);
+        match meta {
+            Some(meta) => meta.apply(out, &opts.restore),
+            None => Ok(())
+        }
+    }
+
     /// removes an EncFile by reference
-    pub fn remove_file(&mut self, file: &EncFile) {
-        // find the index
-        let index = self.files.iter().position(|x| x == file).unwrap();
-        self.files.remove(index);
+    pub fn remove_file(&mut self, file: &EncFile) -> Result<(), VaultError> {
+        let name = file.name.clone();
+        self.remove(&name)
     }
 
     /// removes an EncFile by index
-    pub fn remove_file_idx(&mut self, file_index: usize) {
-        self.files.remove(file_index);
+    pub fn remove_file_idx(&mut self, file_index: usize) -> Result<(), VaultError> {
+        if file_index >= self.files.len() {
+            return Err(VaultError::NotFound(format!("at index {}", file_index)));
+        }
+        // a hard link to the file takes over its data, so the other links
+        // to it keep working
+        let name = self.files[file_index].name.clone();
+        let link = self.files.iter().position(|f| f.kind == EntryKind::Hardlink && f.target == name);
+        if let Some(link) = link {
+            let link = self.files.remove(link);
+            for f in self.files.iter_mut().filter(|f| f.kind == EntryKind::Hardlink && f.target == name) {
+                f.target = link.name.clone();
+            }
+            if let Some(f) = self.files.iter_mut().find(|f| f.name == name) {
+                f.name = link.name;
+            }
+            return Ok(());
+        }
+
+        // its chunks stay in the archive until it is compacted, but nothing
+        // new gets pointed at them
+        let encf = self.files.remove(file_index);
+        for chunk in encf.chunks.iter().flatten() {
+            self.chunks.release(chunk);
+        }
+        Ok(())
     }
 
 }
+
+/// writes a data record holding the stored bytes of `chunk` at byte `pos`
+/// of the archive, returning the chunk with its offset filled in
+fn write_chunk<W: Write>(out: &mut W, pos: &mut u64, header: &Header, key: [u8; 32], stored: &[u8], chunk: ChunkRef) -> Result<ChunkRef, VaultError> {
+    let mut rec = begin_record(&mut *out, header, key, RecordKind::Data, stored.len() as u64)?;
+    rec.write_all(stored)?;
+    rec.finish()?;
+    let chunk = ChunkRef{offset: *pos as usize, stored: stored.len(), ..chunk};
+    *pos += RECORD_HEADER_LEN as u64 + sealed_len(stored.len() as u64);
+    Ok(chunk)
+}
+
+/// copies exactly `len` bytes of `file`'s data from `reader` to `out`
+fn copy_exact<R: Read, W: Write>(reader: &mut R, len: u64, out: &mut W, file: &EncFile) -> Result<(), VaultError> {
+    let copied = std::io::copy(&mut reader.take(len), out)?;
+    if copied != len {
+        return Err(VaultError::Io(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("data for {} ended early", file)
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+
+use hmac_sha256::HMAC;
+
+use crate::codec::Codec;
+use crate::error::VaultError;
+use crate::header::Header;
+use crate::record::{RecordKind, open_record};
+use crate::table::ChunkRef;
+
+
+/// Chunks are never cut shorter than this, unless the entry ends first
+pub const MIN_CHUNK: usize = 16 * 1024;
+
+/// Chunks are always cut once they get this long
+pub const MAX_CHUNK: usize = 256 * 1024;
+
+/// A chunk ends wherever the top 16 bits of the rolling hash are all zero,
+/// which on average happens every 64 KiB
+const CUT_MASK: u64 = 0xffff << 48;
+
+/// How many bytes a gear hash looks back. Older bytes get shifted out
+const GEAR_WINDOW: usize = 64;
+
+/// The random value each byte adds to the rolling hash
+const GEAR: [u64; 256] = gear_table();
+
+
+////////////////////////// DEFINITIONS /////////////////////////////////////
+/// Splits a stream into chunks wherever its content says to, so inserting
+/// or removing data only changes the chunks around the edit
+pub struct Chunker<R: Read> {
+    inner: R,
+    buf: Vec<u8>,
+    eof: bool
+}
+
+/// Every chunk the entries of an archive point at, with how many references
+/// each one has
+#[derive(Default)]
+pub(crate) struct ChunkIndex {
+    chunks: HashMap<usize, (ChunkRef, usize)>,
+    by_hash: HashMap<[u8; 32], usize>
+}
+
+/// Reads the original data of an entry that is split into chunks, opening
+/// each chunk's record as it is reached
+pub(crate) struct ChunkedReader {
+    file: BufReader<File>,
+    header: Header,
+    key: [u8; 32],
+    refs: Vec<ChunkRef>,
+    /// where each chunk starts in the entry
+    starts: Vec<u64>,
+    len: u64,
+    pos: u64,
+    loaded: Option<usize>,
+    buf: Vec<u8>
+}
+
+/// How much space an archive's data takes up, and how much sharing chunks
+/// and compressing them saved
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stats {
+    /// how many entries the archive has saved
+    pub entries: usize,
+    /// how many entries were added but not saved yet
+    pub unsaved: usize,
+    /// total size of the saved entries
+    pub logical: u64,
+    /// how many chunk references the entries make
+    pub chunk_refs: usize,
+    /// how many distinct chunks those references point at
+    pub chunks: usize,
+    /// original size of the distinct data, so of every chunk once plus the
+    /// entries that aren't split into chunks
+    pub unique: u64,
+    /// how many bytes of records that data takes up once compressed and sealed
+    pub stored: u64,
+    /// size of the archive on disk
+    pub archive: u64,
+    /// how many bytes of the archive `compact` would free
+    pub reclaimable: u64
+}
+
+
+/// builds the gear table from splitmix64, so it is fixed without having to
+/// be written out
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x5641_554c_545f_4344;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// finds where the chunk at the front of `data` ends. `data` holds at least
+/// `MAX_CHUNK` bytes unless the stream ends sooner
+fn cut_point(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK {
+        return data.len();
+    }
+    let end = std::cmp::min(data.len(), MAX_CHUNK);
+
+    // only the last GEAR_WINDOW bytes matter, so start hashing just in time
+    // for the first place we may cut
+    let mut hash: u64 = 0;
+    for (i, &b) in data[..end].iter().enumerate().skip(MIN_CHUNK - GEAR_WINDOW) {
+        hash = (hash << 1).wrapping_add(GEAR[b as usize]);
+        if i >= MIN_CHUNK && hash & CUT_MASK == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+/// derives the key chunk ids are made with from the archive's key, so equal
+/// chunks can be found without the ids giving away what they hold
+pub fn chunk_key(key: &[u8; 32]) -> [u8; 32] {
+    HMAC::mac(b"vault chunk ids", key)
+}
+
+/// returns the id of a chunk holding `data`
+pub fn chunk_id(chunk_key: &[u8; 32], data: &[u8]) -> [u8; 32] {
+    HMAC::mac(data, chunk_key)
+}
+
+
+////////////////////////// CHUNKER FUNCTIONS //////////////////////////
+impl<R: Read> Chunker<R> {
+    /// starts splitting `inner` into chunks
+    pub fn new(inner: R) -> Self {
+        Chunker{inner, buf: Vec::with_capacity(MAX_CHUNK), eof: false}
+    }
+
+    /// returns the next chunk, or None once the stream is used up
+    pub fn next_chunk(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        // keep a whole chunk's worth buffered, so where we cut doesn't
+        // depend on how the reads happened to be split up
+        while !self.eof && self.buf.len() < MAX_CHUNK {
+            let have = self.buf.len();
+            self.buf.resize(MAX_CHUNK, 0);
+            let res = self.inner.read(&mut self.buf[have..]);
+            let got = match res {
+                Ok(a) => a,
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => 0,
+                Err(e) => {
+                    self.buf.truncate(have);
+                    return Err(e);
+                }
+            };
+            self.buf.truncate(have + got);
+            self.eof = matches!(res, Ok(0));
+        }
+
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        let cut = cut_point(&self.buf);
+        Ok(Some(self.buf.drain(..cut).collect()))
+    }
+}
+
+
+////////////////////////// CHUNKINDEX FUNCTIONS //////////////////////////
+impl ChunkIndex {
+    /// adds a reference to a chunk
+    pub fn retain(&mut self, chunk: &ChunkRef) {
+        let entry = self.chunks.entry(chunk.offset).or_insert((*chunk, 0));
+        entry.1 += 1;
+        self.by_hash.entry(chunk.hash).or_insert(chunk.offset);
+    }
+
+    /// drops a reference to a chunk, forgetting it once nothing points at it.
+    /// Its record is dead space from then on, until the archive is compacted
+    pub fn release(&mut self, chunk: &ChunkRef) {
+        let refs = match self.chunks.get_mut(&chunk.offset) {
+            Some(a) => {
+                a.1 -= 1;
+                a.1
+            },
+            None => return
+        };
+        if refs == 0 {
+            self.chunks.remove(&chunk.offset);
+            if self.by_hash.get(&chunk.hash) == Some(&chunk.offset) {
+                self.by_hash.remove(&chunk.hash);
+            }
+        }
+    }
+
+    /// returns the stored chunk with the given id
+    pub fn find(&self, hash: &[u8; 32]) -> Option<ChunkRef> {
+        let offset = self.by_hash.get(hash)?;
+        self.chunks.get(offset).map(|a| a.0)
+    }
+
+    /// returns every chunk along with how many references it has
+    pub fn chunks(&self) -> impl Iterator<Item = &(ChunkRef, usize)> {
+        self.chunks.values()
+    }
+}
+
+
+////////////////////////// CHUNKEDREADER FUNCTIONS //////////////////////////
+impl ChunkedReader {
+    /// starts reading the entry made up of `refs` from the archive `file`
+    pub fn new(file: File, header: Header, key: [u8; 32], refs: Vec<ChunkRef>) -> Self {
+        let mut starts: Vec<u64> = Vec::with_capacity(refs.len());
+        let mut len: u64 = 0;
+        for r in refs.iter() {
+            starts.push(len);
+            len += r.size as u64;
+        }
+        ChunkedReader{file: BufReader::new(file), header, key, refs, starts, len, pos: 0, loaded: None, buf: Vec::new()}
+    }
+
+    /// decrypts and decompresses the chunk at `index` into the buffer
+    fn load(&mut self, index: usize) -> Result<(), VaultError> {
+        let chunk = self.refs[index];
+        self.loaded = None;
+        let mut rec = open_record(&mut self.file, chunk.offset as u64, &self.header, self.key, RecordKind::Data)?;
+        if rec.plaintext_len() != chunk.stored as u64 {
+            return Err(VaultError::Corrupt(format!("chunk record at byte {} holds {} bytes", chunk.offset, rec.plaintext_len())));
+        }
+        let mut stored: Vec<u8> = Vec::with_capacity(chunk.stored);
+        rec.read_to_end(&mut stored)?;
+
+        self.buf = Codec::from_id(chunk.codec)?.decompress(&stored, chunk.size)?;
+        if self.buf.len() != chunk.size {
+            return Err(VaultError::Corrupt(format!("chunk record at byte {} holds the wrong amount of data", chunk.offset)));
+        }
+        self.loaded = Some(index);
+        Ok(())
+    }
+}
+
+impl Read for ChunkedReader {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.len || out.is_empty() {
+            return Ok(0);
+        }
+        // the last chunk that starts at or before where we are
+        let index = self.starts.partition_point(|&s| s <= self.pos) - 1;
+        if self.loaded != Some(index) {
+            self.load(index)?;
+        }
+
+        let start = (self.pos - self.starts[index]) as usize;
+        let len = std::cmp::min(out.len(), self.buf.len() - start);
+        out[..len].copy_from_slice(&self.buf[start..start+len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for ChunkedReader {
+    fn seek(&mut self, to: SeekFrom) -> std::io::Result<u64> {
+        let pos = match to {
+            SeekFrom::Start(a) => Some(a),
+            SeekFrom::End(a) => self.len.checked_add_signed(a),
+            SeekFrom::Current(a) => self.pos.checked_add_signed(a)
+        };
+        match pos {
+            Some(a) => {
+                self.pos = a;
+                Ok(a)
+            },
+            None => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek to a negative position"))
+        }
+    }
+}
+
+
+/////////////////////////// DISPLAY IMPL ////////////////////////////////////
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "entries      {} ({} unsaved)", self.entries, self.unsaved)?;
+        writeln!(f, "data         {} bytes", self.logical)?;
+        writeln!(f, "chunks       {} references to {} chunks", self.chunk_refs, self.chunks)?;
+        writeln!(f, "unique data  {} bytes (dedup ratio {:.2}x)", self.unique, self.dedup_ratio())?;
+        writeln!(f, "stored       {} bytes (compression ratio {:.2}x)", self.stored, self.compression_ratio())?;
+        write!(f, "archive      {} bytes, {} reclaimable by compact", self.archive, self.reclaimable)
+    }
+}
+
+
+////////////////////////// STATS FUNCTIONS //////////////////////////
+impl Stats {
+    /// returns how many times over the entries would fill the distinct data
+    pub fn dedup_ratio(&self) -> f64 {
+        match self.unique {
+            0 => 1.0,
+            a => self.logical as f64 / a as f64
+        }
+    }
+
+    /// returns how much compressing the distinct data shrank it, sealing
+    /// overhead included
+    pub fn compression_ratio(&self) -> f64 {
+        match self.stored {
+            0 => 1.0,
+            a => self.unique as f64 / a as f64
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use std::io::{Read, Seek, SeekFrom};
+
+
+////////////////////////// DEFINITIONS /////////////////////////////////////
+/// Anything an entry's data can be read back out of
+pub(crate) trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// A handle to the contents of a single entry. Seeking is relative to the
+/// start of the entry, and reads stop at its end, so only the chunks of the
+/// archive that hold the requested bytes ever get decrypted
+pub struct EntryReader<'a> {
+    inner: Box<dyn ReadSeek + 'a>,
+    start: u64,
+    len: u64,
+    pos: u64,
+    /// whether `inner` may not be at `start + pos` any more
+    moved: bool
+}
+
+
+////////////////////////// ENTRYREADER FUNCTIONS //////////////////////////
+impl<'a> EntryReader<'a> {
+    /// creates a handle over the `len` bytes at `start` in `inner`
+    pub(crate) fn new(inner: Box<dyn ReadSeek + 'a>, start: u64, len: u64) -> Self {
+        EntryReader{inner, start, len, pos: 0, moved: true}
+    }
+
+    /// returns the size of the entry
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// returns true if the entry holds no data
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Read for EntryReader<'_> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.len {
+            return Ok(0);
+        }
+        // only seek the reader underneath when we actually need data, so a
+        // run of seeks costs nothing
+        if self.moved {
+            self.inner.seek(SeekFrom::Start(self.start + self.pos))?;
+            self.moved = false;
+        }
+
+        let want = std::cmp::min(out.len() as u64, self.len - self.pos) as usize;
+        let got = self.inner.read(&mut out[..want])?;
+        self.pos += got as u64;
+        Ok(got)
+    }
+}
+
+impl Seek for EntryReader<'_> {
+    fn seek(&mut self, to: SeekFrom) -> std::io::Result<u64> {
+        let pos = match to {
+            SeekFrom::Start(a) => Some(a),
+            SeekFrom::End(a) => self.len.checked_add_signed(a),
+            SeekFrom::Current(a) => self.pos.checked_add_signed(a)
+        };
+        match pos {
+            Some(a) => {
+                self.pos = a;
+                self.moved = true;
+                Ok(a)
+            },
+            None => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek to a negative position"))
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use openssl::error::ErrorStack;
+
+use crate::table::TableError;
+
+
+////////////////////////// DEFINITIONS /////////////////////////////////////
+/// Everything that can go wrong while working with an archive
+#[derive(Debug)]
+pub enum VaultError {
+    /// Reading or writing a file failed
+    Io(std::io::Error),
+    /// The password did not decrypt a v0 archive
+    WrongPassword,
+    /// The archive decrypted, but what is inside makes no sense
+    Corrupt(String),
+    /// The archive's file table is malformed
+    Table(TableError),
+    /// The archive failed authentication. AEAD can't tell a wrong password
+    /// apart from a modified file, so this covers both
+    Authentication,
+    /// No entry by the given name exists
+    NotFound(String),
+    /// An entry by the given name already exists
+    AlreadyExists(String),
+    /// The name can't be stored in the file table
+    InvalidName(String),
+    /// A directory was given where a file is needed
+    IsDirectory(String),
+    /// A file was given where a directory is needed
+    NotADirectory(String),
+    /// A link, pipe or device was given where a regular file is needed
+    NotAFile(String),
+    /// The directory still has entries in it
+    NotEmpty(String),
+    /// A glob pattern doesn't parse
+    InvalidPattern(String),
+    /// The file is not an archive, or uses a version or algorithm we don't know
+    Unsupported(String),
+    /// The key derivation function rejected its parameters
+    Kdf(String),
+    /// OpenSSL failed while encrypting or gathering randomness
+    Crypto(ErrorStack),
+    /// Another process has the archive open for writing
+    Locked(String),
+    /// The archive was opened read-only, so it can't be saved
+    ReadOnly,
+    /// The archive changed on disk since we opened it
+    Changed(String)
+}
+
+
+/////////////////////////// DISPLAY IMPL ////////////////////////////////////
+impl std::fmt::Display for VaultError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VaultError::Io(e) => write!(f, "I/O error: {}", e),
+            VaultError::WrongPassword => write!(f, "Wrong password"),
+            VaultError::Corrupt(e) => write!(f, "Archive is corrupt: {}", e),
+            VaultError::Table(e) => write!(f, "Archive is corrupt: {}", e),
+            VaultError::Authentication => write!(f, "Authentication failed: wrong password or the archive was modified"),
+            VaultError::NotFound(e) => write!(f, "No file named {}", e),
+            VaultError::AlreadyExists(e) => write!(f, "A file named {} already exists", e),
+            VaultError::InvalidName(e) => write!(f, "Invalid file name '{}'", e),
+            VaultError::IsDirectory(e) => write!(f, "{} is a directory", e),
+            VaultError::NotADirectory(e) => write!(f, "{} is not a directory", e),
+            VaultError::NotAFile(e) => write!(f, "{} is not a regular file", e),
+            VaultError::NotEmpty(e) => write!(f, "Directory {} is not empty", e),
+            VaultError::InvalidPattern(e) => write!(f, "Invalid pattern: {}", e),
+            VaultError::Unsupported(e) => write!(f, "Unsupported archive: {}", e),
+            VaultError::Kdf(e) => write!(f, "Key derivation failed: {}", e),
+            VaultError::Crypto(e) => write!(f, "Crypto error: {}", e),
+            VaultError::Locked(e) => write!(f, "{} is open for writing in another process", e),
+            VaultError::ReadOnly => write!(f, "Archive was opened read-only"),
+            VaultError::Changed(e) => write!(f, "{} changed on disk since it was opened", e)
+        }
+    }
+}
+
+
+/////////////////////////// ERROR IMPL //////////////////////////////////////
+impl std::error::Error for VaultError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VaultError::Io(e) => Some(e),
+            VaultError::Table(e) => Some(e),
+            VaultError::Crypto(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl std::error::Error for TableError {}
+
+
+/////////////////////////// FROM IMPLS //////////////////////////////////////
+impl From<std::io::Error> for VaultError {
+    fn from(e: std::io::Error) -> Self {
+        // our own readers and writers have to pass errors through io::Error,
+        // so unwrap those back into what they were
+        if !e.get_ref().is_some_and(|inner| inner.is::<VaultError>()) {
+            return VaultError::Io(e);
+        }
+        match e.into_inner().map(|inner| inner.downcast::<VaultError>()) {
+            Some(Ok(a)) => *a,
+            _ => VaultError::Corrupt("lost track of a stream error".to_string())
+        }
+    }
+}
+
+impl From<VaultError> for std::io::Error {
+    fn from(e: VaultError) -> Self {
+        match e {
+            VaultError::Io(a) => a,
+            _ => std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+        }
+    }
+}
+
+impl From<TableError> for VaultError {
+    fn from(e: TableError) -> Self {
+        VaultError::Table(e)
+    }
+}
+
+impl From<ErrorStack> for VaultError {
+    fn from(e: ErrorStack) -> Self {
+        VaultError::Crypto(e)
+    }
+}
@@ -0,0 +1,186 @@
+use std::fs::{File, OpenOptions};
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::VaultError;
+use crate::meta::{Metadata, Restore, Timestamp};
+
+
+////////////////////////// DEFINITIONS /////////////////////////////////////
+/// What to do when something already exists where an entry is extracted to
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Overwrite {
+    /// leave it alone and skip the entry
+    #[default]
+    Never,
+    /// let `ExtractOptions::ask` decide
+    Ask,
+    /// replace it
+    Always,
+    /// replace it only if the entry was modified after it
+    Newer
+}
+
+/// How entries get written to disk
+#[derive(Clone, Copy, Debug)]
+pub struct ExtractOptions {
+    /// which metadata gets put back
+    pub restore: Restore,
+    /// whether to create symlinks that are absolute or go through `..`,
+    /// which could lead outside the directory being extracted to and are
+    /// skipped otherwise
+    pub unsafe_links: bool,
+    /// what to do about things already on disk
+    pub overwrite: Overwrite,
+    /// asked whether to replace what is at the path with `Overwrite::Ask`
+    pub ask: fn(&Path) -> bool
+}
+
+
+/////////////////////////// DEFAULT IMPL ////////////////////////////////////
+impl Default for ExtractOptions {
+    fn default() -> Self {
+        ExtractOptions{restore: Restore::default(), unsafe_links: false, overwrite: Overwrite::default(), ask: |_| false}
+    }
+}
+
+
+////////////////////////// OVERWRITE FUNCTIONS //////////////////////////
+impl Overwrite {
+    /// looks a policy up by the name used on the command line
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "never" => Some(Overwrite::Never),
+            "ask" => Some(Overwrite::Ask),
+            "always" => Some(Overwrite::Always),
+            "newer" => Some(Overwrite::Newer),
+            _ => None
+        }
+    }
+}
+
+
+/// creates the directories between `root` and `out`, without going through
+/// symlinks or anything else that isn't a directory. `root` itself is trusted
+/// and has to exist already, and `out` has to be below it
+pub fn create_parents(root: &Path, out: &Path) -> Result<(), VaultError> {
+    let rel = match out.strip_prefix(root) {
+        Ok(a) => a,
+        Err(_) => return Err(VaultError::InvalidName(out.to_string_lossy().to_string()))
+    };
+    let mut dir = root.to_path_buf();
+    let mut parts = rel.components().peekable();
+    while let Some(c) = parts.next() {
+        if parts.peek().is_none() {
+            break;
+        }
+        dir.push(normal(c, out)?);
+        create_dir(&dir)?;
+    }
+    Ok(())
+}
+
+/// creates the directory `out`, which is fine if it exists already but not
+/// if a symlink or anything else is in the way
+pub fn create_dir(out: &Path) -> Result<(), VaultError> {
+    match std::fs::symlink_metadata(out) {
+        Ok(a) if a.is_dir() => return Ok(()),
+        Ok(_) => return Err(VaultError::NotADirectory(out.to_string_lossy().to_string())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into())
+    };
+    match std::fs::create_dir(out) {
+        Ok(_) => Ok(()),
+        // something got there first, so look at it again
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => match std::fs::symlink_metadata(out)?.is_dir() {
+            true => Ok(()),
+            false => Err(VaultError::NotADirectory(out.to_string_lossy().to_string()))
+        },
+        Err(e) => Err(e.into())
+    }
+}
+
+/// returns the name of a path component, refusing anything that would leave
+/// the directory it is in
+fn normal<'a>(c: Component<'a>, out: &Path) -> Result<&'a std::ffi::OsStr, VaultError> {
+    match c {
+        Component::Normal(a) => Ok(a),
+        _ => Err(VaultError::InvalidName(out.to_string_lossy().to_string()))
+    }
+}
+
+/// decides whether an entry last modified at `mtime` gets written to `out`,
+/// clearing whatever is there if so. Returns false if the entry should be
+/// skipped. Directories are never cleared
+pub fn make_room(out: &Path, opts: &ExtractOptions, mtime: Option<Timestamp>) -> Result<bool, VaultError> {
+    let existing = match std::fs::symlink_metadata(out) {
+        Ok(a) => a,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e.into())
+    };
+    let replace = match opts.overwrite {
+        Overwrite::Never => false,
+        Overwrite::Ask => (opts.ask)(out),
+        Overwrite::Always => true,
+        // without a time to go by, we can't say the entry is newer
+        Overwrite::Newer => match (mtime, existing.modified()) {
+            (Some(ours), Ok(theirs)) => {
+                let theirs = Timestamp::from_system_time(theirs);
+                (ours.secs, ours.nanos) > (theirs.secs, theirs.nanos)
+            },
+            _ => false
+        }
+    };
+    if !replace {
+        return Ok(false);
+    }
+    if existing.is_dir() {
+        return Err(VaultError::IsDirectory(out.to_string_lossy().to_string()));
+    }
+    // this removes a symlink itself, never what it points at
+    std::fs::remove_file(out)?;
+    Ok(true)
+}
+
+/// creates a new file at `out`, failing if anything is there already, even a
+/// dangling symlink. With `meta`, it starts out with the permissions it will
+/// end up with, so it is never readable by more people than it should be
+pub fn create_new(out: &Path, meta: Option<&Metadata>, restore: &Restore) -> Result<File, VaultError> {
+    let mut opts = OpenOptions::new();
+    opts.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.custom_flags(libc::O_NOFOLLOW);
+        if let Some(meta) = meta.filter(|_| restore.permissions) {
+            opts.mode(meta.mode & 0o777);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (meta, restore);
+    Ok(opts.open(out)?)
+}
+
+/// returns where the entry `name` goes when extracted to `dest`, refusing
+/// names that are absolute or have `.` or `..` components
+pub fn disk_path(dest: &Path, name: &[u8]) -> Result<PathBuf, VaultError> {
+    if !crate::path::is_valid(name) {
+        return Err(VaultError::InvalidName(String::from_utf8_lossy(name).to_string()));
+    }
+    let mut out = dest.to_path_buf();
+    for c in name.split(|&b| b == crate::path::SEPARATOR) {
+        out.push(os_str(c));
+    }
+    Ok(out)
+}
+
+/// returns a component of an entry name as it is named on disk
+#[cfg(unix)]
+fn os_str(c: &[u8]) -> &std::ffi::OsStr {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::OsStr::from_bytes(c)
+}
+
+#[cfg(not(unix))]
+fn os_str(c: &[u8]) -> std::ffi::OsString {
+    String::from_utf8_lossy(c).to_string().into()
+}
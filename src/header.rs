@@ -0,0 +1,147 @@
+use std::io::Read;
+
+use crate::cipher::{CipherKind, NONCE_LEN};
+use crate::error::VaultError;
+use crate::kdf::{KdfParams, KDF_PARAMS_LEN, SALT_LEN};
+
+
+/// The plaintext signature that leads every archive
+pub const SIGNATURE: &[u8; 8] = b"\x89VAULT\r\n";
+
+/// The archive format version this build writes
+pub const FORMAT_VERSION: u16 = 3;
+
+/// The oldest format version with this header that `--migrate` can read
+pub const MIN_FORMAT_VERSION: u16 = 1;
+
+/// Length of the plaintext header
+pub const HEADER_LEN: usize = SIGNATURE.len() + 2 + 1 + KDF_PARAMS_LEN + SALT_LEN + NONCE_LEN;
+
+
+////////////////////////// DEFINITIONS /////////////////////////////////////
+/// The unencrypted header that leads an archive. It holds everything needed
+/// to derive the key and authenticate the rest of the file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Header {
+    pub version: u16,
+    pub cipher: CipherKind,
+    pub kdf: KdfParams,
+    pub salt: [u8; SALT_LEN],
+    pub nonce: [u8; NONCE_LEN]
+}
+
+/// What we can tell about a file before asking for its password
+pub enum Probe {
+    /// The file starts with a header we understand
+    Current(Header),
+    /// The file starts with a header from an older format version
+    Outdated(Header),
+    /// The file has no signature, but could be a v0 (headerless CBC) archive
+    Legacy,
+    /// The file is definitely not an archive we can open
+    NotVault(VaultError)
+}
+
+
+/////////////////////////// DISPLAY IMPL ////////////////////////////////////
+impl std::fmt::Display for Header {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "format v{}, {}, {}", self.version, self.cipher, self.kdf)
+    }
+}
+
+
+////////////////////////// HEADER FUNCTIONS //////////////////////////
+impl Header {
+    /// reads the header from the start of `reader`
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self, VaultError> {
+        let mut start: Vec<u8> = Vec::with_capacity(HEADER_LEN);
+        reader.by_ref().take(HEADER_LEN as u64).read_to_end(&mut start)?;
+        Header::parse(&start)
+    }
+
+    /// reads the header from the start of `reader` like `read`, but returns
+    /// None if there is no signature rather than failing, since v0 archives
+    /// don't have one
+    pub fn read_if_signed<R: Read>(reader: &mut R) -> Result<Option<Self>, VaultError> {
+        let mut start: Vec<u8> = Vec::with_capacity(HEADER_LEN);
+        reader.by_ref().take(HEADER_LEN as u64).read_to_end(&mut start)?;
+        match start.starts_with(SIGNATURE) {
+            true => Ok(Some(Header::parse(&start)?)),
+            false => Ok(None)
+        }
+    }
+
+    /// creates a header for the current format version
+    pub fn new(cipher: CipherKind, kdf: KdfParams, salt: [u8; SALT_LEN], nonce: [u8; NONCE_LEN]) -> Self {
+        Header{version: FORMAT_VERSION, cipher, kdf, salt, nonce}
+    }
+
+    /// serializes the header for the start of the archive
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out: Vec<u8> = Vec::with_capacity(HEADER_LEN);
+        out.extend_from_slice(SIGNATURE);
+        out.extend_from_slice(&self.version.to_le_bytes());
+        out.push(self.cipher.id());
+        out.extend_from_slice(&self.kdf.serialize());
+        out.extend_from_slice(&self.salt);
+        out.extend_from_slice(&self.nonce);
+        out
+    }
+
+    /// parses the header at the start of `data`
+    pub fn parse(data: &[u8]) -> Result<Self, VaultError> {
+        if !data.starts_with(SIGNATURE) {
+            return Err(VaultError::Unsupported("missing vault signature".to_string()));
+        }
+        if data.len() < SIGNATURE.len() + 2 {
+            return Err(VaultError::Corrupt("archive header is truncated".to_string()));
+        }
+
+        let mut pos = SIGNATURE.len();
+        let version = u16::from_le_bytes([data[pos], data[pos+1]]);
+        if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) {
+            return Err(VaultError::Unsupported(format!("format version {} (this build reads up to v{})", version, FORMAT_VERSION)));
+        }
+        if data.len() < HEADER_LEN {
+            return Err(VaultError::Corrupt("archive header is truncated".to_string()));
+        }
+        pos += 2;
+
+        let cipher = CipherKind::from_id(data[pos])?;
+        pos += 1;
+        let kdf = KdfParams::parse(&data[pos..pos+KDF_PARAMS_LEN])?;
+        pos += KDF_PARAMS_LEN;
+        let mut salt = [0u8; SALT_LEN];
+        salt.copy_from_slice(&data[pos..pos+SALT_LEN]);
+        pos += SALT_LEN;
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(&data[pos..pos+NONCE_LEN]);
+
+        Ok(Header{version, cipher, kdf, salt, nonce})
+    }
+
+    /// looks at the start of a file to figure out what kind of archive it is
+    pub fn probe(path: &str) -> Result<Probe, VaultError> {
+        let mut f = std::fs::File::open(path)?;
+        let len = f.metadata()?.len();
+
+        let mut start: Vec<u8> = Vec::with_capacity(HEADER_LEN);
+        f.by_ref().take(HEADER_LEN as u64).read_to_end(&mut start)?;
+
+        if start.starts_with(SIGNATURE) {
+            return match Header::parse(&start) {
+                Ok(a) if a.version < FORMAT_VERSION => Ok(Probe::Outdated(a)),
+                Ok(a) => Ok(Probe::Current(a)),
+                Err(e) => Ok(Probe::NotVault(e))
+            };
+        }
+
+        // v0 archives are a bare CBC blob, so all we can check is the length
+        if len > 0 && len % 16 == 0 {
+            Ok(Probe::Legacy)
+        } else {
+            Ok(Probe::NotVault(VaultError::Unsupported("missing vault signature".to_string())))
+        }
+    }
+}
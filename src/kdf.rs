@@ -0,0 +1,241 @@
+use std::time::{Duration, Instant};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use openssl::pkcs5::scrypt;
+use openssl::rand::rand_bytes;
+
+use crate::error::VaultError;
+
+
+/// Length of the random salt stored in each archive header
+pub const SALT_LEN: usize = 16;
+
+/// Length of the serialized KDF parameters (id byte + 3 u32 values)
+pub const KDF_PARAMS_LEN: usize = 13;
+
+/// The ceiling we allow calibration to push Argon2's memory cost to (1 GiB)
+const MAX_ARGON2_MEM_KIB: u32 = 1024 * 1024;
+
+/// The most memory we let a header ask the KDF for (4 GiB). The header is
+/// read before the password is checked, so anything it says has to be cheap
+/// enough to try
+const MAX_KDF_MEM: u64 = 4 * 1024 * 1024 * 1024;
+
+/// The most Argon2 passes a header may ask for
+const MAX_ARGON2_PASSES: u32 = 64;
+
+/// The most Argon2 lanes or scrypt parallelism a header may ask for
+const MAX_PARALLELISM: u32 = 16;
+
+/// The largest log2(N) a header may ask scrypt for
+const MAX_SCRYPT_LOG_N: u8 = 30;
+
+/// The largest scrypt block size a header may ask for
+const MAX_SCRYPT_R: u32 = 32;
+
+
+////////////////////////// DEFINITIONS /////////////////////////////////////
+/// The key derivation functions we know how to use, along with their costs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KdfParams {
+    /// Argon2id with memory cost in KiB, number of passes and lanes
+    Argon2id { m_cost: u32, t_cost: u32, p_cost: u32 },
+    /// scrypt with log2(N), block size and parallelism
+    Scrypt { log_n: u8, r: u32, p: u32 }
+}
+
+
+/////////////////////////// DEFAULT IMPL ////////////////////////////////////
+impl Default for KdfParams {
+    /// Argon2id with a 64 MiB, 3 pass setting
+    fn default() -> Self {
+        KdfParams::Argon2id { m_cost: 64 * 1024, t_cost: 3, p_cost: 1 }
+    }
+}
+
+
+/////////////////////////// DISPLAY IMPL ////////////////////////////////////
+impl std::fmt::Display for KdfParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KdfParams::Argon2id { m_cost, t_cost, p_cost } => write!(
+                f, "argon2id (m={} KiB, t={}, p={})", m_cost, t_cost, p_cost
+            ),
+            KdfParams::Scrypt { log_n, r, p } => write!(
+                f, "scrypt (N=2^{}, r={}, p={})", log_n, r, p
+            )
+        }
+    }
+}
+
+
+////////////////////////// KDFPARAMS FUNCTIONS //////////////////////////
+impl KdfParams {
+    /// returns the default parameters for the KDF with the given name
+    pub fn from_name(name: &str) -> Result<Self, VaultError> {
+        match name {
+            "argon2id" => Ok(KdfParams::default()),
+            "scrypt" => Ok(KdfParams::Scrypt { log_n: 17, r: 8, p: 1 }),
+            _ => Err(VaultError::Unsupported(format!("unknown KDF '{}'", name)))
+        }
+    }
+
+    /// serializes the parameters for the archive header
+    pub fn serialize(&self) -> [u8; KDF_PARAMS_LEN] {
+        let (id, a, b, c) = match *self {
+            KdfParams::Argon2id { m_cost, t_cost, p_cost } => (1u8, m_cost, t_cost, p_cost),
+            KdfParams::Scrypt { log_n, r, p } => (2u8, log_n as u32, r, p)
+        };
+
+        let mut out = [0u8; KDF_PARAMS_LEN];
+        out[0] = id;
+        out[1..5].copy_from_slice(&a.to_le_bytes());
+        out[5..9].copy_from_slice(&b.to_le_bytes());
+        out[9..13].copy_from_slice(&c.to_le_bytes());
+        out
+    }
+
+    /// parses parameters previously written by `serialize`
+    pub fn parse(data: &[u8]) -> Result<Self, VaultError> {
+        if data.len() < KDF_PARAMS_LEN {
+            return Err(VaultError::Corrupt("KDF parameters are truncated".to_string()));
+        }
+
+        let field = |start: usize| {
+            let mut buf = [0u8; 4];
+            buf.copy_from_slice(&data[start..start+4]);
+            u32::from_le_bytes(buf)
+        };
+        let (a, b, c) = (field(1), field(5), field(9));
+
+        let params = match data[0] {
+            1 => KdfParams::Argon2id { m_cost: a, t_cost: b, p_cost: c },
+            2 => {
+                if a > MAX_SCRYPT_LOG_N as u32 {
+                    return Err(VaultError::Kdf(format!("scrypt cost 2^{} is more than 2^{}", a, MAX_SCRYPT_LOG_N)));
+                }
+                KdfParams::Scrypt { log_n: a as u8, r: b, p: c }
+            },
+            id => return Err(VaultError::Unsupported(format!("unknown KDF id {}", id)))
+        };
+        params.check()?;
+        Ok(params)
+    }
+
+    /// makes sure the parameters are within the limits we are willing to
+    /// derive a key with
+    pub fn check(&self) -> Result<(), VaultError> {
+        match *self {
+            KdfParams::Argon2id { m_cost, t_cost, p_cost } => {
+                if m_cost as u64 * 1024 > MAX_KDF_MEM {
+                    return Err(VaultError::Kdf(format!("Argon2 memory cost of {} KiB is more than {} KiB", m_cost, MAX_KDF_MEM / 1024)));
+                }
+                if !(1..=MAX_ARGON2_PASSES).contains(&t_cost) {
+                    return Err(VaultError::Kdf(format!("Argon2 pass count {} is not between 1 and {}", t_cost, MAX_ARGON2_PASSES)));
+                }
+                if !(1..=MAX_PARALLELISM).contains(&p_cost) {
+                    return Err(VaultError::Kdf(format!("Argon2 lane count {} is not between 1 and {}", p_cost, MAX_PARALLELISM)));
+                }
+            },
+            KdfParams::Scrypt { log_n, r, p } => {
+                if !(1..=MAX_SCRYPT_LOG_N).contains(&log_n) {
+                    return Err(VaultError::Kdf(format!("scrypt cost 2^{} is not between 2^1 and 2^{}", log_n, MAX_SCRYPT_LOG_N)));
+                }
+                if !(1..=MAX_SCRYPT_R).contains(&r) {
+                    return Err(VaultError::Kdf(format!("scrypt block size {} is not between 1 and {}", r, MAX_SCRYPT_R)));
+                }
+                if !(1..=MAX_PARALLELISM).contains(&p) {
+                    return Err(VaultError::Kdf(format!("scrypt parallelism {} is not between 1 and {}", p, MAX_PARALLELISM)));
+                }
+                if scrypt_mem(log_n, r, p)? > MAX_KDF_MEM {
+                    return Err(VaultError::Kdf(format!("scrypt needs more than {} bytes of memory", MAX_KDF_MEM)));
+                }
+            }
+        };
+        Ok(())
+    }
+
+    /// derives a 32 byte AES key from the passphrase and salt
+    pub fn derive_key(&self, pass: &str, salt: &[u8]) -> Result<[u8; 32], VaultError> {
+        let mut key = [0u8; 32];
+        self.check()?;
+
+        match *self {
+            KdfParams::Argon2id { m_cost, t_cost, p_cost } => {
+                let params = match Params::new(m_cost, t_cost, p_cost, Some(key.len())) {
+                    Ok(a) => a,
+                    Err(e) => return Err(VaultError::Kdf(format!("invalid Argon2 parameters: {}", e)))
+                };
+                let argon = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+                if let Err(e) = argon.hash_password_into(pass.as_bytes(), salt, &mut key) {
+                    return Err(VaultError::Kdf(format!("Argon2 failed: {}", e)));
+                }
+            },
+            KdfParams::Scrypt { log_n, r, p } => {
+                let maxmem = scrypt_mem(log_n, r, p)?;
+                if let Err(e) = scrypt(pass.as_bytes(), salt, 1u64 << log_n, r as u64, p as u64, maxmem, &mut key) {
+                    return Err(VaultError::Kdf(format!("scrypt failed: {}", e)));
+                }
+            }
+        };
+
+        Ok(key)
+    }
+
+    /// picks parameters of the same KDF that take about `target` to derive a
+    /// key on this machine
+    pub fn calibrate(&self, target: Duration) -> Result<Self, VaultError> {
+        let salt = new_salt()?;
+        let mut params = match *self {
+            KdfParams::Argon2id { p_cost, .. } => KdfParams::Argon2id { m_cost: 8 * 1024, t_cost: 1, p_cost },
+            KdfParams::Scrypt { r, p, .. } => KdfParams::Scrypt { log_n: 10, r, p }
+        };
+
+        loop {
+            let start = Instant::now();
+            params.derive_key("calibration", &salt)?;
+            let elapsed = start.elapsed();
+            if elapsed >= target {
+                return Ok(params);
+            }
+
+            // grow memory first since that is what hurts attackers most, and
+            // fall back to extra passes once we hit our memory ceiling
+            params = match params {
+                KdfParams::Argon2id { m_cost, t_cost, p_cost } => {
+                    if m_cost * 2 <= MAX_ARGON2_MEM_KIB {
+                        KdfParams::Argon2id { m_cost: m_cost * 2, t_cost, p_cost }
+                    } else if t_cost < MAX_ARGON2_PASSES {
+                        KdfParams::Argon2id { m_cost, t_cost: t_cost + 1, p_cost }
+                    } else {
+                        return Ok(params);
+                    }
+                },
+                KdfParams::Scrypt { log_n, r, p } => {
+                    let next = KdfParams::Scrypt { log_n: log_n + 1, r, p };
+                    if log_n >= MAX_SCRYPT_LOG_N || next.check().is_err() {
+                        return Ok(params);
+                    }
+                    next
+                }
+            };
+        }
+    }
+}
+
+/// works out how much memory scrypt needs: 128 * N * r bytes, plus some
+/// headroom for p
+fn scrypt_mem(log_n: u8, r: u32, p: u32) -> Result<u64, VaultError> {
+    1u64.checked_shl(log_n as u32)
+        .and_then(|n| n.checked_add(p as u64 + 2))
+        .and_then(|a| a.checked_mul(128))
+        .and_then(|a| a.checked_mul(r as u64))
+        .ok_or_else(|| VaultError::Kdf(format!("scrypt cost 2^{} with r={} is too large", log_n, r)))
+}
+
+/// generates a fresh random salt
+pub fn new_salt() -> Result<[u8; SALT_LEN], VaultError> {
+    let mut salt = [0u8; SALT_LEN];
+    rand_bytes(&mut salt)?;
+    Ok(salt)
+}
@@ -0,0 +1,35 @@
+//! Reading and writing vault archives: encrypted bundles of files sealed
+//! with a password. The `vault` binary's REPL is one consumer of this crate.
+
+pub mod backup;
+pub mod cipher;
+pub mod codec;
+pub mod datafile;
+pub mod dedup;
+pub mod entry;
+pub mod error;
+pub mod extract;
+pub mod header;
+pub mod kdf;
+pub mod lock;
+pub mod meta;
+pub mod path;
+pub mod password;
+pub mod record;
+pub mod recover;
+pub mod stream;
+pub mod table;
+pub mod tree;
+pub mod verify;
+
+pub use cipher::CipherKind;
+pub use codec::Codec;
+pub use datafile::{Datafile, DirEntry, EncFile, EntryKind};
+pub use dedup::Stats;
+pub use entry::EntryReader;
+pub use error::VaultError;
+pub use extract::{ExtractOptions, Overwrite};
+pub use kdf::KdfParams;
+pub use meta::{Capture, Metadata, Restore};
+pub use recover::Recovery;
+pub use verify::Report;
@@ -0,0 +1,94 @@
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::{Read, Seek, SeekFrom};
+use std::time::SystemTime;
+
+use fs2::FileExt;
+use hmac_sha256::Hash;
+
+use crate::error::VaultError;
+use crate::header::HEADER_LEN;
+
+
+////////////////////////// DEFINITIONS /////////////////////////////////////
+/// What an archive looked like on disk the last time we read or wrote it, so
+/// we can tell if something else changed it in the meantime
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Identity {
+    dev: u64,
+    ino: u64,
+    len: u64,
+    mtime: Option<SystemTime>,
+    /// hash of the header, the latest table record and everything after it.
+    /// Every save changes at least one of those
+    hash: [u8; 32]
+}
+
+
+/// returns the device and inode a file lives at
+#[cfg(unix)]
+fn file_id(meta: &Metadata) -> (u64, u64) {
+    use std::os::unix::fs::MetadataExt;
+    (meta.dev(), meta.ino())
+}
+
+#[cfg(not(unix))]
+fn file_id(_meta: &Metadata) -> (u64, u64) {
+    (0, 0)
+}
+
+/// opens the archive at `path` and takes an exclusive advisory lock on it,
+/// failing with `VaultError::Locked` if another process holds one.
+/// Filesystems that don't support locks are opened unlocked, and only the
+/// identity check guards them
+pub fn open_locked(path: &str) -> Result<File, VaultError> {
+    loop {
+        let f = OpenOptions::new().read(true).write(true).open(path)?;
+        match f.try_lock_exclusive() {
+            Ok(_) => (),
+            Err(e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+                return Err(VaultError::Locked(path.to_string()));
+            },
+            Err(_) => return Ok(f)
+        };
+
+        // the archive may have been replaced between opening and locking it,
+        // in which case we locked a file nobody will look at again
+        if file_id(&f.metadata()?) == file_id(&std::fs::metadata(path)?) {
+            return Ok(f);
+        }
+    }
+}
+
+/// takes an exclusive advisory lock on a file we just created. Nothing else
+/// can hold one on it yet, so this only fails where locks aren't supported
+pub fn lock_new(f: &File) {
+    let _ = f.try_lock_exclusive();
+}
+
+
+////////////////////////// IDENTITY FUNCTIONS //////////////////////////
+impl Identity {
+    /// reads the identity of the archive at `path`, whose latest table
+    /// record starts at `table_offset`
+    pub fn read(path: &str, table_offset: u64) -> Result<Self, VaultError> {
+        let mut f = File::open(path)?;
+        let meta = f.metadata()?;
+        let (dev, ino) = file_id(&meta);
+
+        let mut hash = Hash::new();
+        let mut buf: Vec<u8> = Vec::with_capacity(HEADER_LEN);
+        (&mut f).take(HEADER_LEN as u64).read_to_end(&mut buf)?;
+        hash.update(&buf);
+        f.seek(SeekFrom::Start(table_offset))?;
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = f.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hash.update(&buf[..n]);
+        }
+
+        Ok(Identity{dev, ino, len: meta.len(), mtime: meta.modified().ok(), hash: hash.finalize()})
+    }
+}
@@ -1,10 +1,16 @@
 use clap::{Arg, App};
 use colored::Colorize;
-use rpassword::prompt_password_stdout;
 use std::io::prelude::*;
+use std::sync::Mutex;
 
-mod datafile;
-use datafile::Datafile;
+use vault::{Datafile, ExtractOptions, Overwrite, Restore, VaultError};
+use vault::header::{Header, Probe};
+use vault::password::PasswordSource;
+use vault::path;
+use vault::tree::{self, Filter};
+use vault::verify;
+
+mod cli;
 
 
 /// structure that defines a Command for the CLI interface
@@ -14,8 +20,30 @@ struct Command {
     command: fn(String, &mut Datafile) -> u32
 }
 
+/// The directory in the archive that names typed at the prompt start from
+static CWD: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+/// Where `load` gets passwords from, as picked by the `--password-*` options,
+/// or None to ask for them
+static PASS_SOURCE: Mutex<Option<PasswordSource>> = Mutex::new(None);
+
+/// The paths and options given to `add` or `extract`
+#[derive(Default)]
+struct TreeArgs {
+    paths: Vec<String>,
+    recursive: bool,
+    /// whether to put back permissions and times when extracting
+    preserve: bool,
+    /// what to do about files already there when extracting, which is to
+    /// ask unless it says otherwise
+    overwrite: Option<Overwrite>,
+    dest: Option<String>,
+    include: Vec<String>,
+    exclude: Vec<String>
+}
+
 /// Our constant list of commands
-const COMMS: [Command; 9] = [
+const COMMS: [Command; 18] = [
         Command{
             value: "exit",
             help: "Exits the program",
@@ -23,12 +51,32 @@ const COMMS: [Command; 9] = [
         },
         Command{
             value: "ls",
-            help: "Lists all the files in the archive",
+            help: "Lists the files in the current directory, or in the given one. -l also shows each file's SHA-256",
             command: ls
         },
+        Command{
+            value: "cd",
+            help: "Changes the current directory, or goes back to the root",
+            command: cd
+        },
+        Command{
+            value: "pwd",
+            help: "Prints the current directory",
+            command: pwd
+        },
+        Command{
+            value: "mkdir",
+            help: "Creates a directory",
+            command: mkdir
+        },
+        Command{
+            value: "tree",
+            help: "Prints everything below the current directory, or below the given one",
+            command: tree
+        },
         Command{
             value: "load",
-            help: "Loads a new file for interaction",
+            help: "Loads a new file for interaction, reading its password like the one given at startup",
             command: load
         },
         Command{
@@ -46,14 +94,39 @@ const COMMS: [Command; 9] = [
             help: "Removes a file from the current state",
             command: remove
         },
+        Command{
+            value: "rename",
+            help: "Renames a file in the current state",
+            command: rename
+        },
         Command{
             value: "fetch",
             help: "Fetches a file from the current state and saves it to the filesystem",
             command: fetch
         },
+        Command{
+            value: "extract",
+            help: "Extracts the current directory, or the given file or directory, with everything in it: extract [PREFIX] -C DEST. -p restores permissions and times, and --overwrite never|ask|always|newer picks what happens to files already there (ask by default)",
+            command: extract
+        },
+        Command{
+            value: "cat",
+            help: "Prints a file, or a byte range of it given as START:END",
+            command: cat
+        },
+        Command{
+            value: "compact",
+            help: "Saves the archive, reclaiming the space left by removed files",
+            command: compact
+        },
+        Command{
+            value: "stats",
+            help: "Shows how much space the files take up, and what deduplication and compression saved",
+            command: stats
+        },
         Command{
             value: "pass",
-            help: "Updates the currently used passphrase for encryption",
+            help: "Updates the currently used passphrase for encryption, asking for it twice",
             command: pass
         },
         Command{
@@ -67,7 +140,7 @@ const COMMS: [Command; 9] = [
 /// Exits the program
 fn exit(_args: String, _dfile: &mut Datafile) -> u32 {
     // make sure the user really wants to exit the program
-    print!("{}", "[ ] Are you sure you wish to exit? (y/n) > ");
+    print!("[ ] Are you sure you wish to exit? (y/n) > ");
     std::io::stdout().flush().unwrap();
     
     let mut ret = String::new();
@@ -90,34 +163,212 @@ fn exit(_args: String, _dfile: &mut Datafile) -> u32 {
     0
 }
 
-/// lists all files in the datafile
-fn ls(_args: String, dfile: &mut Datafile) -> u32 {
-    println!("{} {} {}", "[+] Currently".green(), dfile.num_files(), "files".green());
+/// returns the current directory
+fn cwd() -> Vec<u8> {
+    CWD.lock().unwrap().clone()
+}
+
+/// turns a name typed at the prompt into a path in the archive, starting
+/// from the current directory unless it starts with a `/`
+fn resolve(name: &str) -> Result<Vec<u8>, VaultError> {
+    path::resolve(&cwd(), name.trim().as_bytes())
+}
+
+/// returns true if there is a file or directory at `name`, other than the root
+fn exists(dfile: &Datafile, name: &[u8]) -> bool {
+    dfile.find(name).is_some() || (!name.is_empty() && dfile.is_dir(name))
+}
+
+/// formats a path in the archive the way the prompt shows it
+fn show_path(name: &[u8]) -> String {
+    format!("/{}", String::from_utf8_lossy(name))
+}
+
+/// lists the files in the current directory, or in the one given
+fn ls(args: String, dfile: &mut Datafile) -> u32 {
+    let long = args.split_whitespace().any(|a| a == "-l");
+    let args: Vec<&str> = args.split_whitespace().filter(|a| *a != "-l").collect();
+    let res = resolve(&args.join(" ")).and_then(|dir| Ok((dfile.read_dir(&dir)?, dir)));
+    let (entries, dir) = match res {
+        Ok(a) => a,
+        Err(e) => {
+            println!("{}: {}", "[-] Failed to list directory".red(), e);
+            return 1
+        }
+    };
+    println!("{} {} {} {}", "[+] Currently".green(), entries.len(), "entries in".green(), show_path(&dir));
     // loop over each of the files
-    for file in dfile.files().iter() {
-        println!("\t{}", file);
+    for entry in entries.iter() {
+        let name = String::from_utf8_lossy(&entry.name);
+        match entry.file {
+            Some(f) if !f.is_dir() && long => {
+                let digest = dfile.recorded_digest(f).map(|d| verify::hex(&d)).unwrap_or_else(|| "-".to_string());
+                println!("\t{} {} {}", name, f.details(), digest)
+            },
+            Some(f) if !f.is_dir() => println!("\t{} {}", name, f.details()),
+            _ => println!("\t{}/", name)
+        };
     }
 
     0
 }
 
+/// changes the current directory
+fn cd(args: String, dfile: &mut Datafile) -> u32 {
+    let dir = match resolve(&args) {
+        Ok(_) if args.trim().is_empty() => Vec::new(),
+        Ok(a) if dfile.is_dir(&a) => a,
+        Ok(a) => {
+            println!("{}: {}", "[-] No directory named".red(), show_path(&a));
+            return 1
+        },
+        Err(e) => {
+            println!("{}: {}", "[-] Failed to change directory".red(), e);
+            return 1
+        }
+    };
+    *CWD.lock().unwrap() = dir;
+    0
+}
+
+/// prints the current directory
+fn pwd(_args: String, _dfile: &mut Datafile) -> u32 {
+    println!("{}", show_path(&cwd()));
+    0
+}
+
+/// creates a directory
+fn mkdir(args: String, dfile: &mut Datafile) -> u32 {
+    let name = if !args.trim().is_empty() {
+        args
+    } else {
+        print!("[ ] Enter directory name > ");
+        std::io::stdout().flush().unwrap();
+        let mut r = String::new();
+        std::io::stdin().read_line(&mut r).expect("Failed to read STDIN");
+        r.replace("\n", "")
+    };
+
+    match resolve(&name).and_then(|a| dfile.mkdir(a)) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("{}: {}", "[-] Failed to create directory".red(), e);
+            1
+        }
+    }
+}
+
+/// prints everything below the current directory, or below the one given
+fn tree(args: String, dfile: &mut Datafile) -> u32 {
+    let dir = match resolve(&args) {
+        Ok(a) => a,
+        Err(e) => {
+            println!("{}: {}", "[-] Failed to list directory".red(), e);
+            return 1
+        }
+    };
+    println!("{}", show_path(&dir));
+    let mut counts = (0, 0);
+    match print_tree(dfile, &dir, "", &mut counts) {
+        Ok(_) => {
+            println!("\n{} directories, {} files", counts.0, counts.1);
+            0
+        },
+        Err(e) => {
+            println!("{}: {}", "[-] Failed to list directory".red(), e);
+            1
+        }
+    }
+}
+
+/// prints the entries of `dir` and everything below them, counting the
+/// directories and files it finds
+fn print_tree(dfile: &Datafile, dir: &[u8], indent: &str, counts: &mut (usize, usize)) -> Result<(), VaultError> {
+    let entries = dfile.read_dir(dir)?;
+    for (i, entry) in entries.iter().enumerate() {
+        let last = i + 1 == entries.len();
+        let name = String::from_utf8_lossy(&entry.name);
+        let (branch, next) = if last { ("└── ", "    ") } else { ("├── ", "│   ") };
+        match entry.file {
+            Some(f) if !f.is_dir() => {
+                println!("{}{}{} {}", indent, branch, name, f.details());
+                counts.1 += 1;
+            },
+            _ => {
+                println!("{}{}{}/", indent, branch, name);
+                counts.0 += 1;
+                print_tree(dfile, &path::join(dir, &entry.name), &format!("{}{}", indent, next), counts)?;
+            }
+        };
+    }
+    Ok(())
+}
+
+/// opens an archive, falling back to read-only if another process has it
+/// open for writing
+fn open_archive(path: String, passwd: String) -> Result<Datafile, VaultError> {
+    match Datafile::checked_new(path.clone(), passwd.clone()) {
+        Err(VaultError::Locked(e)) => {
+            println!("{} {} {}", "[!]".yellow(), e, "is open in another process, opening it read-only".yellow());
+            Datafile::checked_new_read_only(path, passwd)
+        },
+        res => res
+    }
+}
+
+/// swaps in a newly opened archive, keeping the settings of the old one
+fn replace_archive(dfile: &mut Datafile, res: Result<Datafile, VaultError>) -> Result<(), VaultError> {
+    let mut new = res?;
+    new.set_backups(dfile.backups());
+    new.set_codec(dfile.codec());
+    *dfile = new;
+    Ok(())
+}
+
+/// saves the archive, offering to merge our changes into it if something
+/// else changed it since it was opened
+fn save_or_merge(dfile: &mut Datafile, path: String) -> Result<(), VaultError> {
+    match dfile.save(path.clone()) {
+        Err(VaultError::Changed(e)) => {
+            println!("{} {} {}", "[!]".yellow(), e, "changed on disk since it was opened".yellow());
+            print!("[ ] Merge your changes into it? [y/N] > ");
+            std::io::stdout().flush().unwrap();
+            let mut r = String::new();
+            std::io::stdin().read_line(&mut r).expect("Failed to read STDIN");
+            if r.trim() != "y" {
+                return Err(VaultError::Changed(e));
+            }
+            dfile.merge_changes()?;
+            dfile.save(path)
+        },
+        res => res
+    }
+}
+
 /// loads a new file
 fn load(args: String, dfile: &mut Datafile) -> u32 {
-    let passwd = get_pass();
+    let passwd = match get_pass() {
+        Ok(a) => a,
+        Err(e) => {
+            println!("{}: {}", "[-] Failed to read password".red(), e);
+            return 1;
+        }
+    };
         
     if !args.is_empty() {
-        let path: &str;
         // see if we can save to the first path provided by the arguments
-        if args.contains(" ") {
+        let path: &str = if args.contains(' ') {
             let space_index = args.as_bytes().iter().position(|&r| r == b' ').unwrap();
-            path = &args[..space_index];
+            &args[..space_index]
         } else {
-            path = &args[..];
-        }
+            &args[..]
+        };
 
         println!("{}: {}","[ ] Opening database file".yellow(), path);
-        match dfile.load_new(path.to_string(), passwd.clone()) {
+        match replace_archive(dfile, open_archive(path.to_string(), passwd.clone())) {
             Ok(_) => {
+                CWD.lock().unwrap().clear();
+                cli::warn_torn(dfile);
                 println!("{}", "[+] Success!".green());
                 return 0
             },
@@ -131,7 +382,7 @@ fn load(args: String, dfile: &mut Datafile) -> u32 {
     // and save the user's file
     loop {
         let passwd_clone = passwd.clone();
-        print!("{}", "[ ] Enter path to the database > ");
+        print!("[ ] Enter path to the database > ");
         std::io::stdout().flush().unwrap();
     
         let mut path = String::new();
@@ -139,8 +390,10 @@ fn load(args: String, dfile: &mut Datafile) -> u32 {
         let path = path.replace("\n", "");
         
         // try to save the file
-        match dfile.load_new(path, passwd_clone) {
+        match replace_archive(dfile, open_archive(path, passwd_clone)) {
             Ok(_) => {
+                CWD.lock().unwrap().clear();
+                cli::warn_torn(dfile);
                 println!("{}", "[+] Success!".green());
                 return 0
             },
@@ -154,17 +407,16 @@ fn load(args: String, dfile: &mut Datafile) -> u32 {
 fn save(args: String, dfile: &mut Datafile) -> u32 {
     // see if we got a path
     if !args.is_empty() {
-        let path: &str;
         // see if we can save to the first path provided by the arguments
-        if args.contains(" ") {
+        let path: &str = if args.contains(' ') {
             let space_index = args.as_bytes().iter().position(|&r| r == b' ').unwrap();
-            path = &args[..space_index];
+            &args[..space_index]
         } else {
-            path = &args[..];
-        }
+            &args[..]
+        };
 
-        println!("{}: {}","[ ] Saving file to", path);
-        match dfile.save(path.to_string()) {
+        println!("[ ] Saving file to: {}", path);
+        match save_or_merge(dfile, path.to_string()) {
             Ok(_) => return 0,
             Err(e) => println!("{}: {}", "[-] Failed to save file".red(), e)
         };
@@ -173,7 +425,7 @@ fn save(args: String, dfile: &mut Datafile) -> u32 {
     // assuming something doesnt work or we dont get args, we just loop to try 
     // and save the user's file
     loop {
-        print!("{}", "[ ] Enter path to save file to > ");
+        print!("[ ] Enter path to save file to > ");
         std::io::stdout().flush().unwrap();
     
         let mut r = String::new();
@@ -181,7 +433,7 @@ fn save(args: String, dfile: &mut Datafile) -> u32 {
         let r = r.replace("\n", "");
         
         // try to save the file
-        match dfile.save(r) {
+        match save_or_merge(dfile, r) {
             Ok(_) => {
                 println!("{}", "[+] Success!".green());
                 return 0
@@ -191,18 +443,77 @@ fn save(args: String, dfile: &mut Datafile) -> u32 {
     }
 }
 
-/// adds a file
+/// splits the arguments of `add` or `extract` into the paths and the
+/// options: `-r`, `-p`, `-C DEST`, `--overwrite POLICY`, `--include GLOB`
+/// and `--exclude GLOB`
+fn tree_args(args: &str) -> Result<TreeArgs, String> {
+    let mut out = TreeArgs::default();
+    let mut words = args.split_whitespace();
+    while let Some(word) = words.next() {
+        let mut value = || match words.next() {
+            Some(a) => Ok(a.to_string()),
+            None => Err(format!("{} needs a value", word))
+        };
+        match word {
+            "-r" | "--recursive" => out.recursive = true,
+            "-p" | "--preserve" => out.preserve = true,
+            "-C" => out.dest = Some(value()?),
+            "--overwrite" => match Overwrite::from_name(&value()?) {
+                Some(a) => out.overwrite = Some(a),
+                None => return Err(format!("{} takes never, ask, always or newer", word))
+            },
+            "--include" => out.include.push(value()?),
+            "--exclude" => out.exclude.push(value()?),
+            _ => out.paths.push(word.to_string())
+        };
+    }
+    Ok(out)
+}
+
+/// prints what adding or extracting a tree did
+fn tree_summary(what: &str, summary: &tree::Summary) {
+    cli::warn_skipped(summary);
+    println!("{} {} {} {} {}", format!("[+] {}", what).green(), summary.files, "files and".green(), summary.dirs, "directories".green());
+}
+
+/// adds a directory and everything in it to the current directory
+fn add_recursive(opts: TreeArgs, dfile: &mut Datafile) -> u32 {
+    let path = opts.paths.first().cloned().unwrap_or_default();
+    println!("{}: {}","[ ] Adding directory".yellow(), path);
+    let res = Filter::new(&opts.include, &opts.exclude)
+        .and_then(|f| tree::add_tree(dfile, std::path::Path::new(&path), &cwd(), &f, false, &mut cli::progress));
+    match res {
+        Ok(summary) => {
+            tree_summary("Added", &summary);
+            0
+        },
+        Err(e) => {
+            println!("{}: {}", "[-] Failed to add directory".red(), e);
+            1
+        }
+    }
+}
+
+/// adds a file, or with `-r` a directory and everything in it
 fn add(args: String, dfile: &mut Datafile) -> u32 {
     // see if we got a path
     if !args.is_empty() {
-        let path: &str;
-        // see if we can save to the first path provided by the arguments
-        if args.contains(" ") {
-            let space_index = args.as_bytes().iter().position(|&r| r == b' ').unwrap();
-            path = &args[..space_index];
-        } else {
-            path = &args[..];
+        let opts = match tree_args(&args) {
+            Ok(a) => a,
+            Err(e) => {
+                println!("{} {}", "[-]".red(), e);
+                return 1
+            }
+        };
+        let path = opts.paths.first().cloned().unwrap_or_default();
+        if std::path::Path::new(&path).symlink_metadata().map(|m| m.is_dir()).unwrap_or(false) {
+            if !opts.recursive {
+                println!("{} {} {}", "[-]".red(), path, "is a directory, use add -r to add it along with everything in it".red());
+                return 1
+            }
+            return add_recursive(opts, dfile);
         }
+        let path: &str = &path;
 
         println!("{}: {}","[ ] Adding file".yellow(), path);
         let path = std::path::Path::new(path);
@@ -228,7 +539,7 @@ fn add(args: String, dfile: &mut Datafile) -> u32 {
             }
         };
 
-        match dfile.add_file(name, path) {
+        match tree::add_one(dfile, std::path::Path::new(&path), path::join(&cwd(), &name), false) {
             Ok(_) => return 0,
             Err(e) => println!("{}: {}", "[-] Failed to save file".red(), e)
         };
@@ -237,7 +548,7 @@ fn add(args: String, dfile: &mut Datafile) -> u32 {
     // assuming something doesnt work or we dont get args, we just loop to try 
     // and save the user's file
     loop {
-        print!("{}", "[ ] Enter path to new file > ");
+        print!("[ ] Enter path to new file > ");
         std::io::stdout().flush().unwrap();
     
         let mut r = String::new();
@@ -268,8 +579,8 @@ fn add(args: String, dfile: &mut Datafile) -> u32 {
             }
         };
         
-        // try to save the file
-        match dfile.add_file(name, path) {
+        // try to save the file, storing a symlink as a link like `add <path>` does
+        match tree::add_one(dfile, std::path::Path::new(&path), path::join(&cwd(), &name), false) {
             Ok(_) => {
                 println!("{}", "[+] Success!".green());
                 return 0
@@ -284,34 +595,34 @@ fn remove(_args: String, dfile: &mut Datafile) -> u32 {
     loop {
         ls("".to_string(), dfile);
 
-        print!("{}", "[ ] Enter file name > ");
+        print!("[ ] Enter file name > ");
         std::io::stdout().flush().unwrap();
     
         let mut r = String::new();
         std::io::stdin().read_line(&mut r).expect("Failed to read STDIN");
-        let r = r.replace("\n", "");
-        let fname = r.clone();
-        let r = r.into_bytes();
-
-        let mut idx: i32 = 0;
-        let mut found_idx: i32 = -1;
-        // loop over each file and see if the name is the same
-        for file in dfile.files_mut().iter_mut() {
-            
-            if file.get_fname() == r {
-                found_idx = idx;
-                break;                
+        let fname = r.replace("\n", "");
+        let r = match resolve(&fname) {
+            Ok(a) => a,
+            Err(e) => {
+                println!("{}: {}", "[-] Invalid name".yellow(), e);
+                continue;
             }
-            idx += 1;
-        }
+        };
 
-        // see if we found the file
-        if found_idx != -1 {
-            println!("{}: {}", "[ ] Removing file", fname.clone());
+        // see if we have a file by that name
+        if exists(dfile, &r) {
+            println!("[ ] Removing file: {}", fname);
                 
-            dfile.remove_file_idx(found_idx as usize);
-            println!("{}", "[+] Success!");
-            return 0
+            return match dfile.remove(&r) {
+                Ok(_) => {
+                    println!("[+] Success!");
+                    0
+                },
+                Err(e) => {
+                    println!("{}: {}", "[-] Failed to remove file".red(), e);
+                    1
+                }
+            };
             
         }
 
@@ -321,52 +632,193 @@ fn remove(_args: String, dfile: &mut Datafile) -> u32 {
     }
 }
 
-/// fetches a file and stores it wherever the user wants it to be stored
-fn fetch(args: String, dfile: &mut Datafile) -> u32 {
-    
-    if !args.is_empty() {
+/// renames a file
+fn rename(_args: String, dfile: &mut Datafile) -> u32 {
+    loop {
+        ls("".to_string(), dfile);
+
+        print!("[ ] Enter file name > ");
+        std::io::stdout().flush().unwrap();
     
-        // Will work on the argument parsing part later... 
-        // gonna be a bit complicated with 2 potential args...
-        unimplemented!();
+        let mut r = String::new();
+        std::io::stdin().read_line(&mut r).expect("Failed to read STDIN");
+        let fname = r.replace("\n", "");
+        let old = match resolve(&fname) {
+            Ok(a) if exists(dfile, &a) => a,
+            _ => {
+                // if we get here, we know that the user failed to input the correct name
+                println!("{}: {}", "[-] No file by that name found".yellow(), fname);
+                continue;
+            }
+        };
+
+        print!("[ ] Enter new name > ");
+        std::io::stdout().flush().unwrap();
     
-        let path: &str;
-        // see if we can save to the first path provided by the arguments
-        if args.contains(" ") {
-            let space_index = args.as_bytes().iter().position(|&r| r == b' ').unwrap();
-            path = &args[..space_index];
-        } else {
-            path = &args[..];
-        }
+        let mut new_name = String::new();
+        std::io::stdin().read_line(&mut new_name).expect("Failed to read STDIN");
+        let new_name = new_name.replace("\n", "");
 
-        println!("{}: {}","[ ] Adding file".yellow(), path);
-        let path = std::path::Path::new(path);
-        let name = match path.file_name() {
-            Some(a) => match a.to_os_string().into_string(){
-                Ok(b) => b.into_bytes(),
-                Err(_) => {
-                    println!("{}", "[-] Failed to parse path".red());
-                    return 1
-                }
+        println!("[ ] Renaming file: {} -> {}", fname, new_name);
+        return match resolve(&new_name).and_then(|new| dfile.rename(&old, new)) {
+            Ok(_) => {
+                println!("[+] Success!");
+                0
             },
-            None => {
-                println!("{}", "[-] Failed to parse path".red());
-                return 1
+            Err(e) => {
+                println!("{}: {}", "[-] Failed to rename file".red(), e);
+                1
             }
         };
+    }
+}
 
-        let path = match path.to_str(){
-            Some(a) => a.to_string(),
-            None => {
-                println!("{}", "[-] Failed to parse path".red());
-                return 1
-            }
-        };
+/// rewrites the archive without any dead records
+fn compact(_args: String, dfile: &mut Datafile) -> u32 {
+    println!("{}: {}", "[ ] Compacting archive".yellow(), dfile.path());
+    match dfile.compact() {
+        Ok(freed) => {
+            println!("{} {} {}", "[+] Freed".green(), freed, "bytes".green());
+            0
+        },
+        Err(e) => {
+            println!("{}: {}", "[-] Failed to compact archive".red(), e);
+            1
+        }
+    }
+}
 
-        match dfile.add_file(name, path) {
-            Ok(_) => return 0,
-            Err(e) => println!("{}: {}", "[-] Failed to save file".red(), e)
+/// prints how much space the archive takes up and how well it deduplicates
+fn stats(_args: String, dfile: &mut Datafile) -> u32 {
+    match dfile.stats() {
+        Ok(stats) => {
+            println!("{}", stats);
+            0
+        },
+        Err(e) => {
+            println!("{}: {}", "[-] Failed to read archive stats".red(), e);
+            1
+        }
+    }
+}
+
+/// extracts a file or directory, along with everything in it
+fn extract(args: String, dfile: &mut Datafile) -> u32 {
+    let opts = match tree_args(&args) {
+        Ok(a) => a,
+        Err(e) => {
+            println!("{} {}", "[-]".red(), e);
+            return 1
+        }
+    };
+    let prefix = match resolve(opts.paths.first().map(|a| &a[..]).unwrap_or_default()) {
+        Ok(a) => a,
+        Err(e) => {
+            println!("{}: {}", "[-] Failed to extract".red(), e);
+            return 1
+        }
+    };
+    let dest = match opts.dest {
+        Some(a) => a,
+        None => {
+            print!("[ ] Enter directory to extract to > ");
+            std::io::stdout().flush().unwrap();
+            let mut r = String::new();
+            std::io::stdin().read_line(&mut r).expect("Failed to read STDIN");
+            r.replace("\n", "")
+        }
+    };
+
+    println!("{}: {} -> {}", "[ ] Extracting".yellow(), show_path(&prefix), dest);
+    let restore = Restore{permissions: opts.preserve, times: opts.preserve, ..Restore::default()};
+    let overwrite = opts.overwrite.unwrap_or(Overwrite::Ask);
+    let extract = ExtractOptions{restore, overwrite, ask: cli::ask_overwrite, ..ExtractOptions::default()};
+    let res = Filter::new(&opts.include, &opts.exclude)
+        .and_then(|f| tree::extract_tree(dfile, &prefix, std::path::Path::new(&dest), &f, &extract, &mut cli::progress));
+    match res {
+        Ok(summary) => {
+            tree_summary("Extracted", &summary);
+            0
+        },
+        Err(e) => {
+            println!("{}: {}", "[-] Failed to extract".red(), e);
+            1
+        }
+    }
+}
+
+/// prints a file, or just a byte range of it, to the terminal
+fn cat(args: String, dfile: &mut Datafile) -> u32 {
+    let args = args.trim().to_string();
+
+    // a trailing START:END is the range, anything before it is the name
+    let (fname, range) = if !args.is_empty() {
+        match args.rfind(' ') {
+            Some(a) if args[a+1..].contains(':') => (args[..a].trim().to_string(), args[a+1..].to_string()),
+            _ => (args, ":".to_string())
+        }
+    } else {
+        ls("".to_string(), dfile);
+
+        print!("[ ] Enter file name > ");
+        std::io::stdout().flush().unwrap();
+        let mut r = String::new();
+        std::io::stdin().read_line(&mut r).expect("Failed to read STDIN");
+        let fname = r.replace("\n", "");
+
+        print!("[ ] Enter byte range (START:END, empty for all of it) > ");
+        std::io::stdout().flush().unwrap();
+        let mut r = String::new();
+        std::io::stdin().read_line(&mut r).expect("Failed to read STDIN");
+        let range = match r.trim() {
+            "" => ":".to_string(),
+            a => a.to_string()
         };
+        (fname, range)
+    };
+
+    let (start, end) = match cli::parse_range(&range) {
+        Ok(a) => a,
+        Err(e) => {
+            println!("{} {}", "[-]".red(), e);
+            return 1
+        }
+    };
+    let mut reader = match resolve(&fname).and_then(|a| dfile.open_entry(&a)) {
+        Ok(a) => a,
+        Err(e) => {
+            println!("{}: {}", "[-] Failed to open file".red(), e);
+            return 1
+        }
+    };
+    if start > reader.len() {
+        println!("{} Range starts past the end of the file ({} bytes)", "[-]".red(), reader.len());
+        return 1
+    }
+    let len = std::cmp::min(end.unwrap_or(u64::MAX), reader.len()) - start;
+
+    let stdout = std::io::stdout();
+    let mut handle = stdout.lock();
+    let res = reader.seek(std::io::SeekFrom::Start(start))
+        .and_then(|_| std::io::copy(&mut reader.take(len), &mut handle));
+    println!();
+    match res {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("{}: {}", "[-] Failed to read file".red(), e);
+            1
+        }
+    }
+}
+
+/// fetches a file and stores it wherever the user wants it to be stored
+fn fetch(args: String, dfile: &mut Datafile) -> u32 {
+    
+    if !args.is_empty() {
+    
+        // Will work on the argument parsing part later... 
+        // gonna be a bit complicated with 2 potential args...
+        unimplemented!();
     } 
 
     // assuming something doesnt work or we dont get args, we just loop to try 
@@ -374,20 +826,20 @@ fn fetch(args: String, dfile: &mut Datafile) -> u32 {
     loop {
         ls("".to_string(), dfile);
 
-        print!("{}", "[ ] Enter file name > ");
+        print!("[ ] Enter file name > ");
         std::io::stdout().flush().unwrap();
     
         let mut r = String::new();
         std::io::stdin().read_line(&mut r).expect("Failed to read STDIN");
         let r = r.replace("\n", "");
         let fname = r.clone();
-        let r = r.into_bytes();
+        let r = resolve(&r).unwrap_or_default();
 
         // loop over each file and see if the name is the same
         for file in dfile.files() {
             
             if file.get_fname() == r {
-                print!("{}", "[ ] Enter file path to save > ");
+                print!("[ ] Enter file path to save > ");
                 std::io::stdout().flush().unwrap();
     
                 let mut path = String::new();
@@ -395,9 +847,10 @@ fn fetch(args: String, dfile: &mut Datafile) -> u32 {
                 let path = path.replace("\n", "");
 
                 println!("{}: {}","[ ] Saving to filesystem".yellow(), path);
-                match dfile.save_to_file(file, path) {
+                let opts = ExtractOptions{overwrite: Overwrite::Ask, ask: cli::ask_overwrite, ..ExtractOptions::default()};
+                match dfile.save_to_file(file, path, &opts) {
                     Ok(_) => {
-                        println!("{}", "[+] Success!");
+                        println!("[+] Success!");
                         return 0
                     },
                     Err(e) => {
@@ -420,40 +873,45 @@ fn fetch(args: String, dfile: &mut Datafile) -> u32 {
 
 /// updates the current password
 fn pass(args: String, dfile: &mut Datafile) -> u32 {
+    // a password typed on the command line ends up in the terminal's
+    // scrollback, so it is only ever asked for without echoing
     if !args.is_empty() {
-        let pass: &str;
-        // see if we can save to the first path provided by the arguments
-        if args.contains(" ") {
-            println!("{}", "[-] Found space in password. Ignoring...".red());
+        println!("{}", "[-] pass takes no arguments, it asks for the new password".red());
+        return 1;
+    }
+
+    let ask = |msg: &str| PasswordSource::Prompt(format!("{}", msg.green())).read();
+    let (r, confirm) = match ask("[ ] Enter a password > ").and_then(|a| Ok((a, ask("[ ] Repeat the password > ")?))) {
+        Ok(a) => a,
+        Err(e) => {
+            println!("{}: {}", "[-] Failed to read password".red(), e);
             return 1;
-        } else {
-            pass = &args[..];
         }
-
-        println!("{}","[ ] Updating password...".yellow());
-        dfile.update_pass(pass.to_string());
-    } 
-
-    // assuming something doesnt work or we dont get args, we just loop to try 
-    // and save the user's file
-    
-    print!("{}", "[ ] Enter a password > ");
-    std::io::stdout().flush().unwrap();
-    let mut r = String::new();
-    std::io::stdin().read_line(&mut r).expect("Failed to read STDIN");
-    let r = r.replace("\n", "");
+    };
+    if r != confirm {
+        println!("{}", "[-] Passwords do not match".red());
+        return 1;
+    }
     
     // try to save the file
-    dfile.update_pass(r);
-    0    
+    match dfile.update_pass(r) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("{}: {}", "[-] Failed to update password".red(), e);
+            1
+        }
+    }
 }
 
-/// like `pass()`, but instead returns string of password. Useful for states 
-/// before Datafile is initialized
-fn get_pass() -> String {
-    prompt_password_stdout(
-        &format!("{}", "[ ] Enter password > ".green())[..]
-    ).unwrap()
+/// reads a password from the source the archive was opened with, or asks
+/// for it if there wasn't one. Useful for states before Datafile is
+/// initialized
+fn get_pass() -> Result<String, VaultError> {
+    let source = PASS_SOURCE.lock().unwrap().clone();
+    match source {
+        Some(a) => a.read(),
+        None => PasswordSource::Prompt(format!("{}", "[ ] Enter password > ".green())).read()
+    }
 }
 
 /// prints help info
@@ -483,26 +941,112 @@ fn main() {
                             .value_name("FILE")
                             .takes_value(true)
                             .help("Creates a new archive with name FILE"))
+                        .arg(Arg::with_name("migrate")
+                            .long("migrate")
+                            .value_name("FILE")
+                            .takes_value(true)
+                            .conflicts_with_all(&["new", "datafile"])
+                            .help("Rewrites an archive FILE in an older format (v0–v2) to the current format"))
+                        .args(&cli::crypto_args())
+                        .args(&cli::password_args())
+                        .args(&cli::save_args())
+                        .subcommands(cli::subcommands())
                         .get_matches();
 
+    // subcommands run without the REPL, and exit once they are done
+    if let (name, Some(sub)) = matches.subcommand() {
+        std::process::exit(cli::run(name, sub));
+    }
+
     let mut dfile: Datafile;
     let mut path: String;
 
     // see if we are gonna try to make a new file or if we are working with a pre-existing one
-    if !matches.is_present("new") && !matches.is_present("datafile") {
+    if !matches.is_present("new") && !matches.is_present("datafile") && !matches.is_present("migrate") {
         println!("[-] Missing arguments");
         println!("{}", matches.usage());
         std::process::exit(1);
     }
 
+    // figure out the key derivation settings for new archives
+    let (kdf, cipher) = match cli::crypto_settings(&matches) {
+        Ok(a) => a,
+        Err(e) => {
+            println!("{} {}", "[-]".red(), e);
+            std::process::exit(1);
+        }
+    };
+
+    // look at the archive's header before bothering the user for a password
+    for (arg, legacy_ok) in [("datafile", false), ("migrate", true)] {
+        if let Some(a) = matches.value_of(arg) {
+            match Header::probe(a) {
+                Ok(Probe::Current(h)) if !legacy_ok => println!("{}: {}", "[ ] Opening archive".yellow(), h),
+                Ok(Probe::Current(_)) => {
+                    println!("{}", "[-] Archive is already in the current format".red());
+                    std::process::exit(1);
+                },
+                Ok(Probe::Outdated(h)) if legacy_ok => println!("{}: {}", "[ ] Migrating archive".yellow(), h),
+                Ok(Probe::Outdated(h)) => {
+                    println!("{} {}", "[-] This archive uses".red(), format!("format v{}. Convert it with --migrate first", h.version).red());
+                    std::process::exit(1);
+                },
+                Ok(Probe::Legacy) if legacy_ok => println!("{}", "[ ] Migrating v0 archive".yellow()),
+                Ok(Probe::Legacy) => {
+                    println!("{}", "[-] This looks like a v0 archive. Convert it with --migrate first".red());
+                    std::process::exit(1);
+                },
+                Ok(Probe::NotVault(e)) => {
+                    println!("{}: {}", "[-] Not a vault archive".red(), e);
+                    std::process::exit(1);
+                },
+                Err(e) => {
+                    println!("{}: {}", "[-] Failed to read archive".red(), e);
+                    std::process::exit(1);
+                }
+            };
+        }
+    }
+
+    let source = match cli::password_source(&matches) {
+        Ok(a) => a,
+        Err(e) => {
+            println!("{} {}", "[-]".red(), e);
+            std::process::exit(1);
+        }
+    };
+    let (backups, codec) = match cli::backups(&matches).and_then(|b| Ok((b, cli::codec(&matches)?))) {
+        Ok(a) => a,
+        Err(e) => {
+            println!("{} {}", "[-]".red(), e);
+            std::process::exit(1);
+        }
+    };
+
     // loop until the user has successfully decrypted the file
     loop {
-        let aes_pass = get_pass();
-        if matches.is_present("new") {
+        let aes_pass = match source.read() {
+            Ok(a) => a,
+            Err(e) => {
+                println!("{}: {}", "[-] Failed to read password".red(), e);
+                std::process::exit(1);
+            }
+        };
+        if let Some(a) = matches.value_of("migrate") {
+            path = a.to_string();
+            match Datafile::migrate(path, aes_pass, kdf, cipher) {
+                Ok(a) => {
+                    println!("{}", "[+] Archive migrated to the current format".green());
+                    dfile = a;
+                    break;
+                },
+                Err(e) => println!("{}{}", "[-] Failed to migrate data file: ".red(), e)
+            };
+        } else if matches.is_present("new") {
             match matches.value_of("new") {
                 Some(a) => {
                     path = a.to_string();
-                    match Datafile::setup_new(aes_pass, path) {
+                    match Datafile::setup_new(aes_pass, path, kdf, cipher) {
                         Ok(a) => {
                             dfile = a;
                             break;
@@ -516,7 +1060,7 @@ fn main() {
             match matches.value_of("datafile") {
                 Some(a) => {
                     path = a.to_string();
-                    match Datafile::checked_new(path, aes_pass){
+                    match open_archive(path, aes_pass){
                         Ok(a) => {
                             dfile = a;
                             break;
@@ -529,10 +1073,21 @@ fn main() {
             
         }
 
+        // asking a script or file again would just give the same password
+        if !source.is_interactive() {
+            std::process::exit(1);
+        }
+    }
+    dfile.set_backups(backups);
+    dfile.set_codec(codec);
+    cli::warn_torn(&dfile);
+    // a descriptor has been used up by now, so `load` asks instead
+    if source.can_read_again() {
+        *PASS_SOURCE.lock().unwrap() = Some(source);
     }
     
-    
-    
+    println!("{} {} {}", "[+] Opened archive with".green(), dfile.num_files(), "files".green());
+
     // begin our main interaction loop
     loop {
         let mut user_cmd = String::new();
@@ -541,17 +1096,12 @@ fn main() {
         std::io::stdin().read_line(&mut user_cmd).unwrap(); 
         let user_cmd = user_cmd.replace("\n", "");
 
-        let cmd: &str;
-        let args: &str;
-        if user_cmd.contains(" ") {
+        let (cmd, args): (&str, &str) = if user_cmd.contains(' ') {
             let space_index = user_cmd.as_bytes().iter().position(|&r| r == b' ').unwrap();
-            cmd = &user_cmd[..space_index];
-            args = &user_cmd[space_index+1..]
-        
+            (&user_cmd[..space_index], &user_cmd[space_index+1..])
         } else {
-            cmd = &user_cmd[..];
-            args = &"";
-        }
+            (&user_cmd[..], "")
+        };
 
         let mut ret_val = 0xffffffff;
         // figure out what command it is
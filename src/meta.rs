@@ -0,0 +1,361 @@
+use std::convert::TryFrom;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::VaultError;
+
+
+/// Linux keeps POSIX ACLs in extended attributes whose names start with this
+const ACL_PREFIX: &[u8] = b"system.posix_acl_";
+
+/// Length of a serialized `ATTR_METADATA` value, leaving out the names of
+/// the owner and group
+const FIXED_LEN: usize = 4 + 4 + 4 + 3 * 12;
+
+
+////////////////////////// DEFINITIONS /////////////////////////////////////
+/// A point in time, as seconds and nanoseconds since the Unix epoch
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Timestamp {
+    pub secs: i64,
+    pub nanos: u32
+}
+
+/// What a file looked like on disk when it was added
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metadata {
+    /// permission bits, along with the setuid, setgid and sticky bits
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// name of the owner, or empty if the uid had none. Ids differ between
+    /// machines, so restoring ownership looks the name up first
+    pub user: Vec<u8>,
+    /// name of the group, or empty if the gid had none
+    pub group: Vec<u8>,
+    pub mtime: Timestamp,
+    pub atime: Timestamp,
+    /// when the inode last changed. Nothing can set this, so it is only kept
+    /// for reference
+    pub ctime: Timestamp,
+    /// extended attributes as (name, value) pairs, POSIX ACLs included
+    pub xattrs: Vec<(Vec<u8>, Vec<u8>)>
+}
+
+/// Which optional metadata gets recorded when files are added. Permissions,
+/// ownership and timestamps always are
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Capture {
+    pub xattrs: bool,
+    pub acls: bool
+}
+
+/// Which metadata gets put back when files are extracted
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Restore {
+    /// the permission bits
+    pub permissions: bool,
+    /// the owner and group, which usually takes root
+    pub owner: bool,
+    /// the modification and access times
+    pub times: bool,
+    pub xattrs: bool,
+    pub acls: bool
+}
+
+
+////////////////////////// TIMESTAMP FUNCTIONS //////////////////////////
+impl Timestamp {
+    /// converts a time from the filesystem
+    pub fn from_system_time(t: SystemTime) -> Self {
+        match t.duration_since(UNIX_EPOCH) {
+            Ok(a) => Timestamp{secs: a.as_secs() as i64, nanos: a.subsec_nanos()},
+            // before the epoch, so count back from it
+            Err(e) => {
+                let d = e.duration();
+                let secs = (d.as_secs() as i64).saturating_neg();
+                match d.subsec_nanos() {
+                    0 => Timestamp{secs, nanos: 0},
+                    n => Timestamp{secs: secs.saturating_sub(1), nanos: 1_000_000_000 - n}
+                }
+            }
+        }
+    }
+
+    /// converts the timestamp into a time the filesystem takes, failing if
+    /// it is too far from the epoch for this platform to represent
+    pub fn to_system_time(self) -> Result<SystemTime, VaultError> {
+        let nanos = Duration::from_nanos(self.nanos as u64);
+        let time = match self.secs {
+            s if s >= 0 => UNIX_EPOCH.checked_add(Duration::from_secs(s as u64)),
+            s => UNIX_EPOCH.checked_sub(Duration::from_secs(s.unsigned_abs()))
+        };
+        match time.and_then(|t| t.checked_add(nanos)) {
+            Some(a) => Ok(a),
+            None => Err(VaultError::Corrupt(format!("timestamp {}.{:09} is out of range", self.secs, self.nanos)))
+        }
+    }
+
+    fn serialize(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.secs.to_le_bytes());
+        out.extend_from_slice(&self.nanos.to_le_bytes());
+    }
+
+    fn parse(data: &[u8]) -> Option<Self> {
+        let secs = i64::from_le_bytes(<[u8; 8]>::try_from(data.get(..8)?).ok()?);
+        let nanos = u32::from_le_bytes(<[u8; 4]>::try_from(data.get(8..12)?).ok()?);
+        if nanos >= 1_000_000_000 {
+            return None;
+        }
+        Some(Timestamp{secs, nanos})
+    }
+}
+
+
+////////////////////////// METADATA FUNCTIONS //////////////////////////
+impl Metadata {
+    /// reads the metadata of the file or directory at `path`, following
+    /// symlinks like opening it would
+    pub fn read(path: &Path, capture: Capture) -> Result<Self, VaultError> {
+        Metadata::read_from(path, capture, true)
+    }
+
+    /// reads the metadata of whatever is at `path`, which for a symlink is
+    /// the link itself rather than what it points at
+    pub fn read_link(path: &Path, capture: Capture) -> Result<Self, VaultError> {
+        Metadata::read_from(path, capture, false)
+    }
+
+    #[cfg(unix)]
+    fn read_from(path: &Path, capture: Capture, follow: bool) -> Result<Self, VaultError> {
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::MetadataExt;
+
+        let m = match follow {
+            true => std::fs::metadata(path)?,
+            false => std::fs::symlink_metadata(path)?
+        };
+        let time = |secs: i64, nanos: i64| Timestamp{secs, nanos: nanos as u32};
+        let user = match uzers::get_user_by_uid(m.uid()) {
+            Some(a) => a.name().as_bytes().to_vec(),
+            None => Vec::new()
+        };
+        let group = match uzers::get_group_by_gid(m.gid()) {
+            Some(a) => a.name().as_bytes().to_vec(),
+            None => Vec::new()
+        };
+        let mut meta = Metadata{
+            mode: m.mode() & 0o7777,
+            uid: m.uid(),
+            gid: m.gid(),
+            user,
+            group,
+            mtime: time(m.mtime(), m.mtime_nsec()),
+            atime: time(m.atime(), m.atime_nsec()),
+            ctime: time(m.ctime(), m.ctime_nsec()),
+            xattrs: Vec::new()
+        };
+        if !capture.xattrs && !capture.acls {
+            return Ok(meta);
+        }
+
+        // filesystems without extended attributes simply have none
+        let names = match if follow { xattr::list_deref(path) } else { xattr::list(path) } {
+            Ok(a) => a,
+            Err(e) if e.kind() == std::io::ErrorKind::Unsupported => return Ok(meta),
+            Err(e) => return Err(e.into())
+        };
+        for name in names {
+            let wanted = match is_acl(name.as_bytes()) {
+                true => capture.acls,
+                false => capture.xattrs
+            };
+            if !wanted {
+                continue;
+            }
+            // it may have been removed since we listed it
+            let value = match follow {
+                true => xattr::get_deref(path, &name)?,
+                false => xattr::get(path, &name)?
+            };
+            if let Some(value) = value {
+                meta.xattrs.push((name.as_bytes().to_vec(), value));
+            }
+        }
+        Ok(meta)
+    }
+
+    /// reads what metadata this platform has, which is only whether the file
+    /// is read-only and its timestamps
+    #[cfg(not(unix))]
+    fn read_from(path: &Path, _capture: Capture, follow: bool) -> Result<Self, VaultError> {
+        let m = match follow {
+            true => std::fs::metadata(path)?,
+            false => std::fs::symlink_metadata(path)?
+        };
+        let time = |t: std::io::Result<SystemTime>| t.map(Timestamp::from_system_time).unwrap_or_default();
+        Ok(Metadata{
+            mode: if m.permissions().readonly() { 0o444 } else { 0o644 },
+            mtime: time(m.modified()),
+            atime: time(m.accessed()),
+            ..Metadata::default()
+        })
+    }
+
+    /// puts back the parts of the metadata `restore` asks for on whatever is
+    /// at `path`, without following it if it is a symlink. The times go
+    /// last, since everything else can change them
+    #[cfg(unix)]
+    pub fn apply(&self, path: &Path, restore: &Restore) -> Result<(), VaultError> {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::PermissionsExt;
+
+        // symlinks have no permissions of their own
+        let is_link = std::fs::symlink_metadata(path)?.file_type().is_symlink();
+
+        // changing the owner clears the setuid and setgid bits, so it has to
+        // come before the permissions
+        if restore.owner {
+            let uid = match uzers::get_user_by_name(OsStr::from_bytes(&self.user)) {
+                Some(a) if !self.user.is_empty() => a.uid(),
+                _ => self.uid
+            };
+            let gid = match uzers::get_group_by_name(OsStr::from_bytes(&self.group)) {
+                Some(a) if !self.group.is_empty() => a.gid(),
+                _ => self.gid
+            };
+            std::os::unix::fs::lchown(path, Some(uid), Some(gid))?;
+        }
+        for (name, value) in self.xattrs.iter() {
+            let wanted = match is_acl(name) {
+                true => restore.acls,
+                false => restore.xattrs
+            };
+            if wanted {
+                xattr::set(path, OsStr::from_bytes(name), value)?;
+            }
+        }
+        if restore.permissions && !is_link {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(self.mode))?;
+        }
+        if restore.times {
+            // handed over as they are, so times the system can't set come
+            // back as an error from it
+            let mtime = filetime::FileTime::from_unix_time(self.mtime.secs, self.mtime.nanos);
+            let atime = filetime::FileTime::from_unix_time(self.atime.secs, self.atime.nanos);
+            filetime::set_symlink_file_times(path, atime, mtime)?;
+        }
+        Ok(())
+    }
+
+    /// puts back what this platform can of the metadata on the file at `path`
+    #[cfg(not(unix))]
+    pub fn apply(&self, path: &Path, restore: &Restore) -> Result<(), VaultError> {
+        if restore.permissions {
+            let mut perms = std::fs::metadata(path)?.permissions();
+            perms.set_readonly(self.mode & 0o222 == 0);
+            std::fs::set_permissions(path, perms)?;
+        }
+        if restore.times {
+            let times = std::fs::FileTimes::new()
+                .set_modified(self.mtime.to_system_time()?)
+                .set_accessed(self.atime.to_system_time()?);
+            std::fs::File::open(path)?.set_times(times)?;
+        }
+        Ok(())
+    }
+
+    /// serializes everything but the extended attributes, for an
+    /// `ATTR_METADATA` attribute
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out: Vec<u8> = Vec::with_capacity(FIXED_LEN + 2 + self.user.len() + self.group.len());
+        out.extend_from_slice(&self.mode.to_le_bytes());
+        out.extend_from_slice(&self.uid.to_le_bytes());
+        out.extend_from_slice(&self.gid.to_le_bytes());
+        self.mtime.serialize(&mut out);
+        self.atime.serialize(&mut out);
+        self.ctime.serialize(&mut out);
+        // names too long to store are left out, and the ids used instead
+        for name in [&self.user, &self.group].iter() {
+            match u8::try_from(name.len()) {
+                Ok(len) => {
+                    out.push(len);
+                    out.extend_from_slice(name);
+                },
+                Err(_) => out.push(0)
+            };
+        }
+        out
+    }
+
+    /// parses the value of an `ATTR_METADATA` attribute, returning None if
+    /// it is malformed
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let word = |pos: usize| Some(u32::from_le_bytes(<[u8; 4]>::try_from(data.get(pos..pos+4)?).ok()?));
+        let mut meta = Metadata{
+            mode: word(0)?,
+            uid: word(4)?,
+            gid: word(8)?,
+            mtime: Timestamp::parse(data.get(12..)?)?,
+            atime: Timestamp::parse(data.get(24..)?)?,
+            ctime: Timestamp::parse(data.get(36..)?)?,
+            ..Metadata::default()
+        };
+        if meta.mode > 0o7777 {
+            return None;
+        }
+
+        let mut pos = FIXED_LEN;
+        for name in [&mut meta.user, &mut meta.group].iter_mut() {
+            let len = *data.get(pos)? as usize;
+            **name = data.get(pos+1..pos+1+len)?.to_vec();
+            pos += 1 + len;
+        }
+        match pos == data.len() {
+            true => Some(meta),
+            false => None
+        }
+    }
+
+    /// serializes the extended attributes for an `ATTR_XATTRS` attribute:
+    /// each one is a u32 length and its name, then a u32 length and its value
+    pub fn serialize_xattrs(&self) -> Vec<u8> {
+        let mut out: Vec<u8> = Vec::new();
+        for (name, value) in self.xattrs.iter() {
+            out.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            out.extend_from_slice(name);
+            out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            out.extend_from_slice(value);
+        }
+        out
+    }
+
+    /// parses the value of an `ATTR_XATTRS` attribute, returning None if it
+    /// is malformed
+    pub fn parse_xattrs(data: &[u8]) -> Option<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut out: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+        let mut pos = 0;
+        let field = |pos: &mut usize| -> Option<Vec<u8>> {
+            let len = u32::from_le_bytes(<[u8; 4]>::try_from(data.get(*pos..pos.checked_add(4)?)?).ok()?) as usize;
+            let value = data.get(*pos+4..(*pos+4).checked_add(len)?)?.to_vec();
+            *pos += 4 + len;
+            Some(value)
+        };
+        while pos < data.len() {
+            let name = field(&mut pos)?;
+            if name.is_empty() || name.contains(&0) {
+                return None;
+            }
+            let value = field(&mut pos)?;
+            out.push((name, value));
+        }
+        Some(out)
+    }
+}
+
+
+/// returns true if the extended attribute `name` holds a POSIX ACL
+fn is_acl(name: &[u8]) -> bool {
+    name.starts_with(ACL_PREFIX)
+}
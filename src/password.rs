@@ -0,0 +1,155 @@
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use rpassword::prompt_password_stderr;
+
+use crate::error::VaultError;
+
+
+////////////////////////// DEFINITIONS /////////////////////////////////////
+/// Where to get an archive's password from
+#[derive(Clone, Debug, PartialEq)]
+pub enum PasswordSource {
+    /// Ask on the terminal with the given prompt, without echoing
+    Prompt(String),
+    /// Read the first line from an already open file descriptor
+    Fd(i32),
+    /// Read the first line of a file
+    File(PathBuf),
+    /// Read an environment variable
+    Env(String),
+    /// Run a shell command and use the first line it prints
+    Command(String)
+}
+
+
+/////////////////////////// DISPLAY IMPL ////////////////////////////////////
+impl std::fmt::Display for PasswordSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PasswordSource::Prompt(_) => write!(f, "terminal prompt"),
+            PasswordSource::Fd(fd) => write!(f, "file descriptor {}", fd),
+            PasswordSource::File(path) => write!(f, "file {}", path.display()),
+            PasswordSource::Env(var) => write!(f, "environment variable {}", var),
+            PasswordSource::Command(cmd) => write!(f, "command `{}`", cmd)
+        }
+    }
+}
+
+
+////////////////////////// PASSWORDSOURCE FUNCTIONS //////////////////////////
+impl PasswordSource {
+    /// returns true if the password comes from a person, so it is worth
+    /// asking again when it turns out to be wrong
+    pub fn is_interactive(&self) -> bool {
+        matches!(self, PasswordSource::Prompt(_))
+    }
+
+    /// returns true if reading the password again gives the same answer.
+    /// A descriptor other than stdin is read to its end and closed, after
+    /// which its number may belong to some other file of ours
+    pub fn can_read_again(&self) -> bool {
+        !matches!(self, PasswordSource::Fd(fd) if *fd != 0)
+    }
+
+    /// returns warnings about ways this source could leak the password
+    pub fn warnings(&self) -> Vec<String> {
+        let mut out: Vec<String> = Vec::new();
+        match self {
+            PasswordSource::Env(var) => {
+                out.push(format!(
+                    "Reading the password from ${} exposes it to child processes and to anything that can read this process's environment",
+                    var
+                ));
+            },
+            PasswordSource::File(path) => {
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    if let Ok(meta) = std::fs::metadata(path) {
+                        if meta.permissions().mode() & 0o077 != 0 {
+                            out.push(format!("Password file {} can be read by other users", path.display()));
+                        }
+                    }
+                }
+            },
+            _ => ()
+        };
+        out
+    }
+
+    /// fetches the password
+    pub fn read(&self) -> Result<String, VaultError> {
+        let raw = match self {
+            PasswordSource::Prompt(msg) => return Ok(prompt_password_stderr(msg)?),
+            PasswordSource::Fd(fd) => read_fd(*fd)?,
+            PasswordSource::File(path) => std::fs::read(path)?,
+            PasswordSource::Env(var) => match std::env::var(var) {
+                Ok(a) => a.into_bytes(),
+                Err(_) => return Err(VaultError::Io(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("environment variable {} is not set", var)
+                )))
+            },
+            PasswordSource::Command(cmd) => {
+                let output = Command::new("sh")
+                    .arg("-c")
+                    .arg(cmd)
+                    .stdin(Stdio::inherit())
+                    .stderr(Stdio::inherit())
+                    .output()?;
+                if !output.status.success() {
+                    return Err(VaultError::Io(std::io::Error::other(
+                        format!("password command exited with {}", output.status)
+                    )));
+                }
+                output.stdout
+            }
+        };
+
+        first_line(raw)
+    }
+}
+
+/// reads everything from an inherited file descriptor
+#[cfg(unix)]
+fn read_fd(fd: i32) -> Result<Vec<u8>, VaultError> {
+    use std::os::unix::io::FromRawFd;
+
+    if fd < 0 {
+        return Err(VaultError::Io(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid file descriptor {}", fd))));
+    }
+    // stdin may still be needed for commands afterwards, so only take a line
+    if fd == 0 {
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line)?;
+        return Ok(line.into_bytes());
+    }
+
+    // any other descriptor was handed to us to read the password from, so
+    // it is ours to consume and close
+    let mut f = unsafe { std::fs::File::from_raw_fd(fd) };
+    let mut raw: Vec<u8> = Vec::new();
+    f.read_to_end(&mut raw)?;
+    Ok(raw)
+}
+
+#[cfg(not(unix))]
+fn read_fd(_fd: i32) -> Result<Vec<u8>, VaultError> {
+    Err(VaultError::Unsupported("reading passwords from a file descriptor".to_string()))
+}
+
+/// keeps only the first line of what a source gave us, without its line ending
+fn first_line(raw: Vec<u8>) -> Result<String, VaultError> {
+    let line = match raw.iter().position(|&b| b == b'\n') {
+        Some(a) => &raw[..a],
+        None => &raw[..]
+    };
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+    match String::from_utf8(line.to_vec()) {
+        Ok(a) => Ok(a),
+        Err(_) => Err(VaultError::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, "password is not valid UTF-8")))
+    }
+}
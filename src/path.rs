@@ -0,0 +1,87 @@
+use crate::error::VaultError;
+
+
+/// What separates the components of an entry's path
+pub const SEPARATOR: u8 = b'/';
+
+
+/// returns true if `name` can be stored as an entry's path: relative, split
+/// by single `/`s and without `.` or `..` components, so extracting it can
+/// never land outside the directory it is extracted to
+pub fn is_valid(name: &[u8]) -> bool {
+    !name.is_empty() && !name.contains(&0) &&
+    name.split(|&b| b == SEPARATOR).all(|c| !c.is_empty() && c != b"." && c != b"..")
+}
+
+/// returns the directory holding `name`, which is empty for the root
+pub fn parent(name: &[u8]) -> &[u8] {
+    match name.iter().rposition(|&b| b == SEPARATOR) {
+        Some(a) => &name[..a],
+        None => &[]
+    }
+}
+
+/// returns the last component of `name`
+pub fn file_name(name: &[u8]) -> &[u8] {
+    match name.iter().rposition(|&b| b == SEPARATOR) {
+        Some(a) => &name[a+1..],
+        None => name
+    }
+}
+
+/// returns `name` inside the directory `dir`
+pub fn join(dir: &[u8], name: &[u8]) -> Vec<u8> {
+    let mut out = dir.to_vec();
+    if !out.is_empty() {
+        out.push(SEPARATOR);
+    }
+    out.extend_from_slice(name);
+    out
+}
+
+/// returns true if `name` lies somewhere below the directory `dir`
+pub fn is_within(name: &[u8], dir: &[u8]) -> bool {
+    dir.is_empty() || (name.len() > dir.len() && name.starts_with(dir) && name[dir.len()] == SEPARATOR)
+}
+
+/// returns every directory above `name`, outermost first
+pub fn ancestors(name: &[u8]) -> impl Iterator<Item = &[u8]> {
+    name.iter().enumerate().filter(|(_, &b)| b == SEPARATOR).map(move |(i, _)| &name[..i])
+}
+
+/// turns a path typed by the user into an entry's path. Paths starting with
+/// a `/` start at the root and anything else starts at `cwd`, and `.` and
+/// `..` are followed but can't climb above the root. The root itself comes
+/// back empty
+pub fn resolve(cwd: &[u8], input: &[u8]) -> Result<Vec<u8>, VaultError> {
+    let mut parts: Vec<&[u8]> = Vec::new();
+    if input.first() != Some(&SEPARATOR) {
+        parts.extend(cwd.split(|&b| b == SEPARATOR).filter(|c| !c.is_empty()));
+    }
+    for c in input.split(|&b| b == SEPARATOR) {
+        match c {
+            b"" | b"." => (),
+            b".." => {
+                if parts.pop().is_none() {
+                    return Err(VaultError::InvalidName(String::from_utf8_lossy(input).to_string()));
+                }
+            },
+            _ => parts.push(c)
+        };
+    }
+
+    let out = parts.join(&SEPARATOR);
+    if !out.is_empty() && !is_valid(&out) {
+        return Err(VaultError::InvalidName(String::from_utf8_lossy(input).to_string()));
+    }
+    Ok(out)
+}
+
+/// returns true if a symlink pointing at `target` stays inside the directory
+/// the archive is extracted to. Any `..` is refused, even one that looks
+/// like it stays inside: the components before it may be links themselves,
+/// so `a/b/..` can climb out if `a/b` does. Without `..` every link in a
+/// chain can only point further down from where it is
+pub fn link_stays_within(target: &[u8]) -> bool {
+    target.first() != Some(&SEPARATOR) && !target.split(|&b| b == SEPARATOR).any(|c| c == b"..")
+}
@@ -0,0 +1,320 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use hmac_sha256::HMAC;
+
+use crate::cipher::{NONCE_LEN, new_nonce};
+use crate::error::VaultError;
+use crate::header::Header;
+use crate::stream::{ChunkReader, ChunkWriter, sealed_len};
+
+
+/// The bytes that lead every record, so records can be found by scanning
+pub const RECORD_MAGIC: &[u8; 4] = b"VREC";
+
+/// Length of the plaintext header in front of each record
+pub const RECORD_HEADER_LEN: usize = RECORD_MAGIC.len() + 1 + NONCE_LEN + 8;
+
+/// The bytes that close every trailer
+pub const TRAILER_MAGIC: &[u8; 8] = b"VTRAILER";
+
+/// Length of the trailer that ends the archive
+pub const TRAILER_LEN: usize = 8 + TRAILER_MAGIC.len();
+
+/// What a record's key is derived for, ahead of its nonce
+const RECORD_KEY_INFO: &[u8] = b"vault record key";
+
+
+////////////////////////// DEFINITIONS /////////////////////////////////////
+/// What a record holds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecordKind {
+    /// The contents of a single entry
+    Data,
+    /// A complete file table, replacing every table before it
+    Table
+}
+
+/// What `scan` found at one stretch of an archive
+#[derive(Clone, Debug, PartialEq)]
+pub enum Span {
+    /// A record whose every chunk authenticated, holding `len` bytes of
+    /// plaintext
+    Record{offset: u64, kind: RecordKind, len: u64},
+    /// Something that starts like a record but doesn't authenticate, running
+    /// `len` bytes up to wherever the next record or trailer starts
+    Damaged{offset: u64, len: u64, reason: String},
+    /// A trailer pointing at the table record at `table`
+    Trailer{offset: u64, table: u64},
+    /// Bytes that aren't part of any record or trailer
+    Gap{offset: u64, len: u64}
+}
+
+/// The plaintext header in front of each record. The archive header and
+/// this header are both bound to every chunk of the record as AAD
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RecordHeader {
+    pub kind: RecordKind,
+    pub nonce: [u8; NONCE_LEN],
+    /// How many bytes of sealed chunks follow the header
+    pub sealed_len: u64
+}
+
+
+/////////////////////////// DISPLAY IMPL ////////////////////////////////////
+impl std::fmt::Display for RecordKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecordKind::Data => write!(f, "data"),
+            RecordKind::Table => write!(f, "table")
+        }
+    }
+}
+
+
+////////////////////////// RECORDKIND FUNCTIONS //////////////////////////
+impl RecordKind {
+    /// returns the id we store in the record header
+    pub fn id(&self) -> u8 {
+        match self {
+            RecordKind::Data => 1,
+            RecordKind::Table => 2
+        }
+    }
+
+    /// returns the kind for an id read from a record header
+    pub fn from_id(id: u8) -> Result<Self, VaultError> {
+        match id {
+            1 => Ok(RecordKind::Data),
+            2 => Ok(RecordKind::Table),
+            _ => Err(VaultError::Corrupt(format!("unknown record kind {}", id)))
+        }
+    }
+}
+
+
+////////////////////////// RECORDHEADER FUNCTIONS //////////////////////////
+impl RecordHeader {
+    /// creates a header for a record holding `len` bytes of plaintext
+    pub fn new(kind: RecordKind, len: u64) -> Result<Self, VaultError> {
+        Ok(RecordHeader{kind, nonce: new_nonce()?, sealed_len: sealed_len(len)})
+    }
+
+    /// serializes the header for the front of the record
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out: Vec<u8> = Vec::with_capacity(RECORD_HEADER_LEN);
+        out.extend_from_slice(RECORD_MAGIC);
+        out.push(self.kind.id());
+        out.extend_from_slice(&self.nonce);
+        out.extend_from_slice(&self.sealed_len.to_le_bytes());
+        out
+    }
+
+    /// parses a record header
+    pub fn parse(data: &[u8]) -> Result<Self, VaultError> {
+        if data.len() < RECORD_HEADER_LEN || !data.starts_with(RECORD_MAGIC) {
+            return Err(VaultError::Corrupt("record header not found".to_string()));
+        }
+        let mut pos = RECORD_MAGIC.len();
+        let kind = RecordKind::from_id(data[pos])?;
+        pos += 1;
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(&data[pos..pos+NONCE_LEN]);
+        pos += NONCE_LEN;
+        let mut len = [0u8; 8];
+        len.copy_from_slice(&data[pos..pos+8]);
+
+        Ok(RecordHeader{kind, nonce, sealed_len: u64::from_le_bytes(len)})
+    }
+
+    /// returns how many bytes the whole record takes up on disk
+    pub fn record_len(&self) -> u64 {
+        RECORD_HEADER_LEN as u64 + self.sealed_len
+    }
+
+    /// returns the AAD bound to every chunk of the record
+    fn aad(&self, archive: &Header) -> Vec<u8> {
+        let mut aad = archive.serialize();
+        aad.extend_from_slice(&self.serialize());
+        aad
+    }
+
+    /// returns the key the record's chunks are sealed under. Every record
+    /// gets its own, expanded from the archive key and the record's whole
+    /// random nonce with HKDF, so chunk nonces only have to be unique within
+    /// a record
+    fn key(&self, key: &[u8; 32]) -> [u8; 32] {
+        let mut info: Vec<u8> = Vec::with_capacity(RECORD_KEY_INFO.len() + NONCE_LEN + 1);
+        info.extend_from_slice(RECORD_KEY_INFO);
+        info.extend_from_slice(&self.nonce);
+        info.push(1);
+        HMAC::mac(&info, key)
+    }
+}
+
+/// writes the header of a record holding `len` bytes of plaintext, returning
+/// a writer that seals whatever is written to it as the record's contents
+pub fn begin_record<W: Write>(mut out: W, archive: &Header, key: [u8; 32], kind: RecordKind, len: u64) -> Result<ChunkWriter<W>, VaultError> {
+    let rec = RecordHeader::new(kind, len)?;
+    out.write_all(&rec.serialize())?;
+    Ok(ChunkWriter::new(out, archive.cipher, rec.key(&key), rec.nonce, rec.aad(archive)))
+}
+
+/// opens the record of the given kind at `offset`, returning a reader over
+/// its plaintext
+pub fn open_record<R: Read + Seek>(mut inner: R, offset: u64, archive: &Header, key: [u8; 32], kind: RecordKind) -> Result<ChunkReader<R>, VaultError> {
+    inner.seek(SeekFrom::Start(offset))?;
+    let mut head: Vec<u8> = Vec::with_capacity(RECORD_HEADER_LEN);
+    inner.by_ref().take(RECORD_HEADER_LEN as u64).read_to_end(&mut head)?;
+    let rec = RecordHeader::parse(&head)?;
+    if rec.kind != kind {
+        return Err(VaultError::Corrupt(format!("expected a {} record at byte {}, found a {} record", kind, offset, rec.kind)));
+    }
+    ChunkReader::new(inner, rec.sealed_len, archive.cipher, rec.key(&key), rec.nonce, rec.aad(archive))
+}
+
+/// builds the trailer that points at the latest table record
+pub fn trailer(table_offset: u64) -> [u8; TRAILER_LEN] {
+    let mut out = [0u8; TRAILER_LEN];
+    out[..8].copy_from_slice(&table_offset.to_le_bytes());
+    out[8..].copy_from_slice(TRAILER_MAGIC);
+    out
+}
+
+/// checks whether a trailer ends at `end` and points at a table record that
+/// runs right up to it, returning the table's offset if so
+fn check_trailer<R: Read + Seek>(inner: &mut R, end: u64) -> Result<Option<u64>, VaultError> {
+    if end < TRAILER_LEN as u64 {
+        return Ok(None);
+    }
+    let start = end - TRAILER_LEN as u64;
+    inner.seek(SeekFrom::Start(start))?;
+    let mut buf = [0u8; TRAILER_LEN];
+    inner.read_exact(&mut buf)?;
+    if &buf[8..] != TRAILER_MAGIC {
+        return Ok(None);
+    }
+    let mut offset = [0u8; 8];
+    offset.copy_from_slice(&buf[..8]);
+    let offset = u64::from_le_bytes(offset);
+    if offset >= start {
+        return Ok(None);
+    }
+
+    inner.seek(SeekFrom::Start(offset))?;
+    let mut head: Vec<u8> = Vec::with_capacity(RECORD_HEADER_LEN);
+    inner.by_ref().take(RECORD_HEADER_LEN as u64).read_to_end(&mut head)?;
+    match RecordHeader::parse(&head) {
+        Ok(a) if a.kind == RecordKind::Table && offset.checked_add(a.record_len()) == Some(start) => Ok(Some(offset)),
+        _ => Ok(None)
+    }
+}
+
+/// finds the last complete trailer, returning the offset of the table record
+/// it points to and where the trailer ends. A save that was interrupted can
+/// leave part of its records after the last trailer, so if the archive
+/// doesn't end in one we search backwards for it
+pub fn find_trailer<R: Read + Seek>(inner: &mut R) -> Result<(u64, u64), VaultError> {
+    let len = inner.seek(SeekFrom::End(0))?;
+    if let Some(offset) = check_trailer(inner, len)? {
+        return Ok((offset, len));
+    }
+
+    // look through the file a block at a time from the end, overlapping each
+    // block with the next so magic split across them is still found
+    let block = 64 * 1024;
+    let mut end = len;
+    while end > 0 {
+        let start = end.saturating_sub(block);
+        let stop = std::cmp::min(end + TRAILER_MAGIC.len() as u64 - 1, len);
+        let mut buf: Vec<u8> = Vec::with_capacity((stop - start) as usize);
+        inner.seek(SeekFrom::Start(start))?;
+        inner.by_ref().take(stop - start).read_to_end(&mut buf)?;
+
+        for i in (0..buf.len().saturating_sub(TRAILER_MAGIC.len() - 1)).rev() {
+            if &buf[i..i+TRAILER_MAGIC.len()] != TRAILER_MAGIC {
+                continue;
+            }
+            let trailer_end = start + (i + TRAILER_MAGIC.len()) as u64;
+            if let Some(offset) = check_trailer(inner, trailer_end)? {
+                return Ok((offset, trailer_end));
+            }
+        }
+        end = start;
+    }
+
+    Err(VaultError::Corrupt("archive trailer is missing".to_string()))
+}
+
+/// walks the records and trailers of an archive from `start` up to `end`,
+/// decrypting every record to check that it authenticates. Nothing is taken
+/// on trust: after a record that fails to, or bytes that aren't a record at
+/// all, we search for where the next record or trailer starts
+pub fn scan<R: Read + Seek>(inner: &mut R, start: u64, end: u64, archive: &Header, key: [u8; 32]) -> Result<Vec<Span>, VaultError> {
+    let mut spans: Vec<Span> = Vec::new();
+    let mut pos = start;
+    while pos < end {
+        inner.seek(SeekFrom::Start(pos))?;
+        let mut head: Vec<u8> = Vec::with_capacity(RECORD_HEADER_LEN);
+        inner.by_ref().take(std::cmp::min(RECORD_HEADER_LEN as u64, end - pos)).read_to_end(&mut head)?;
+
+        if head.len() >= TRAILER_LEN && &head[8..TRAILER_LEN] == TRAILER_MAGIC {
+            let mut table = [0u8; 8];
+            table.copy_from_slice(&head[..8]);
+            spans.push(Span::Trailer{offset: pos, table: u64::from_le_bytes(table)});
+            pos += TRAILER_LEN as u64;
+            continue;
+        }
+        if !head.starts_with(RECORD_MAGIC) {
+            let next = next_boundary(inner, pos + 1, end)?;
+            spans.push(Span::Gap{offset: pos, len: next - pos});
+            pos = next;
+            continue;
+        }
+
+        let res = RecordHeader::parse(&head).and_then(|rec| match pos.checked_add(rec.record_len()) {
+            Some(a) if a <= end => Ok((rec, a)),
+            _ => Err(VaultError::Corrupt("record runs past the end of the archive".to_string()))
+        });
+        let res = res.and_then(|(rec, rec_end)| {
+            let mut reader = ChunkReader::new(inner.by_ref(), rec.sealed_len, archive.cipher, rec.key(&key), rec.nonce, rec.aad(archive))?;
+            let len = std::io::copy(&mut reader, &mut std::io::sink())?;
+            Ok((rec, rec_end, len))
+        });
+        match res {
+            Ok((rec, rec_end, len)) => {
+                spans.push(Span::Record{offset: pos, kind: rec.kind, len});
+                pos = rec_end;
+            },
+            Err(e) => {
+                let next = next_boundary(inner, pos + 1, end)?;
+                spans.push(Span::Damaged{offset: pos, len: next - pos, reason: e.to_string()});
+                pos = next;
+            }
+        };
+    }
+    Ok(spans)
+}
+
+/// finds the first place at or after `from` where a record or a trailer
+/// could start, or `end` if there is none before it
+fn next_boundary<R: Read + Seek>(inner: &mut R, from: u64, end: u64) -> Result<u64, VaultError> {
+    // a trailer is recognised by the magic at its end, so blocks overlap by
+    // a trailer's length to catch magic split across them
+    let block = 64 * 1024;
+    let mut start = from;
+    while start < end {
+        let stop = std::cmp::min(start + block + TRAILER_LEN as u64, end);
+        let mut buf: Vec<u8> = Vec::with_capacity((stop - start) as usize);
+        inner.seek(SeekFrom::Start(start))?;
+        inner.by_ref().take(stop - start).read_to_end(&mut buf)?;
+
+        for i in 0..std::cmp::min(block as usize, buf.len()) {
+            let rest = &buf[i..];
+            if rest.starts_with(RECORD_MAGIC) || (rest.len() >= TRAILER_LEN && &rest[8..TRAILER_LEN] == TRAILER_MAGIC) {
+                return Ok(start + i as u64);
+            }
+        }
+        start += block;
+    }
+    Ok(end)
+}
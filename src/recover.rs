@@ -0,0 +1,75 @@
+use crate::verify::escape;
+
+
+/// The directory in a recovered archive that data no table knows about is
+/// saved in, one entry per record named after its offset
+pub const LOST_AND_FOUND: &[u8] = b"lost+found";
+
+
+////////////////////////// DEFINITIONS /////////////////////////////////////
+/// What `Datafile::recover` salvaged from a damaged archive, and what it
+/// couldn't
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Recovery {
+    /// where the table the entries were rebuilt from starts, if any survived
+    pub table: Option<u64>,
+    /// newer tables that authenticated but couldn't be read, and why
+    pub unusable_tables: Vec<(u64, String)>,
+    /// stretches of the archive that aren't records that authenticate, as
+    /// (offset, length, why)
+    pub damaged: Vec<(u64, u64, String)>,
+    /// how many entries made it into the new archive whole
+    pub recovered: usize,
+    /// entries that didn't, and why
+    pub lost: Vec<(Vec<u8>, String)>,
+    /// data records no table uses, saved in `LOST_AND_FOUND`, along with
+    /// how many bytes each holds
+    pub found: Vec<(Vec<u8>, u64)>
+}
+
+
+/////////////////////////// DISPLAY IMPL ////////////////////////////////////
+/// One line per finding, then one line per count, with tab separated
+/// fields like `Report`
+impl std::fmt::Display for Recovery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (offset, len, reason) in self.damaged.iter() {
+            writeln!(f, "damaged\t{}\t{}\t{}", offset, len, escape(reason.as_bytes()))?;
+        }
+        for (offset, reason) in self.unusable_tables.iter() {
+            writeln!(f, "unusable-table\t{}\t{}", offset, escape(reason.as_bytes()))?;
+        }
+        for (name, reason) in self.lost.iter() {
+            writeln!(f, "lost\t{}\t{}", escape(name), escape(reason.as_bytes()))?;
+        }
+        for (name, len) in self.found.iter() {
+            writeln!(f, "found\t{}\t{}", escape(name), len)?;
+        }
+        match self.table {
+            Some(a) => writeln!(f, "table\t{}", a)?,
+            None => writeln!(f, "table\t-")?
+        };
+        writeln!(f, "recovered\t{}", self.recovered)?;
+        writeln!(f, "lost-entries\t{}", self.lost.len())?;
+        writeln!(f, "found-records\t{}", self.found.len())?;
+        write!(f, "status\t{}", match self.is_complete() {
+            true => "complete",
+            false => "partial"
+        })
+    }
+}
+
+
+////////////////////////// RECOVERY FUNCTIONS //////////////////////////
+impl Recovery {
+    /// returns true if, as far as we can tell, every entry of the archive's
+    /// latest table was recovered. Damage before the table we used only hit
+    /// records nothing needs, but damage after it may have been a newer table
+    pub fn is_complete(&self) -> bool {
+        let table = match self.table {
+            Some(a) => a,
+            None => return false
+        };
+        self.unusable_tables.is_empty() && self.lost.is_empty() && self.damaged.iter().all(|d| d.0 < table)
+    }
+}
@@ -0,0 +1,216 @@
+use std::convert::TryFrom;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::cipher::{CipherKind, NONCE_LEN, TAG_LEN};
+use crate::error::VaultError;
+
+
+/// How much plaintext goes into each sealed chunk
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// How big a full chunk is on disk, tag included
+pub const SEALED_CHUNK_SIZE: usize = CHUNK_SIZE + TAG_LEN;
+
+/// How many bytes of the header's nonce lead every chunk nonce. The rest
+/// holds the chunk's index and whether it is the last one
+const NONCE_PREFIX_LEN: usize = NONCE_LEN - 4 - 1;
+
+
+////////////////////////// DEFINITIONS /////////////////////////////////////
+/// Seals everything written to it as a stream of fixed-size chunks, so only
+/// one chunk of plaintext is ever held in memory
+pub struct ChunkWriter<W: Write> {
+    inner: W,
+    cipher: CipherKind,
+    key: [u8; 32],
+    nonce: [u8; NONCE_LEN],
+    aad: Vec<u8>,
+    index: u32,
+    buf: Vec<u8>
+}
+
+/// Opens a stream of chunks written by `ChunkWriter`, one chunk at a time.
+/// Every chunk can be opened on its own, so when the underlying reader can
+/// seek, so can this
+pub struct ChunkReader<R: Read> {
+    inner: R,
+    cipher: CipherKind,
+    key: [u8; 32],
+    nonce: [u8; NONCE_LEN],
+    aad: Vec<u8>,
+    /// how many chunks the stream holds
+    chunks: u64,
+    /// how many sealed bytes the stream takes up
+    sealed_len: u64,
+    /// how much plaintext the stream holds
+    len: u64,
+    /// the index of the chunk the underlying reader is positioned at
+    next: u64,
+    /// the index of the chunk held in `buf`
+    loaded: Option<u64>,
+    buf: Vec<u8>,
+    pos: u64
+}
+
+
+/// builds the nonce for a chunk. Binding the index stops chunks from being
+/// reordered, and the last-chunk flag stops the stream from being truncated
+fn chunk_nonce(base: &[u8; NONCE_LEN], index: u32, last: bool) -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(&base[..NONCE_PREFIX_LEN]);
+    nonce[NONCE_PREFIX_LEN..NONCE_LEN-1].copy_from_slice(&index.to_be_bytes());
+    nonce[NONCE_LEN-1] = last as u8;
+    nonce
+}
+
+/// works out how many chunks a sealed stream of `sealed_len` bytes holds,
+/// and how much plaintext is in them
+fn stream_layout(sealed_len: u64) -> Result<(u64, u64), VaultError> {
+    let chunk = SEALED_CHUNK_SIZE as u64;
+    let chunks = sealed_len.div_ceil(chunk);
+    // every stream ends with a chunk that at least holds its tag
+    let last = sealed_len - chunks.saturating_sub(1) * chunk;
+    if chunks == 0 || last < TAG_LEN as u64 {
+        return Err(VaultError::Corrupt("archive is truncated".to_string()));
+    }
+    Ok((chunks, sealed_len - chunks * TAG_LEN as u64))
+}
+
+/// works out how many bytes `len` bytes of plaintext take up once sealed
+pub fn sealed_len(len: u64) -> u64 {
+    let chunks = std::cmp::max(1, len.div_ceil(CHUNK_SIZE as u64));
+    len + chunks * TAG_LEN as u64
+}
+
+
+////////////////////////// CHUNKWRITER FUNCTIONS //////////////////////////
+impl<W: Write> ChunkWriter<W> {
+    /// starts a stream sealed under `key`, with chunk nonces derived from
+    /// `nonce` and `aad` bound to every chunk
+    pub fn new(inner: W, cipher: CipherKind, key: [u8; 32], nonce: [u8; NONCE_LEN], aad: Vec<u8>) -> Self {
+        ChunkWriter{inner, cipher, key, nonce, aad, index: 0, buf: Vec::with_capacity(CHUNK_SIZE)}
+    }
+
+    /// seals and writes out whatever is buffered
+    fn write_chunk(&mut self, last: bool) -> Result<(), VaultError> {
+        let nonce = chunk_nonce(&self.nonce, self.index, last);
+        let sealed = self.cipher.seal(&self.key, &nonce, &self.aad, &self.buf)?;
+        self.inner.write_all(&sealed)?;
+        self.buf.clear();
+        self.index = match self.index.checked_add(1) {
+            Some(a) => a,
+            None => return Err(VaultError::Unsupported("archive has too many chunks".to_string()))
+        };
+        Ok(())
+    }
+
+    /// seals the last chunk and hands back the underlying writer
+    pub fn finish(mut self) -> Result<W, VaultError> {
+        self.write_chunk(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkWriter<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        // a full chunk is only sealed once we know more data follows it,
+        // since the last chunk has to be sealed differently
+        if self.buf.len() == CHUNK_SIZE && !data.is_empty() {
+            self.write_chunk(false)?;
+        }
+        let len = std::cmp::min(data.len(), CHUNK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+
+////////////////////////// CHUNKREADER FUNCTIONS //////////////////////////
+impl<R: Read> ChunkReader<R> {
+    /// starts reading a stream of `sealed_len` bytes written by `ChunkWriter`
+    /// with the same settings
+    pub fn new(inner: R, sealed_len: u64, cipher: CipherKind, key: [u8; 32], nonce: [u8; NONCE_LEN], aad: Vec<u8>) -> Result<Self, VaultError> {
+        let (chunks, len) = stream_layout(sealed_len)?;
+        Ok(ChunkReader{
+            inner, cipher, key, nonce, aad, chunks, sealed_len, len,
+            next: 0,
+            loaded: None,
+            buf: Vec::new(),
+            pos: 0
+        })
+    }
+
+    /// returns how much plaintext the stream holds
+    pub fn plaintext_len(&self) -> u64 {
+        self.len
+    }
+
+    /// reads and opens the chunk the underlying reader is positioned at
+    fn read_chunk(&mut self) -> Result<(), VaultError> {
+        let index = self.next;
+        let last = index + 1 == self.chunks;
+        // the stream may be followed by other data, so never read past its end
+        let want = std::cmp::min(SEALED_CHUNK_SIZE as u64, self.sealed_len - index * SEALED_CHUNK_SIZE as u64);
+        let mut sealed: Vec<u8> = Vec::with_capacity(want as usize);
+        self.loaded = None;
+        self.inner.by_ref().take(want).read_to_end(&mut sealed)?;
+        self.next = index + 1;
+
+        let counter = match u32::try_from(index) {
+            Ok(a) => a,
+            Err(_) => return Err(VaultError::Corrupt("archive has too many chunks".to_string()))
+        };
+        let nonce = chunk_nonce(&self.nonce, counter, last);
+        self.buf = self.cipher.open(&self.key, &nonce, &self.aad, &sealed)?;
+        self.loaded = Some(index);
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for ChunkReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.len || out.is_empty() {
+            return Ok(0);
+        }
+        let index = self.pos / CHUNK_SIZE as u64;
+        if self.loaded != Some(index) {
+            self.read_chunk()?;
+        }
+
+        let start = (self.pos % CHUNK_SIZE as u64) as usize;
+        let len = std::cmp::min(out.len(), self.buf.len().saturating_sub(start));
+        out[..len].copy_from_slice(&self.buf[start..start+len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek> Seek for ChunkReader<R> {
+    fn seek(&mut self, to: SeekFrom) -> std::io::Result<u64> {
+        let pos = match to {
+            SeekFrom::Start(a) => Some(a),
+            SeekFrom::End(a) => self.len.checked_add_signed(a),
+            SeekFrom::Current(a) => self.pos.checked_add_signed(a)
+        };
+        let pos = match pos {
+            Some(a) => a,
+            None => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek to a negative position"))
+        };
+
+        // move the underlying reader over to the chunk we will need next,
+        // unless it is already decrypted
+        let index = pos / CHUNK_SIZE as u64;
+        if index < self.chunks && self.loaded != Some(index) && self.next != index {
+            let delta = (index as i64 - self.next as i64) * SEALED_CHUNK_SIZE as i64;
+            self.inner.seek(SeekFrom::Current(delta))?;
+            self.next = index;
+        }
+        self.pos = pos;
+        Ok(pos)
+    }
+}
@@ -0,0 +1,574 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::Read;
+use std::ops::Range;
+
+use crate::meta::Metadata;
+use crate::path;
+use crate::record::RECORD_HEADER_LEN;
+use crate::stream::sealed_len;
+
+
+/// Length of the magic bytes that lead a decrypted payload
+pub const MAGIC_LEN: usize = 16;
+
+/// Where the file table starts in the payload (magic bytes and file count)
+pub const TABLE_START: usize = MAGIC_LEN + 8;
+
+/// The smallest an entry can be: an empty name's NUL plus size and offset
+const MIN_ENTRY_LEN: usize = 1 + 8 + 8;
+
+/// The smallest an entry in a record table can be, which adds the length of
+/// its attributes
+const MIN_RECORD_ENTRY_LEN: usize = MIN_ENTRY_LEN + 4;
+
+/// Attribute tag for an entry whose data is compressed. The value is the
+/// codec id followed by the little-endian size of the original data, and the
+/// entry's size is then how much compressed data its record holds
+pub const ATTR_COMPRESSION: u8 = 1;
+
+/// Attribute tag for an entry whose data is split into chunks, each stored
+/// in its own data record and possibly shared with other entries. The value
+/// is a list of chunk references, and the entry's size is its original size
+pub const ATTR_CHUNKS: u8 = 2;
+
+/// Length of a serialized chunk reference
+pub const CHUNK_REF_LEN: usize = 8 + 4 + 4 + 1 + 32;
+
+/// Attribute tag for an entry that isn't a regular file. The value is one
+/// of the `KIND_` bytes below
+pub const ATTR_KIND: u8 = 3;
+
+/// Kind of an entry that is a directory. Directories have no data, so their
+/// size and offset are 0, and neither do any of the kinds below
+pub const KIND_DIRECTORY: u8 = 1;
+
+/// Kind of an entry that is a symbolic link, whose target is in its
+/// `ATTR_TARGET` attribute
+pub const KIND_SYMLINK: u8 = 2;
+
+/// Kind of an entry that is another name for a regular file in the same
+/// table, named by its `ATTR_TARGET` attribute
+pub const KIND_HARDLINK: u8 = 3;
+
+/// Kind of an entry that is a named pipe
+pub const KIND_FIFO: u8 = 4;
+
+/// Kind of an entry that is a character device, whose device number is in
+/// its `ATTR_DEVICE` attribute
+pub const KIND_CHAR_DEVICE: u8 = 5;
+
+/// Kind of an entry that is a block device, whose device number is in its
+/// `ATTR_DEVICE` attribute
+pub const KIND_BLOCK_DEVICE: u8 = 6;
+
+/// Attribute tag for the permissions, ownership and timestamps a file had
+/// on disk. The value is laid out by `Metadata::serialize`
+pub const ATTR_METADATA: u8 = 4;
+
+/// Attribute tag for the extended attributes a file had on disk, POSIX ACLs
+/// included. Only entries with an `ATTR_METADATA` attribute can have one
+pub const ATTR_XATTRS: u8 = 5;
+
+/// Attribute tag for where a link points: the raw target of a symlink, or
+/// the path of the entry a hard link shares its data with
+pub const ATTR_TARGET: u8 = 6;
+
+/// Attribute tag for the device number of a device entry, as a u64 in the
+/// encoding of the system that added it
+pub const ATTR_DEVICE: u8 = 7;
+
+/// Attribute tag for the SHA-256 of a regular file's original data, which
+/// `verify` checks the data against
+pub const ATTR_DIGEST: u8 = 8;
+
+/// Length of an `ATTR_DIGEST` value
+pub const DIGEST_LEN: usize = 32;
+
+
+////////////////////////// DEFINITIONS /////////////////////////////////////
+/// A single parsed row of the file table
+#[derive(Clone, Debug, PartialEq)]
+pub struct TableEntry {
+    pub name: Vec<u8>,
+    pub size: usize,
+    pub offset: usize,
+    /// Extra per-entry fields as (tag, value) pairs. Only record tables
+    /// (format v3 on) have room for these
+    pub attrs: Vec<(u8, Vec<u8>)>
+}
+
+/// Where one chunk of an entry's data lives
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChunkRef {
+    /// offset of the chunk's data record in the archive
+    pub offset: usize,
+    /// how many bytes the record holds
+    pub stored: usize,
+    /// how many bytes of the entry the chunk holds once decompressed
+    pub size: usize,
+    /// id of the codec the chunk is compressed with
+    pub codec: u8,
+    /// keyed hash of the chunk's original data
+    pub hash: [u8; 32]
+}
+
+/// The ways a file table can be malformed
+#[derive(Clone, Debug, PartialEq)]
+pub enum TableErrorKind {
+    /// The payload ends before the field we are reading
+    Truncated,
+    /// A name runs to the end of the payload without a NUL
+    UnterminatedName,
+    /// A name is zero bytes long
+    EmptyName,
+    /// A value does not fit in this machine's usize
+    TooLarge(u64),
+    /// Two entries share a name with the given earlier entry
+    Duplicate(usize),
+    /// The entry's data lies outside the data region of the payload
+    OutOfBounds,
+    /// The entry's data overlaps the data of the given entry
+    Overlap(usize),
+    /// The entry's attributes run past their stated length
+    BadAttributes,
+    /// The name is absolute, or has empty, `.` or `..` components
+    BadPath,
+    /// The name lies below the given entry, which isn't a directory
+    ParentNotDir(usize),
+    /// The entry is a hard link that doesn't point at a regular file
+    BadLink
+}
+
+/// A malformed file table, along with where we found the problem
+#[derive(Clone, Debug, PartialEq)]
+pub struct TableError {
+    /// The index of the offending entry, if the problem is in an entry
+    pub entry: Option<usize>,
+    /// The byte position in the payload the problem was found at
+    pub pos: usize,
+    pub kind: TableErrorKind
+}
+
+
+/////////////////////////// DISPLAY IMPL ////////////////////////////////////
+impl std::fmt::Display for TableErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TableErrorKind::Truncated => write!(f, "table is truncated"),
+            TableErrorKind::UnterminatedName => write!(f, "name is not terminated"),
+            TableErrorKind::EmptyName => write!(f, "name is empty"),
+            TableErrorKind::TooLarge(v) => write!(f, "value {} is too large for this machine", v),
+            TableErrorKind::Duplicate(i) => write!(f, "name duplicates entry {}", i),
+            TableErrorKind::OutOfBounds => write!(f, "data lies outside the payload"),
+            TableErrorKind::Overlap(i) => write!(f, "data overlaps entry {}", i),
+            TableErrorKind::BadAttributes => write!(f, "attributes are malformed"),
+            TableErrorKind::BadPath => write!(f, "name is not a valid relative path"),
+            TableErrorKind::ParentNotDir(i) => write!(f, "name lies below entry {}, which is not a directory", i),
+            TableErrorKind::BadLink => write!(f, "hard link does not point at a file in the table")
+        }
+    }
+}
+
+impl std::fmt::Display for TableError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.entry {
+            Some(i) => write!(f, "file table entry {} at byte {}: {}", i, self.pos, self.kind),
+            None => write!(f, "file table at byte {}: {}", self.pos, self.kind)
+        }
+    }
+}
+
+
+////////////////////////// TABLEENTRY FUNCTIONS //////////////////////////
+impl TableEntry {
+    /// serializes the entry for a record table
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out: Vec<u8> = Vec::with_capacity(self.name.len() + MIN_RECORD_ENTRY_LEN);
+        out.extend_from_slice(&self.name);
+        out.push(0u8); // add in the null-term for reading it later
+        out.extend_from_slice(&encode_u64(self.size));
+        out.extend_from_slice(&encode_u64(self.offset));
+
+        // each attribute is its tag, the length of its value and the value
+        let mut attrs: Vec<u8> = Vec::new();
+        for (tag, value) in self.attrs.iter() {
+            attrs.push(*tag);
+            attrs.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            attrs.extend_from_slice(value);
+        }
+        out.extend_from_slice(&(attrs.len() as u32).to_le_bytes());
+        out.extend_from_slice(&attrs);
+        out
+    }
+
+    /// returns the chunks the entry's data is split into, or None if it is
+    /// stored in a single record
+    pub fn chunk_refs(&self, entry: Option<usize>) -> Result<Option<Vec<ChunkRef>>, TableError> {
+        let value = match self.attrs.iter().find(|(tag, _)| *tag == ATTR_CHUNKS) {
+            Some((_, a)) => a,
+            None => return Ok(None)
+        };
+        if value.len() % CHUNK_REF_LEN != 0 {
+            return Err(TableError{entry, pos: self.offset, kind: TableErrorKind::BadAttributes});
+        }
+
+        let mut refs: Vec<ChunkRef> = Vec::with_capacity(value.len() / CHUNK_REF_LEN);
+        for data in value.chunks(CHUNK_REF_LEN) {
+            let mut hash = [0u8; 32];
+            hash.copy_from_slice(&data[17..]);
+            refs.push(ChunkRef{
+                offset: read_u64(data, 0, entry)?,
+                stored: read_u32(data, 8, entry)?,
+                size: read_u32(data, 12, entry)?,
+                codec: data[16],
+                hash
+            });
+        }
+        Ok(Some(refs))
+    }
+
+    /// returns the entry's `KIND_` byte, or None for a regular file
+    pub fn kind(&self, entry: Option<usize>) -> Result<Option<u8>, TableError> {
+        match self.attrs.iter().find(|(tag, _)| *tag == ATTR_KIND) {
+            Some((_, a)) if a.len() == 1 => Ok(Some(a[0])),
+            Some(_) => Err(TableError{entry, pos: self.offset, kind: TableErrorKind::BadAttributes}),
+            None => Ok(None)
+        }
+    }
+
+    /// returns the metadata the entry's file had on disk, if it was recorded
+    pub fn metadata(&self, entry: Option<usize>) -> Result<Option<Metadata>, TableError> {
+        let bad = TableError{entry, pos: self.offset, kind: TableErrorKind::BadAttributes};
+        let xattrs = match self.attrs.iter().find(|(tag, _)| *tag == ATTR_XATTRS) {
+            Some((_, a)) => Some(Metadata::parse_xattrs(a).ok_or_else(|| bad.clone())?),
+            None => None
+        };
+        let mut meta = match self.attrs.iter().find(|(tag, _)| *tag == ATTR_METADATA) {
+            Some((_, a)) => Metadata::parse(a).ok_or_else(|| bad.clone())?,
+            None if xattrs.is_some() => return Err(bad),
+            None => return Ok(None)
+        };
+        meta.xattrs = xattrs.unwrap_or_default();
+        Ok(Some(meta))
+    }
+
+    /// returns where the entry points if it is a link
+    pub fn target(&self, entry: Option<usize>) -> Result<Option<&[u8]>, TableError> {
+        match self.attrs.iter().find(|(tag, _)| *tag == ATTR_TARGET) {
+            Some((_, a)) if !a.is_empty() && !a.contains(&0) => Ok(Some(a)),
+            Some(_) => Err(TableError{entry, pos: self.offset, kind: TableErrorKind::BadAttributes}),
+            None => Ok(None)
+        }
+    }
+
+    /// returns the entry's device number if it is a device
+    pub fn device(&self, entry: Option<usize>) -> Result<Option<u64>, TableError> {
+        match self.attrs.iter().find(|(tag, _)| *tag == ATTR_DEVICE) {
+            Some((_, a)) if a.len() == 8 => Ok(Some(u64::from_le_bytes(<[u8; 8]>::try_from(&a[..]).unwrap_or_default()))),
+            Some(_) => Err(TableError{entry, pos: self.offset, kind: TableErrorKind::BadAttributes}),
+            None => Ok(None)
+        }
+    }
+
+    /// returns the SHA-256 of the entry's data, if it was recorded
+    pub fn digest(&self, entry: Option<usize>) -> Result<Option<[u8; DIGEST_LEN]>, TableError> {
+        match self.attrs.iter().find(|(tag, _)| *tag == ATTR_DIGEST) {
+            Some((_, a)) => match <[u8; DIGEST_LEN]>::try_from(&a[..]) {
+                Ok(b) => Ok(Some(b)),
+                Err(_) => Err(TableError{entry, pos: self.offset, kind: TableErrorKind::BadAttributes})
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// returns true if the entry is a directory
+    pub fn is_dir(&self) -> bool {
+        matches!(self.kind(None), Ok(Some(KIND_DIRECTORY)))
+    }
+
+    /// returns true if the entry is a regular file, and so has data
+    pub fn is_file(&self) -> bool {
+        matches!(self.kind(None), Ok(None))
+    }
+}
+
+////////////////////////// CHUNKREF FUNCTIONS //////////////////////////
+impl ChunkRef {
+    /// serializes the reference for an `ATTR_CHUNKS` attribute
+    pub fn serialize(&self) -> [u8; CHUNK_REF_LEN] {
+        let mut out = [0u8; CHUNK_REF_LEN];
+        out[..8].copy_from_slice(&encode_u64(self.offset));
+        out[8..12].copy_from_slice(&(self.stored as u32).to_le_bytes());
+        out[12..16].copy_from_slice(&(self.size as u32).to_le_bytes());
+        out[16] = self.codec;
+        out[17..].copy_from_slice(&self.hash);
+        out
+    }
+}
+
+/// Encodes a size, offset or count as the fixed width little-endian u64 we
+/// store on disk, whatever this machine's pointer width and byte order
+pub fn encode_u64(val: usize) -> [u8; 8] {
+    (val as u64).to_le_bytes()
+}
+
+/// Reads a little-endian u32 field at `pos`, making sure it is in bounds
+fn read_u32(data: &[u8], pos: usize, entry: Option<usize>) -> Result<usize, TableError> {
+    match data.get(pos..pos.saturating_add(4)) {
+        Some(a) if a.len() == 4 => Ok(u32::from_le_bytes([a[0], a[1], a[2], a[3]]) as usize),
+        _ => Err(TableError{entry, pos, kind: TableErrorKind::Truncated})
+    }
+}
+
+/// Splits an entry's attribute block into its (tag, value) pairs
+fn parse_attrs(data: &[u8], start: usize, entry: Option<usize>) -> Result<Vec<(u8, Vec<u8>)>, TableError> {
+    let mut attrs: Vec<(u8, Vec<u8>)> = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let len = match read_u32(data, pos + 1, entry) {
+            Ok(a) => a,
+            Err(_) => return Err(TableError{entry, pos: start + pos, kind: TableErrorKind::BadAttributes})
+        };
+        let value = match data.get(pos+5..(pos+5).saturating_add(len)) {
+            Some(a) if a.len() == len => a.to_vec(),
+            _ => return Err(TableError{entry, pos: start + pos, kind: TableErrorKind::BadAttributes})
+        };
+        attrs.push((data[pos], value));
+        pos += 5 + len;
+    }
+    Ok(attrs)
+}
+
+/// Reads a little-endian u64 field at `pos`, making sure it is in bounds and
+/// fits in this machine's usize
+fn read_u64(data: &[u8], pos: usize, entry: Option<usize>) -> Result<usize, TableError> {
+    let bytes = match data.get(pos..pos.saturating_add(8)) {
+        Some(a) if a.len() == 8 => a,
+        _ => return Err(TableError{entry, pos, kind: TableErrorKind::Truncated})
+    };
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(bytes);
+    let val = u64::from_le_bytes(buf);
+    match usize::try_from(val) {
+        Ok(a) => Ok(a),
+        Err(_) => Err(TableError{entry, pos, kind: TableErrorKind::TooLarge(val)})
+    }
+}
+
+/// Reads the magic bytes, file count and file table from the front of a
+/// payload stream, stopping where the file data begins. It only walks the
+/// table's layout, so a malformed table comes back short for `parse_table`
+/// to report on
+pub fn read_table<R: Read>(reader: &mut R, payload_len: usize) -> std::io::Result<Vec<u8>> {
+    let mut raw: Vec<u8> = Vec::new();
+    reader.by_ref().take(TABLE_START as u64).read_to_end(&mut raw)?;
+    let num_files = match read_u64(&raw, MAGIC_LEN, None) {
+        Ok(a) => a,
+        Err(_) => return Ok(raw)
+    };
+    if num_files > payload_len.saturating_sub(TABLE_START) / MIN_ENTRY_LEN {
+        return Ok(raw);
+    }
+
+    let mut byte = [0u8; 1];
+    for _ in 0..num_files {
+        // names run until their NUL, then come the size and offset
+        loop {
+            if reader.read(&mut byte)? == 0 {
+                return Ok(raw);
+            }
+            raw.push(byte[0]);
+            if byte[0] == 0 {
+                break;
+            }
+        }
+        reader.by_ref().take(16).read_to_end(&mut raw)?;
+    }
+
+    Ok(raw)
+}
+
+/// Parses the file table at the front of a decrypted v0 to v2 payload that
+/// is `payload_len` bytes long. `data` only has to hold the table itself.
+/// Every read is checked against its length, and every entry's data has to
+/// lie in the data region after the table without overlapping any other entry
+pub fn parse_table(data: &[u8], payload_len: usize) -> Result<Vec<TableEntry>, TableError> {
+    let (entries, starts, end) = parse_entries(data, false)?;
+    check_paths(&entries, &starts)?;
+    check_regions(&entries, end..payload_len, false)?;
+    Ok(entries)
+}
+
+/// Parses the contents of a table record. Every read is checked against its
+/// length, and every entry's data has to lie in `region` of the archive
+/// without overlapping any other entry
+pub fn parse_record_table(data: &[u8], region: Range<usize>) -> Result<Vec<TableEntry>, TableError> {
+    let (entries, starts, _) = parse_entries(data, true)?;
+    check_paths(&entries, &starts)?;
+    check_regions(&entries, region, true)?;
+    Ok(entries)
+}
+
+/// Parses the entries of a table, returning them along with where each one
+/// starts and where the table ends. Only record tables give their entries
+/// attributes
+fn parse_entries(data: &[u8], with_attrs: bool) -> Result<(Vec<TableEntry>, Vec<usize>, usize), TableError> {
+    let num_files = read_u64(data, MAGIC_LEN, None)?;
+
+    // don't trust the count to size anything before we know the table fits
+    let min_len = if with_attrs { MIN_RECORD_ENTRY_LEN } else { MIN_ENTRY_LEN };
+    if num_files > (data.len() - TABLE_START) / min_len {
+        return Err(TableError{entry: None, pos: MAGIC_LEN, kind: TableErrorKind::Truncated});
+    }
+
+    let mut entries: Vec<TableEntry> = Vec::with_capacity(num_files);
+    let mut starts: Vec<usize> = Vec::with_capacity(num_files);
+    // names seen so far and the entry that had them, so duplicates are
+    // found without comparing every pair of entries
+    let mut seen: HashMap<&[u8], usize> = HashMap::with_capacity(num_files);
+    let mut pos = TABLE_START;
+    for idx in 0..num_files {
+        let entry = Some(idx);
+        let start = pos;
+
+        // read until we find the nullterm of the file's name
+        let name_len = match data[pos..].iter().position(|&b| b == 0) {
+            Some(a) => a,
+            None => return Err(TableError{entry, pos, kind: TableErrorKind::UnterminatedName})
+        };
+        if name_len == 0 {
+            return Err(TableError{entry, pos, kind: TableErrorKind::EmptyName});
+        }
+        let name = &data[pos..pos+name_len];
+        if !path::is_valid(name) {
+            return Err(TableError{entry, pos, kind: TableErrorKind::BadPath});
+        }
+        if let Some(&other) = seen.get(name) {
+            return Err(TableError{entry, pos, kind: TableErrorKind::Duplicate(other)});
+        }
+        seen.insert(name, idx);
+        let name = name.to_vec();
+        pos += name_len + 1;
+
+        let size = read_u64(data, pos, entry)?;
+        let offset = read_u64(data, pos + 8, entry)?;
+        pos += 16;
+
+        let mut attrs: Vec<(u8, Vec<u8>)> = Vec::new();
+        if with_attrs {
+            let attr_len = read_u32(data, pos, entry)?;
+            pos += 4;
+            let block = match data.get(pos..pos.saturating_add(attr_len)) {
+                Some(a) if a.len() == attr_len => a,
+                _ => return Err(TableError{entry, pos, kind: TableErrorKind::Truncated})
+            };
+            attrs = parse_attrs(block, pos, entry)?;
+            pos += attr_len;
+        }
+        entries.push(TableEntry{name, size, offset, attrs});
+        starts.push(start);
+    }
+
+    Ok((entries, starts, pos))
+}
+
+/// Makes sure every entry that has entries below it is a directory, that
+/// only regular files carry data, that links and devices say where they
+/// point and that metadata is well formed
+fn check_paths(entries: &[TableEntry], starts: &[usize]) -> Result<(), TableError> {
+    let mut kinds: HashMap<&[u8], (usize, Option<u8>)> = HashMap::with_capacity(entries.len());
+    for (idx, e) in entries.iter().enumerate() {
+        let bad = TableError{entry: Some(idx), pos: e.offset, kind: TableErrorKind::BadAttributes};
+        e.metadata(Some(idx))?;
+        let kind = e.kind(Some(idx))?;
+        e.digest(Some(idx))?;
+        let has_data = e.attrs.iter().any(|(tag, _)| *tag == ATTR_CHUNKS || *tag == ATTR_COMPRESSION || *tag == ATTR_DIGEST);
+        if kind.is_some() && (e.size != 0 || e.offset != 0 || has_data) {
+            return Err(bad);
+        }
+        let is_link = kind == Some(KIND_SYMLINK) || kind == Some(KIND_HARDLINK);
+        let is_device = kind == Some(KIND_CHAR_DEVICE) || kind == Some(KIND_BLOCK_DEVICE);
+        if e.target(Some(idx))?.is_some() != is_link || e.device(Some(idx))?.is_some() != is_device {
+            return Err(bad);
+        }
+        kinds.insert(&e.name, (idx, kind));
+    }
+
+    for (idx, e) in entries.iter().enumerate() {
+        for dir in path::ancestors(&e.name) {
+            if let Some(&(other, kind)) = kinds.get(dir) {
+                if kind != Some(KIND_DIRECTORY) {
+                    return Err(TableError{entry: Some(idx), pos: starts[idx], kind: TableErrorKind::ParentNotDir(other)});
+                }
+            }
+        }
+        // hard links share the data of a regular file, which can't be
+        // another link
+        if e.kind(Some(idx))? == Some(KIND_HARDLINK) {
+            let target = e.target(Some(idx))?.unwrap_or_default();
+            if !matches!(kinds.get(target), Some((_, None))) {
+                return Err(TableError{entry: Some(idx), pos: starts[idx], kind: TableErrorKind::BadLink});
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Makes sure every entry's data lies in `region` without overlapping the
+/// data of any other entry. The data of a chunked entry is its chunks, which
+/// other entries may share, so only chunks at different offsets can overlap.
+/// In a record log, the data is the whole sealed record holding it
+fn check_regions(entries: &[TableEntry], region: Range<usize>, records: bool) -> Result<(), TableError> {
+    let len = |idx: usize, offset: usize, stored: usize| match records {
+        true => record_len(stored).ok_or(TableError{entry: Some(idx), pos: offset, kind: TableErrorKind::OutOfBounds}),
+        false => Ok(stored)
+    };
+
+    // every stretch of data as (entry, offset, len, whether it is a chunk)
+    let mut spans: Vec<(usize, usize, usize, bool)> = Vec::new();
+    for (idx, e) in entries.iter().enumerate().filter(|(_, e)| e.is_file()) {
+        match e.chunk_refs(Some(idx))? {
+            Some(refs) => {
+                let total = refs.iter().try_fold(0usize, |sum, c| sum.checked_add(c.size));
+                if total != Some(e.size) {
+                    return Err(TableError{entry: Some(idx), pos: e.offset, kind: TableErrorKind::BadAttributes});
+                }
+                for c in refs.iter() {
+                    spans.push((idx, c.offset, len(idx, c.offset, c.stored)?, true));
+                }
+            },
+            None => spans.push((idx, e.offset, len(idx, e.offset, e.size)?, false))
+        };
+    }
+
+    for &(idx, offset, len, _) in spans.iter() {
+        let fits = match offset.checked_add(len) {
+            Some(end) => offset >= region.start && end <= region.end,
+            None => false
+        };
+        if !fits {
+            return Err(TableError{entry: Some(idx), pos: offset, kind: TableErrorKind::OutOfBounds});
+        }
+    }
+
+    // and that no two entries claim the same bytes, unless they share a chunk
+    spans.retain(|&(_, _, len, _)| len > 0);
+    spans.sort_by_key(|&(_, offset, len, _)| (offset, len));
+    spans.dedup_by(|next, prev| next.3 && prev.3 && next.1 == prev.1 && next.2 == prev.2);
+    for pair in spans.windows(2) {
+        let (prev, next) = (pair[0], pair[1]);
+        if prev.1 + prev.2 > next.1 {
+            return Err(TableError{entry: Some(next.0), pos: next.1, kind: TableErrorKind::Overlap(prev.0)});
+        }
+    }
+
+    Ok(())
+}
+
+/// returns how much of the archive the record holding `stored` bytes of
+/// data takes up, or None if that is more than a usize can count
+fn record_len(stored: usize) -> Option<usize> {
+    let stored = u64::try_from(stored).ok().filter(|&a| a <= u64::MAX / 2)?;
+    usize::try_from(sealed_len(stored)).ok()?.checked_add(RECORD_HEADER_LEN)
+}
@@ -0,0 +1,523 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use walkdir::WalkDir;
+
+use crate::datafile::{Datafile, EncFile, EntryKind};
+use crate::error::VaultError;
+use crate::extract::{self, ExtractOptions};
+use crate::meta::Metadata;
+use crate::path;
+use crate::verify::{self, Report};
+
+
+////////////////////////// DEFINITIONS /////////////////////////////////////
+/// Glob patterns picking which files of a tree get added or extracted
+#[derive(Default)]
+pub struct Filter {
+    include: Patterns,
+    exclude: Patterns
+}
+
+/// A list of glob patterns. Patterns with a `/` in them match a path from
+/// the top of the tree, and the rest match the last component, wherever it is
+#[derive(Default)]
+struct Patterns {
+    by_path: GlobSet,
+    by_name: GlobSet,
+    len: usize
+}
+
+/// What adding or extracting a tree did
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Summary {
+    /// how many files, links, pipes and devices were added or extracted
+    pub files: usize,
+    /// how many directories were
+    pub dirs: usize,
+    /// what was left out, and why
+    pub skipped: Vec<(PathBuf, String)>
+}
+
+/// What something on disk that isn't a file or directory gets stored as
+enum Special {
+    Symlink,
+    Fifo,
+    Device{block: bool, rdev: u64},
+    /// a socket, or anything else that can't be stored
+    Other
+}
+
+/// Why an entry with a name that would land outside the directory being
+/// extracted to was skipped
+const UNSAFE_NAME: &str = "its name is absolute or has . or .. in it";
+
+/// Why an entry was skipped when the policy is not to replace what is there
+const EXISTS: &str = "it already exists";
+
+/// Called with how many files are done, how many there are in all and the
+/// name of the one that was just done
+pub type Progress<'a> = dyn FnMut(usize, usize, &[u8]) + 'a;
+
+
+////////////////////////// PATTERNS FUNCTIONS //////////////////////////
+impl Patterns {
+    /// compiles a list of glob patterns
+    fn new<S: AsRef<str>>(patterns: &[S]) -> Result<Self, VaultError> {
+        let mut by_path = GlobSetBuilder::new();
+        let mut by_name = GlobSetBuilder::new();
+        for p in patterns.iter().map(|a| a.as_ref()) {
+            // `*` stops at a `/`, so only `**` can match across directories
+            let glob = match GlobBuilder::new(p.trim_start_matches('/')).literal_separator(true).build() {
+                Ok(a) => a,
+                Err(e) => return Err(VaultError::InvalidPattern(e.to_string()))
+            };
+            if p.contains('/') {
+                by_path.add(glob);
+            } else {
+                by_name.add(glob);
+            }
+        }
+        match (by_path.build(), by_name.build()) {
+            (Ok(by_path), Ok(by_name)) => Ok(Patterns{by_path, by_name, len: patterns.len()}),
+            (Err(e), _) | (_, Err(e)) => Err(VaultError::InvalidPattern(e.to_string()))
+        }
+    }
+
+    /// returns true if a pattern matches `rel`, a path from the top of the tree
+    fn is_match(&self, rel: &[u8]) -> bool {
+        let rel = String::from_utf8_lossy(rel);
+        let name = rel.rsplit('/').next().unwrap_or_default();
+        self.by_path.is_match(&*rel) || self.by_name.is_match(name)
+    }
+}
+
+
+////////////////////////// FILTER FUNCTIONS //////////////////////////
+impl Filter {
+    /// builds a filter that only keeps files matching one of `include` (or
+    /// every file, if there are none) and leaves out anything matching one of
+    /// `exclude`. Leaving out a directory leaves out everything in it
+    pub fn new<S: AsRef<str>>(include: &[S], exclude: &[S]) -> Result<Self, VaultError> {
+        Ok(Filter{include: Patterns::new(include)?, exclude: Patterns::new(exclude)?})
+    }
+
+    /// returns true if the directory at `rel` is left out, along with
+    /// everything in it
+    pub fn excludes_dir(&self, rel: &[u8]) -> bool {
+        self.exclude.is_match(rel) || path::ancestors(rel).any(|a| self.exclude.is_match(a))
+    }
+
+    /// returns true if the file at `rel` is kept
+    pub fn includes_file(&self, rel: &[u8]) -> bool {
+        (self.include.len == 0 || self.include.is_match(rel)) && !self.excludes_dir(rel)
+    }
+
+    /// returns true if there are include patterns. Empty directories are only
+    /// kept without them, since nothing in them was asked for
+    pub fn has_includes(&self) -> bool {
+        self.include.len != 0
+    }
+}
+
+
+/// returns `path` below `root` as an entry path, split by `/`
+fn relative(path: &Path, root: &Path) -> Vec<u8> {
+    let rel = path.strip_prefix(root).unwrap_or(path);
+    let parts: Vec<Vec<u8>> = rel.components().map(|c| os_bytes(Path::new(c.as_os_str()))).collect();
+    parts.join(&b'/')
+}
+
+/// adds the directory `src` and everything in it that `filter` keeps to the
+/// archive, under the directory `dest`. The tree keeps its own name, unless
+/// it has none (like `.`), in which case what is in it goes straight into
+/// `dest`. Files are added with `Datafile::add_file`, so they are only read
+/// when the archive is saved. Directories get entries of their own, which
+/// carry their metadata, and files with several names in the tree are
+/// stored once and hard linked. Symlinks are stored as links unless
+/// `follow` is set, in which case what they point at is added instead
+pub fn add_tree(df: &mut Datafile, src: &Path, dest: &[u8], filter: &Filter, follow: bool, progress: &mut Progress) -> Result<Summary, VaultError> {
+    let base = match src.file_name() {
+        Some(a) => path::join(dest, &os_bytes(Path::new(a))),
+        None => dest.to_vec()
+    };
+    if !std::fs::metadata(src)?.is_dir() {
+        return Err(VaultError::NotADirectory(src.to_string_lossy().to_string()));
+    }
+
+    // find everything first, so we know how much there is to do
+    let mut summary = Summary::default();
+    let mut nodes: Vec<(Vec<u8>, PathBuf, std::fs::Metadata)> = Vec::new();
+    let mut dirs: Vec<(Vec<u8>, PathBuf)> = Vec::new();
+    let walker = WalkDir::new(src).follow_links(follow).sort_by_file_name().into_iter()
+        .filter_entry(|e| e.depth() == 0 || !e.file_type().is_dir() || !filter.excludes_dir(&relative(e.path(), src)));
+    for entry in walker {
+        let entry = entry.map_err(std::io::Error::from)?;
+        let rel = relative(entry.path(), src);
+        let name = match rel.is_empty() {
+            true => base.clone(),
+            false => path::join(&base, &rel)
+        };
+        if entry.file_type().is_dir() {
+            dirs.push((name, entry.path().to_path_buf()));
+        } else if filter.includes_file(&rel) {
+            let meta = entry.metadata().map_err(std::io::Error::from)?;
+            if !meta.is_file() && matches!(special(&meta), Special::Other) {
+                summary.skipped.push((entry.path().to_path_buf(), "sockets can't be stored".to_string()));
+                continue;
+            }
+            nodes.push((name, entry.path().to_path_buf(), meta));
+        }
+    }
+
+    // with include patterns, only directories holding something that was
+    // kept are wanted. Parents come before their children, so they are made
+    // first
+    let used: HashSet<&[u8]> = nodes.iter().flat_map(|f| path::ancestors(&f.0)).collect();
+    for (name, disk) in dirs.iter() {
+        if name.is_empty() || (filter.has_includes() && !used.contains(&name[..])) || df.is_dir(name) {
+            continue;
+        }
+        let meta = Metadata::read(disk, df.capture())?;
+        df.mkdir(name.clone())?;
+        df.set_metadata(name, Some(meta))?;
+        summary.dirs += 1;
+    }
+
+    let total = nodes.len();
+    let mut inodes: HashMap<(u64, u64), Vec<u8>> = HashMap::new();
+    for (i, (name, disk, meta)) in nodes.into_iter().enumerate() {
+        add_node(df, &disk, name.clone(), &meta, &mut inodes)?;
+        summary.files += 1;
+        progress(i + 1, total, &name);
+    }
+    Ok(summary)
+}
+
+/// adds whatever is at `src` that isn't a directory as the entry `name`.
+/// Symlinks are stored as links unless `follow` is set
+pub fn add_one(df: &mut Datafile, src: &Path, name: Vec<u8>, follow: bool) -> Result<(), VaultError> {
+    let meta = match follow {
+        true => std::fs::metadata(src)?,
+        false => std::fs::symlink_metadata(src)?
+    };
+    if meta.is_dir() {
+        return Err(VaultError::IsDirectory(src.to_string_lossy().to_string()));
+    }
+    add_node(df, src, name, &meta, &mut HashMap::new())
+}
+
+/// adds `src`, which `meta` describes, as the entry `name`. Regular files
+/// that share an inode with one in `inodes` become hard links to it
+fn add_node(df: &mut Datafile, src: &Path, name: Vec<u8>, meta: &std::fs::Metadata, inodes: &mut HashMap<(u64, u64), Vec<u8>>) -> Result<(), VaultError> {
+    if meta.is_file() {
+        let id = match inode(meta) {
+            Some(a) => a,
+            None => return df.add_file(name, src)
+        };
+        if let Some(first) = inodes.get(&id) {
+            return df.add_hardlink(name, first.clone());
+        }
+        inodes.insert(id, name.clone());
+        return df.add_file(name, src);
+    }
+
+    match special(meta) {
+        Special::Symlink => df.add_symlink(name.clone(), os_bytes(&std::fs::read_link(src)?))?,
+        Special::Fifo => df.add_fifo(name.clone())?,
+        Special::Device{block, rdev} => df.add_device(name.clone(), block, rdev)?,
+        Special::Other => return Err(VaultError::NotAFile(src.to_string_lossy().to_string()))
+    };
+    df.set_metadata(&name, Some(Metadata::read_link(src, df.capture())?))
+}
+
+/// writes the entry at `prefix` and everything below it that `filter` keeps
+/// to the directory `dest`, recreating the directories they are in. Entries
+/// keep their whole path, so `a/b.txt` lands at `dest/a/b.txt`. The empty
+/// prefix extracts the whole archive. Links, pipes and devices are recreated
+/// as what they were, and the parts of everything's metadata `opts` asks for
+/// are put back. Nothing gets written outside `dest` or through a symlink,
+/// and what is already there is only replaced if `opts.overwrite` says so
+pub fn extract_tree(df: &Datafile, prefix: &[u8], dest: &Path, filter: &Filter, opts: &ExtractOptions, progress: &mut Progress) -> Result<Summary, VaultError> {
+    if !df.is_dir(prefix) && df.find(prefix).is_none() {
+        return Err(VaultError::NotFound(String::from_utf8_lossy(prefix).to_string()));
+    }
+    std::fs::create_dir_all(dest)?;
+
+    let mut summary = Summary::default();
+    let mut nodes: Vec<&EncFile> = Vec::new();
+    let mut dirs: Vec<(PathBuf, &Metadata)> = Vec::new();
+    for f in df.files().iter() {
+        let name = f.get_fname();
+        let rel = if name == prefix {
+            path::file_name(&name).to_vec()
+        } else if path::is_within(&name, prefix) {
+            match prefix.is_empty() {
+                true => name.clone(),
+                false => name[prefix.len()+1..].to_vec()
+            }
+        } else {
+            continue;
+        };
+
+        if f.is_dir() {
+            if !filter.has_includes() && !filter.excludes_dir(&rel) {
+                let out = match extract::disk_path(dest, &name) {
+                    Ok(a) => a,
+                    Err(_) => {
+                        summary.skipped.push((PathBuf::from(String::from_utf8_lossy(&name).to_string()), UNSAFE_NAME.to_string()));
+                        continue;
+                    }
+                };
+                extract::create_parents(dest, &out)?;
+                extract::create_dir(&out)?;
+                if let Some(meta) = f.get_metadata() {
+                    dirs.push((out, meta));
+                }
+                summary.dirs += 1;
+            }
+        } else if filter.includes_file(&rel) {
+            nodes.push(f);
+        }
+    }
+
+    // links go last, so hard links find their file in place and nothing
+    // gets written through a symlink we made
+    let mut written: HashSet<Vec<u8>> = nodes.iter().filter(|f| f.is_file()).map(|f| f.get_fname()).collect();
+    nodes.sort_by_key(|f| match f.get_kind() {
+        EntryKind::Hardlink => 1,
+        EntryKind::Symlink => 2,
+        _ => 0
+    });
+    let total = nodes.len();
+    for (i, f) in nodes.into_iter().enumerate() {
+        let name = f.get_fname();
+        let out = match extract::disk_path(dest, &name) {
+            Ok(a) => a,
+            Err(_) => {
+                summary.skipped.push((PathBuf::from(String::from_utf8_lossy(&name).to_string()), UNSAFE_NAME.to_string()));
+                continue;
+            }
+        };
+        extract::create_parents(dest, &out)?;
+        if let Some(reason) = extract_node(df, f, &out, dest, &written, opts)? {
+            // so no hard link gets made to whatever was left there
+            written.remove(&name);
+            summary.skipped.push((out, reason));
+            continue;
+        }
+        summary.files += 1;
+        progress(i + 1, total, &name);
+    }
+
+    // directories go last and deepest first, since writing into them changes
+    // their times and restoring their permissions may stop us writing
+    dirs.sort_by_key(|(out, _)| std::cmp::Reverse(out.components().count()));
+    for (out, meta) in dirs {
+        meta.apply(&out, &opts.restore)?;
+    }
+    Ok(summary)
+}
+
+/// writes the entry `f`, which isn't a directory, to `out`. Hard links to a
+/// file in `written` are linked to it, and the rest get a copy of its data.
+/// Returns why the entry was skipped, if it was
+fn extract_node(df: &Datafile, f: &EncFile, out: &Path, dest: &Path, written: &HashSet<Vec<u8>>, opts: &ExtractOptions) -> Result<Option<String>, VaultError> {
+    let target = f.get_target().unwrap_or_default();
+    let file = match f.get_kind() {
+        EntryKind::File => Some(f),
+        EntryKind::Hardlink if written.contains(target) => None,
+        EntryKind::Hardlink => match df.find(target) {
+            Some(a) => Some(a),
+            None => return Err(VaultError::NotFound(String::from_utf8_lossy(target).to_string()))
+        },
+        EntryKind::Symlink if !opts.unsafe_links && !path::link_stays_within(target) => {
+            return Ok(Some(format!("its target {} could lead outside the directory being extracted to", String::from_utf8_lossy(target))));
+        },
+        _ => None
+    };
+    if let Some(file) = file {
+        return match df.save_to_file(file, out, opts) {
+            Ok(_) => Ok(None),
+            Err(VaultError::AlreadyExists(_)) => Ok(Some(EXISTS.to_string())),
+            Err(e) => Err(e)
+        };
+    }
+
+    let mtime = f.get_metadata().map(|m| m.mtime);
+    if !extract::make_room(out, opts, mtime)? {
+        return Ok(Some(EXISTS.to_string()));
+    }
+    let res = match f.get_kind() {
+        EntryKind::Hardlink => std::fs::hard_link(extract::disk_path(dest, target)?, out),
+        EntryKind::Symlink => make_symlink(target, out),
+        kind => make_node(out, kind, f.get_device().unwrap_or_default())
+    };
+    match res {
+        Ok(_) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied && f.get_device().is_some() => {
+            return Ok(Some("creating devices takes root".to_string()));
+        },
+        Err(e) => return Err(e.into())
+    };
+    if let Some(meta) = f.get_metadata() {
+        meta.apply(out, &opts.restore)?;
+    }
+    Ok(None)
+}
+
+
+/// compares the whole archive with the directory `dir`, as if it had been
+/// extracted there: every entry has to be there as the same kind of thing,
+/// files with the same SHA-256 and links pointing at the same place, and
+/// nothing else may be. Differences go in the report
+pub fn compare_tree(df: &Datafile, dir: &Path) -> Result<Report, VaultError> {
+    if !std::fs::metadata(dir)?.is_dir() {
+        return Err(VaultError::NotADirectory(dir.to_string_lossy().to_string()));
+    }
+
+    let mut report = Report::default();
+    for f in df.files().iter() {
+        let name = f.get_fname();
+        let out = match extract::disk_path(dir, &name) {
+            Ok(a) => a,
+            Err(_) => {
+                report.entry(&name, None, UNSAFE_NAME.to_string());
+                continue;
+            }
+        };
+        let meta = match std::fs::symlink_metadata(&out) {
+            Ok(a) => a,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                report.entry(&name, None, format!("{} is missing", out.display()));
+                continue;
+            },
+            Err(e) => return Err(e.into())
+        };
+
+        let same = match f.get_kind() {
+            EntryKind::Directory => meta.is_dir(),
+            EntryKind::File | EntryKind::Hardlink if meta.is_file() => {
+                report.entries += 1;
+                let (digest, len) = verify::digest(std::fs::File::open(&out)?)?;
+                report.bytes += len;
+                digest == df.digest_of(f)?
+            },
+            EntryKind::Symlink if meta.file_type().is_symlink() => os_bytes(&std::fs::read_link(&out)?) == f.get_target().unwrap_or_default(),
+            EntryKind::Fifo => matches!(special(&meta), Special::Fifo),
+            EntryKind::CharDevice | EntryKind::BlockDevice => match special(&meta) {
+                Special::Device{block, rdev} => block == (f.get_kind() == EntryKind::BlockDevice) && Some(rdev) == f.get_device(),
+                _ => false
+            },
+            _ => false
+        };
+        if !same {
+            report.entry(&name, None, format!("{} differs from the archive", out.display()));
+        }
+    }
+
+    // and anything on disk the archive doesn't have
+    let mut walker = WalkDir::new(dir).min_depth(1).sort_by_file_name().into_iter();
+    while let Some(entry) = walker.next() {
+        let entry = entry.map_err(std::io::Error::from)?;
+        let name = relative(entry.path(), dir);
+        if df.find(&name).is_none() && !df.is_dir(&name) {
+            report.entry(&name, None, format!("{} is not in the archive", entry.path().display()));
+            // once is enough for a whole directory
+            if entry.file_type().is_dir() {
+                walker.skip_current_dir();
+            }
+        }
+    }
+    Ok(report)
+}
+
+
+////////////////////////// PLATFORM FUNCTIONS //////////////////////////
+/// works out what something on disk that isn't a file or directory is
+#[cfg(unix)]
+fn special(meta: &std::fs::Metadata) -> Special {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    let ft = meta.file_type();
+    if ft.is_symlink() {
+        Special::Symlink
+    } else if ft.is_fifo() {
+        Special::Fifo
+    } else if ft.is_char_device() || ft.is_block_device() {
+        Special::Device{block: ft.is_block_device(), rdev: meta.rdev()}
+    } else {
+        Special::Other
+    }
+}
+
+#[cfg(not(unix))]
+fn special(meta: &std::fs::Metadata) -> Special {
+    match meta.file_type().is_symlink() {
+        true => Special::Symlink,
+        false => Special::Other
+    }
+}
+
+/// returns the device and inode of a file with more than one name
+#[cfg(unix)]
+fn inode(meta: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    match meta.nlink() > 1 {
+        true => Some((meta.dev(), meta.ino())),
+        false => None
+    }
+}
+
+#[cfg(not(unix))]
+fn inode(_meta: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// returns the raw bytes of a path
+#[cfg(unix)]
+fn os_bytes(p: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    p.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+fn os_bytes(p: &Path) -> Vec<u8> {
+    p.to_string_lossy().as_bytes().to_vec()
+}
+
+/// creates a symlink at `out` pointing at `target`
+#[cfg(unix)]
+fn make_symlink(target: &[u8], out: &Path) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    std::os::unix::fs::symlink(std::ffi::OsStr::from_bytes(target), out)
+}
+
+#[cfg(not(unix))]
+fn make_symlink(_target: &[u8], _out: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "symlinks can't be created on this platform"))
+}
+
+/// creates a named pipe or device at `out`. Devices start out only
+/// readable by their owner, until their permissions are restored
+#[cfg(unix)]
+fn make_node(out: &Path, kind: EntryKind, rdev: u64) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    let c_path = std::ffi::CString::new(out.as_os_str().as_bytes())?;
+    let (mode, dev) = match kind {
+        EntryKind::Fifo => (libc::S_IFIFO | 0o666, 0),
+        EntryKind::CharDevice => (libc::S_IFCHR | 0o600, rdev),
+        EntryKind::BlockDevice => (libc::S_IFBLK | 0o600, rdev),
+        _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a pipe or device"))
+    };
+    // SAFETY: the path is NUL terminated and outlives the call
+    match unsafe { libc::mknod(c_path.as_ptr(), mode, dev as libc::dev_t) } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+fn make_node(_out: &Path, _kind: EntryKind, _rdev: u64) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "pipes and devices can't be created on this platform"))
+}
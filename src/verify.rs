@@ -0,0 +1,134 @@
+use std::io::Read;
+
+use hmac_sha256::Hash;
+
+use crate::table::DIGEST_LEN;
+
+
+////////////////////////// DEFINITIONS /////////////////////////////////////
+/// Something wrong that `Datafile::verify` found
+#[derive(Clone, Debug, PartialEq)]
+pub struct Problem {
+    /// where in the archive it is, if it is somewhere in particular
+    pub offset: Option<u64>,
+    /// the entry it affects, if any
+    pub entry: Option<Vec<u8>>,
+    pub message: String
+}
+
+/// What `Datafile::verify` checked, and what it found wrong
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Report {
+    /// how many records authenticated
+    pub records: usize,
+    /// how many regular files were checked
+    pub entries: usize,
+    /// how many bytes of their data were read back
+    pub bytes: u64,
+    /// how many of them have no digest to check their data against, because
+    /// they were saved before digests were recorded
+    pub undigested: usize,
+    /// how many bytes an interrupted save left at the end of the archive
+    pub torn: u64,
+    pub problems: Vec<Problem>
+}
+
+
+/////////////////////////// DISPLAY IMPL ////////////////////////////////////
+/// One line per problem, then one line per count, with tab separated fields
+/// so scripts can pick them apart. Names have tabs, newlines and
+/// backslashes escaped
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for p in self.problems.iter() {
+            writeln!(f, "{}", p)?;
+        }
+        writeln!(f, "records\t{}", self.records)?;
+        writeln!(f, "entries\t{}", self.entries)?;
+        writeln!(f, "bytes\t{}", self.bytes)?;
+        writeln!(f, "undigested\t{}", self.undigested)?;
+        writeln!(f, "torn\t{}", self.torn)?;
+        writeln!(f, "problems\t{}", self.problems.len())?;
+        write!(f, "status\t{}", match self.is_ok() {
+            true => "ok",
+            false => "failed"
+        })
+    }
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let offset = match self.offset {
+            Some(a) => a.to_string(),
+            None => "-".to_string()
+        };
+        let entry = match &self.entry {
+            Some(a) => escape(a),
+            None => "-".to_string()
+        };
+        write!(f, "problem\t{}\t{}\t{}", offset, entry, escape(self.message.as_bytes()))
+    }
+}
+
+
+////////////////////////// REPORT FUNCTIONS //////////////////////////
+impl Report {
+    /// returns true if nothing was found wrong
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// notes a problem at a place in the archive
+    pub fn at(&mut self, offset: u64, message: String) {
+        self.problems.push(Problem{offset: Some(offset), entry: None, message});
+    }
+
+    /// notes a problem with an entry, which may be at a place in the archive
+    pub fn entry(&mut self, name: &[u8], offset: Option<u64>, message: String) {
+        self.problems.push(Problem{offset, entry: Some(name.to_vec()), message});
+    }
+}
+
+
+/// hashes everything `reader` has left, returning its SHA-256 along with how
+/// many bytes there were
+pub fn digest<R: Read>(mut reader: R) -> std::io::Result<([u8; DIGEST_LEN], u64)> {
+    let mut hash = Hash::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut len: u64 = 0;
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok((hash.finalize(), len)),
+            Ok(n) => {
+                hash.update(&buf[..n]);
+                len += n as u64;
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e)
+        };
+    }
+}
+
+/// writes a digest out in lowercase hex
+pub fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// builds the line `sha256sum` would print for a file called `name` with
+/// this digest. Like it, names with a backslash or newline in them get those
+/// escaped and the line marked with a leading backslash
+pub fn manifest_line(digest: &[u8], name: &[u8]) -> String {
+    let name = String::from_utf8_lossy(name);
+    match name.contains(['\\', '\n', '\r']) {
+        true => format!("\\{}  {}", hex(digest), name.replace('\\', "\\\\").replace('\n', "\\n").replace('\r', "\\r")),
+        false => format!("{}  {}", hex(digest), name)
+    }
+}
+
+/// turns a name into something that fits in one field of a report line
+pub(crate) fn escape(name: &[u8]) -> String {
+    String::from_utf8_lossy(name)
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,201 @@
+//! Entries have to read back the same however they got into the archive,
+//! before and after it is saved
+mod common;
+
+use std::io::Read;
+
+use common::*;
+use vault::{Datafile, VaultError};
+
+
+/// gives out `len` bytes of a pattern, a few at a time, the way a pipe would
+struct Trickle {
+    pos: usize,
+    len: usize
+}
+
+impl Read for Trickle {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = buf.len().min(1000).min(self.len - self.pos);
+        for (i, b) in buf[..n].iter_mut().enumerate() {
+            *b = ((self.pos + i) * 7 % 253) as u8;
+        }
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// what `Trickle` gives out
+fn trickled(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 % 253) as u8).collect()
+}
+
+#[test]
+fn streamed_entries_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let (mut df, path) = create(dir.path(), "streamed.vault");
+    // bigger than one sealed chunk, and not a multiple of it
+    let len = 200_000;
+    df.add_reader(b"piped".to_vec(), Trickle{pos: 0, len}).unwrap();
+    df.add_reader(b"empty".to_vec(), &b""[..]).unwrap();
+    assert_eq!(df.read_entry(b"piped").unwrap(), trickled(len));
+    assert_eq!(df.read_entry(b"empty").unwrap(), Vec::<u8>::new());
+    df.save_in_place().unwrap();
+    drop(df);
+
+    let df = Datafile::open_read_only(&path, PASSWORD).unwrap();
+    assert_eq!(df.read_entry(b"piped").unwrap(), trickled(len));
+    assert_eq!(df.read_entry(b"empty").unwrap(), Vec::<u8>::new());
+    assert!(df.verify().unwrap().is_ok());
+}
+
+#[test]
+fn failed_streams_add_nothing() {
+    struct Broken;
+    impl Read for Broken {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("pipe burst"))
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let (mut df, _) = create(dir.path(), "broken.vault");
+    assert!(matches!(df.add_reader(b"piped".to_vec(), Broken), Err(VaultError::Io(_))));
+    assert!(df.find(b"piped").is_none());
+    assert!(matches!(df.add_reader(b"../up".to_vec(), &b"x"[..]), Err(VaultError::InvalidName(_))));
+}
+
+#[test]
+fn appended_saves_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let (mut df, path) = create(dir.path(), "appended.vault");
+    df.add_bytes(b"hello.txt".to_vec(), fixture_file("hello.txt")).unwrap();
+    df.save_in_place().unwrap();
+    drop(df);
+    let first = std::fs::read(&path).unwrap();
+
+    // saving back only adds to the end, and leaves what was there alone
+    let mut df = Datafile::open(&path, PASSWORD).unwrap();
+    df.add_bytes(b"data.bin".to_vec(), fixture_file("data.bin")).unwrap();
+    df.save_in_place().unwrap();
+    drop(df);
+    let second = std::fs::read(&path).unwrap();
+    assert!(second.len() > first.len());
+    assert_eq!(&second[..first.len()], &first[..]);
+
+    let df = Datafile::open_read_only(&path, PASSWORD).unwrap();
+    assert_fixture_files(&df);
+    assert!(df.verify().unwrap().is_ok());
+}
+
+#[test]
+fn compacting_drops_what_no_entry_uses() {
+    let dir = tempfile::tempdir().unwrap();
+    let (mut df, path) = create(dir.path(), "compacted.vault");
+    for name in FILES.iter() {
+        df.add_bytes(name.as_bytes().to_vec(), fixture_file(name)).unwrap();
+    }
+    df.add_bytes(b"gone".to_vec(), trickled(100_000)).unwrap();
+    df.save_in_place().unwrap();
+    drop(df);
+
+    let mut df = Datafile::open(&path, PASSWORD).unwrap();
+    df.remove(b"gone").unwrap();
+    df.save_in_place().unwrap();
+    let before = std::fs::metadata(&path).unwrap().len();
+    assert!(df.stats().unwrap().reclaimable >= 100_000);
+    let freed = df.compact().unwrap();
+    assert!(freed >= 100_000);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), before - freed);
+    assert_eq!(df.stats().unwrap().reclaimable, 0);
+    drop(df);
+
+    let df = Datafile::open_read_only(&path, PASSWORD).unwrap();
+    assert_fixture_files(&df);
+    assert!(df.verify().unwrap().is_ok());
+}
+
+#[test]
+fn torn_saves_are_dropped() {
+    let dir = tempfile::tempdir().unwrap();
+    let (mut df, path) = create(dir.path(), "torn.vault");
+    for name in FILES.iter() {
+        df.add_bytes(name.as_bytes().to_vec(), fixture_file(name)).unwrap();
+    }
+    df.save_in_place().unwrap();
+    drop(df);
+    let saved = std::fs::read(&path).unwrap();
+
+    // what a save cut short leaves after the last trailer
+    let mut torn = saved.clone();
+    torn.extend_from_slice(b"VREC half a record");
+    std::fs::write(&path, &torn).unwrap();
+    let mut df = Datafile::open(&path, PASSWORD).unwrap();
+    assert_eq!(df.torn_tail(), 18);
+    assert_fixture_files(&df);
+
+    // and the next save starts where the last complete one ended
+    df.add_bytes(b"more".to_vec(), b"after the tear".to_vec()).unwrap();
+    df.save_in_place().unwrap();
+    drop(df);
+    assert_eq!(&std::fs::read(&path).unwrap()[..saved.len()], &saved[..]);
+    let df = Datafile::open_read_only(&path, PASSWORD).unwrap();
+    assert_eq!(df.torn_tail(), 0);
+    assert_eq!(df.read_entry(b"more").unwrap(), b"after the tear");
+    assert!(df.verify().unwrap().is_ok());
+}
+
+#[test]
+fn shared_data_is_stored_once() {
+    let dir = tempfile::tempdir().unwrap();
+    let (mut df, path) = create(dir.path(), "dedup.vault");
+    let data = trickled(300_000);
+    df.add_bytes(b"one".to_vec(), data.clone()).unwrap();
+    df.add_bytes(b"two".to_vec(), data.clone()).unwrap();
+    df.save_in_place().unwrap();
+    let stats = df.stats().unwrap();
+    assert_eq!(stats.logical, 600_000);
+    assert_eq!(stats.unique, 300_000);
+    assert_eq!(stats.chunk_refs, 2 * stats.chunks);
+    assert!(stats.archive < 400_000);
+    drop(df);
+
+    // a copy added later only costs a new table
+    let mut df = Datafile::open(&path, PASSWORD).unwrap();
+    let before = std::fs::metadata(&path).unwrap().len();
+    df.add_reader(b"three".to_vec(), &data[..]).unwrap();
+    df.save_in_place().unwrap();
+    assert!(std::fs::metadata(&path).unwrap().len() - before < 10_000);
+
+    // and new keys mean new chunk ids, but the chunks are still shared
+    df.update_pass(PASSWORD.to_string()).unwrap();
+    df.save_in_place().unwrap();
+    assert_eq!(df.stats().unwrap().unique, 300_000);
+    drop(df);
+
+    let df = Datafile::open_read_only(&path, PASSWORD).unwrap();
+    for name in [&b"one"[..], b"two", b"three"] {
+        assert_eq!(df.read_entry(name).unwrap(), data);
+    }
+    assert!(df.verify().unwrap().is_ok());
+}
+
+#[test]
+fn removing_a_copy_keeps_the_shared_chunks() {
+    let dir = tempfile::tempdir().unwrap();
+    let (mut df, path) = create(dir.path(), "dedup.vault");
+    let data = trickled(300_000);
+    df.add_bytes(b"one".to_vec(), data.clone()).unwrap();
+    df.add_bytes(b"two".to_vec(), data.clone()).unwrap();
+    df.save_in_place().unwrap();
+
+    df.remove(b"one").unwrap();
+    df.save_in_place().unwrap();
+    assert!(df.stats().unwrap().reclaimable < 10_000);
+    df.compact().unwrap();
+    drop(df);
+
+    let df = Datafile::open_read_only(&path, PASSWORD).unwrap();
+    assert_eq!(df.read_entry(b"two").unwrap(), data);
+    assert!(df.verify().unwrap().is_ok());
+}
@@ -0,0 +1,58 @@
+//! Helpers shared by the integration tests. The archives in `fixtures/`
+//! hold the files in `fixtures/files/` under `PASSWORD`. `v0.vault` was made
+//! by the original headerless build, and the others by the build of their
+//! format version with `cheap_kdf()`
+#![allow(dead_code)]
+
+use std::path::{Path, PathBuf};
+
+use vault::{CipherKind, Datafile, KdfParams};
+
+
+/// The password every fixture archive is sealed with
+pub const PASSWORD: &str = "fixture";
+
+/// The files every fixture archive holds, by name
+pub const FILES: [&str; 2] = ["hello.txt", "data.bin"];
+
+
+/// Argon2 settings that keep the tests fast. Nothing here is about strength
+pub fn cheap_kdf() -> KdfParams {
+    KdfParams::Argon2id { m_cost: 64, t_cost: 1, p_cost: 1 }
+}
+
+/// returns where the fixtures live
+pub fn fixtures() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures")
+}
+
+/// reads one of the files the fixture archives hold
+pub fn fixture_file(name: &str) -> Vec<u8> {
+    std::fs::read(fixtures().join("files").join(name)).unwrap()
+}
+
+/// copies a fixture archive into `dir`, so tests never change the original
+pub fn fixture(dir: &Path, name: &str) -> String {
+    let out = dir.join(name);
+    std::fs::copy(fixtures().join(name), &out).unwrap();
+    out.to_string_lossy().to_string()
+}
+
+/// creates an empty archive called `name` in `dir`
+pub fn create(dir: &Path, name: &str) -> (Datafile, String) {
+    let path = dir.join(name).to_string_lossy().to_string();
+    let df = Datafile::setup_new(PASSWORD.to_string(), path.clone(), cheap_kdf(), CipherKind::default()).unwrap();
+    (df, path)
+}
+
+/// makes sure the archive holds exactly the fixture files, byte for byte
+pub fn assert_fixture_files(df: &Datafile) {
+    let mut names: Vec<Vec<u8>> = df.files().iter().map(|f| f.get_fname()).collect();
+    names.sort();
+    let mut want: Vec<Vec<u8>> = FILES.iter().map(|n| n.as_bytes().to_vec()).collect();
+    want.sort();
+    assert_eq!(names, want);
+    for name in FILES.iter() {
+        assert_eq!(df.read_entry(name.as_bytes()).unwrap(), fixture_file(name), "{}", name);
+    }
+}
@@ -0,0 +1,161 @@
+//! Damage to an archive has to be found by `verify`, named down to the
+//! entries it hurts, and `recover` has to save everything it didn't hurt
+mod common;
+
+use std::convert::TryInto;
+use std::path::Path;
+
+use common::*;
+use vault::{Datafile, VaultError};
+use vault::record::{RECORD_HEADER_LEN, TRAILER_LEN};
+use vault::recover::LOST_AND_FOUND;
+use vault::tree;
+
+
+/// saves an archive holding the fixture files, returning its path
+fn saved(dir: &Path) -> String {
+    let (mut df, path) = create(dir, "damaged.vault");
+    for name in FILES.iter() {
+        df.add_bytes(name.as_bytes().to_vec(), fixture_file(name)).unwrap();
+    }
+    df.save_in_place().unwrap();
+    path
+}
+
+/// returns where the first chunk record of `name` starts
+fn first_chunk(path: &str, name: &[u8]) -> usize {
+    let df = Datafile::open_read_only(path, PASSWORD).unwrap();
+    df.find(name).unwrap().get_chunks().unwrap()[0].offset
+}
+
+/// flips a byte of the archive at `path`
+fn flip(path: &str, at: usize) {
+    let mut raw = std::fs::read(path).unwrap();
+    raw[at] ^= 0x40;
+    std::fs::write(path, &raw).unwrap();
+}
+
+#[test]
+fn intact_archives_verify() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = saved(dir.path());
+    let df = Datafile::open_read_only(&path, PASSWORD).unwrap();
+    let report = df.verify().unwrap();
+    assert!(report.is_ok(), "{:?}", report.problems);
+    assert_eq!(report.entries, 2);
+    assert_eq!(report.bytes, FILES.iter().map(|n| fixture_file(n).len() as u64).sum::<u64>());
+    assert_eq!(report.undigested, 0);
+}
+
+#[test]
+fn damaged_data_is_blamed_on_its_entry() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = saved(dir.path());
+    let chunk = first_chunk(&path, b"data.bin");
+    flip(&path, chunk + RECORD_HEADER_LEN + 5);
+
+    // the table is its own record, so the archive still opens
+    let df = Datafile::open_read_only(&path, PASSWORD).unwrap();
+    assert_eq!(df.read_entry(b"hello.txt").unwrap(), fixture_file("hello.txt"));
+    assert!(df.read_entry(b"data.bin").is_err());
+
+    let report = df.verify().unwrap();
+    assert!(!report.is_ok());
+    assert!(report.problems.iter().any(|p| p.offset == Some(chunk as u64) && p.entry.is_none()));
+    let blamed: Vec<&[u8]> = report.problems.iter().filter_map(|p| p.entry.as_deref()).collect();
+    assert_eq!(blamed, vec![&b"data.bin"[..]]);
+}
+
+#[test]
+fn stray_bytes_are_reported() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = saved(dir.path());
+    let mut raw = std::fs::read(&path).unwrap();
+    raw.extend_from_slice(b"left over");
+    std::fs::write(&path, &raw).unwrap();
+
+    let df = Datafile::open_read_only(&path, PASSWORD).unwrap();
+    let report = df.verify().unwrap();
+    assert_eq!(report.torn, 9);
+    assert_eq!(report.problems.len(), 1);
+}
+
+#[test]
+fn archives_compare_with_what_was_extracted() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = saved(dir.path());
+    let df = Datafile::open_read_only(&path, PASSWORD).unwrap();
+    let dest = dir.path().join("dest");
+    std::fs::create_dir(&dest).unwrap();
+    for name in FILES.iter() {
+        std::fs::write(dest.join(name), fixture_file(name)).unwrap();
+    }
+    assert!(tree::compare_tree(&df, &dest).unwrap().is_ok());
+
+    std::fs::write(dest.join("hello.txt"), b"hello, someone else\n").unwrap();
+    std::fs::write(dest.join("extra"), b"").unwrap();
+    let report = tree::compare_tree(&df, &dest).unwrap();
+    assert_eq!(report.problems.len(), 2);
+}
+
+#[test]
+fn recover_keeps_the_entries_that_are_whole() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = saved(dir.path());
+    flip(&path, first_chunk(&path, b"data.bin") + RECORD_HEADER_LEN + 5);
+    let damaged = std::fs::read(&path).unwrap();
+
+    let out = dir.path().join("recovered.vault").to_string_lossy().to_string();
+    let report = Datafile::recover(path.clone(), PASSWORD.to_string(), out.clone()).unwrap();
+    assert!(report.table.is_some());
+    assert_eq!(report.damaged.len(), 1);
+    assert_eq!(report.recovered, 1);
+    assert_eq!(report.lost.iter().map(|(n, _)| n.clone()).collect::<Vec<_>>(), vec![b"data.bin".to_vec()]);
+    // the damaged archive is left as it was
+    assert_eq!(std::fs::read(&path).unwrap(), damaged);
+
+    let df = Datafile::open_read_only(&out, PASSWORD).unwrap();
+    assert_eq!(df.read_entry(b"hello.txt").unwrap(), fixture_file("hello.txt"));
+    assert!(df.find(b"data.bin").is_none());
+    assert!(df.verify().unwrap().is_ok());
+
+    // and nothing already at the destination gets replaced
+    let res = Datafile::recover(path, PASSWORD.to_string(), out);
+    assert!(matches!(res, Err(VaultError::AlreadyExists(_))));
+}
+
+#[test]
+fn recover_falls_back_to_an_older_table() {
+    let dir = tempfile::tempdir().unwrap();
+    let (mut df, path) = create(dir.path(), "damaged.vault");
+    df.add_bytes(b"hello.txt".to_vec(), fixture_file("hello.txt")).unwrap();
+    df.save_in_place().unwrap();
+    df.add_bytes(b"data.bin".to_vec(), fixture_file("data.bin")).unwrap();
+    df.save_in_place().unwrap();
+    drop(df);
+
+    // break the table the trailer points at
+    let raw = std::fs::read(&path).unwrap();
+    let trailer = &raw[raw.len()-TRAILER_LEN..];
+    let table = u64::from_le_bytes(trailer[..8].try_into().unwrap()) as usize;
+    flip(&path, table + RECORD_HEADER_LEN + 5);
+    assert!(Datafile::open_read_only(&path, PASSWORD).is_err());
+
+    let out = dir.path().join("recovered.vault").to_string_lossy().to_string();
+    let report = Datafile::recover(path, PASSWORD.to_string(), out.clone()).unwrap();
+    assert!(matches!(report.table, Some(a) if (a as usize) < table));
+    assert_eq!(report.recovered, 1);
+    assert!(report.lost.is_empty());
+    // the newer file's data is still there, just without a name
+    assert!(!report.found.is_empty());
+
+    let df = Datafile::open_read_only(&out, PASSWORD).unwrap();
+    assert_eq!(df.read_entry(b"hello.txt").unwrap(), fixture_file("hello.txt"));
+    let mut found: Vec<u8> = Vec::new();
+    for (name, _) in report.found.iter() {
+        assert!(name.starts_with(LOST_AND_FOUND));
+        found.extend_from_slice(&df.read_entry(name).unwrap());
+    }
+    assert_eq!(found, fixture_file("data.bin"));
+    assert!(df.verify().unwrap().is_ok());
+}
@@ -0,0 +1,173 @@
+//! Extracting an archive must never write anything, or leave a link that
+//! leads anywhere, outside the directory it extracts to
+#![cfg(unix)]
+mod common;
+
+use std::path::Path;
+
+use common::*;
+use vault::{Datafile, ExtractOptions, Metadata, Overwrite, VaultError};
+use vault::meta::Timestamp;
+use vault::tree::{self, Filter};
+
+
+/// extracts the whole archive into `dest`, returning the names it skipped
+fn extract_all(df: &Datafile, dest: &Path, opts: &ExtractOptions) -> Vec<String> {
+    let filter = Filter::new::<&str>(&[], &[]).unwrap();
+    let summary = tree::extract_tree(df, b"", dest, &filter, opts, &mut |_, _, _| ()).unwrap();
+    summary.skipped.iter().map(|(p, _)| p.strip_prefix(dest).unwrap_or(p).to_string_lossy().to_string()).collect()
+}
+
+/// makes sure everything under `dest` resolves to somewhere inside it
+fn assert_contained(dest: &Path) {
+    let root = dest.canonicalize().unwrap();
+    for entry in walkdir::WalkDir::new(dest).min_depth(1) {
+        let entry = entry.unwrap();
+        if let Ok(real) = entry.path().canonicalize() {
+            assert!(real.starts_with(&root), "{} leads to {}", entry.path().display(), real.display());
+        }
+    }
+}
+
+#[test]
+fn symlink_chains_stay_inside() {
+    // each link on its own only looks like it climbs back to where it
+    // started, but `s` goes through `a/b` to end up above `dest`
+    let dir = tempfile::tempdir().unwrap();
+    let (mut df, _) = create(dir.path(), "links.vault");
+    df.mkdir(b"a".to_vec()).unwrap();
+    df.add_symlink(b"a/b".to_vec(), b"..".to_vec()).unwrap();
+    df.add_symlink(b"s".to_vec(), b"a/b/..".to_vec()).unwrap();
+    df.add_symlink(b"a/down".to_vec(), b"c/d".to_vec()).unwrap();
+
+    let dest = dir.path().join("dest");
+    std::fs::create_dir(&dest).unwrap();
+    let mut skipped = extract_all(&df, &dest, &ExtractOptions::default());
+    skipped.sort();
+    assert_eq!(skipped, vec!["a/b".to_string(), "s".to_string()]);
+    assert_contained(&dest);
+    assert_eq!(std::fs::read_link(dest.join("a/down")).unwrap(), Path::new("c/d"));
+}
+
+#[test]
+fn unsafe_links_are_created_when_asked() {
+    let dir = tempfile::tempdir().unwrap();
+    let (mut df, _) = create(dir.path(), "links.vault");
+    df.add_symlink(b"up".to_vec(), b"../elsewhere".to_vec()).unwrap();
+
+    let dest = dir.path().join("dest");
+    std::fs::create_dir(&dest).unwrap();
+    let opts = ExtractOptions{unsafe_links: true, ..ExtractOptions::default()};
+    assert!(extract_all(&df, &dest, &opts).is_empty());
+    assert_eq!(std::fs::read_link(dest.join("up")).unwrap(), Path::new("../elsewhere"));
+}
+
+/// builds an archive holding the fixture files, both last modified at `secs`
+fn fixture_archive(dir: &Path, secs: i64) -> Datafile {
+    let (mut df, _) = create(dir, "files.vault");
+    for name in FILES.iter() {
+        df.add_bytes(name.as_bytes().to_vec(), fixture_file(name)).unwrap();
+        let meta = Metadata{mode: 0o644, mtime: Timestamp{secs, nanos: 0}, ..Metadata::default()};
+        df.set_metadata(name.as_bytes(), Some(meta)).unwrap();
+    }
+    df
+}
+
+#[test]
+fn extracted_tree_round_trips() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut df = fixture_archive(dir.path(), 0);
+    df.mkdir(b"sub".to_vec()).unwrap();
+    df.add_bytes(b"sub/nested".to_vec(), b"deeper".to_vec()).unwrap();
+    df.save_in_place().unwrap();
+
+    let dest = dir.path().join("dest");
+    assert!(extract_all(&df, &dest, &ExtractOptions::default()).is_empty());
+    for name in FILES.iter() {
+        assert_eq!(std::fs::read(dest.join(name)).unwrap(), fixture_file(name));
+    }
+    assert_eq!(std::fs::read(dest.join("sub/nested")).unwrap(), b"deeper");
+
+    // extracting again leaves everything alone
+    let mut skipped = extract_all(&df, &dest, &ExtractOptions::default());
+    skipped.sort();
+    assert_eq!(skipped, vec!["data.bin".to_string(), "hello.txt".to_string(), "sub/nested".to_string()]);
+}
+
+#[test]
+fn existing_files_follow_the_overwrite_policy() {
+    let dir = tempfile::tempdir().unwrap();
+    let out = dir.path().join("out");
+    let out_name = out.to_string_lossy().to_string();
+    let policy = |overwrite: Overwrite| ExtractOptions{overwrite, ..ExtractOptions::default()};
+
+    let old = fixture_archive(dir.path(), 1);
+    let file = old.find(b"hello.txt").unwrap();
+    std::fs::write(&out, b"already here").unwrap();
+    assert!(matches!(old.save_to_file(file, out_name.clone(), &policy(Overwrite::Never)), Err(VaultError::AlreadyExists(_))));
+    assert!(matches!(old.save_to_file(file, out_name.clone(), &policy(Overwrite::Ask)), Err(VaultError::AlreadyExists(_))));
+    // what is on disk was modified after 1970
+    assert!(matches!(old.save_to_file(file, out_name.clone(), &policy(Overwrite::Newer)), Err(VaultError::AlreadyExists(_))));
+    assert_eq!(std::fs::read(&out).unwrap(), b"already here");
+
+    std::fs::create_dir(dir.path().join("new")).unwrap();
+    let new = fixture_archive(&dir.path().join("new"), i64::from(u32::MAX));
+    new.save_to_file(new.find(b"hello.txt").unwrap(), out_name.clone(), &policy(Overwrite::Newer)).unwrap();
+    assert_eq!(std::fs::read(&out).unwrap(), fixture_file("hello.txt"));
+
+    std::fs::write(&out, b"already here").unwrap();
+    old.save_to_file(file, out_name, &policy(Overwrite::Always)).unwrap();
+    assert_eq!(std::fs::read(&out).unwrap(), fixture_file("hello.txt"));
+}
+
+#[test]
+fn symlinks_on_disk_are_never_written_through() {
+    let dir = tempfile::tempdir().unwrap();
+    let df = fixture_archive(dir.path(), 0);
+    let victim = dir.path().join("victim");
+    std::fs::write(&victim, b"untouched").unwrap();
+
+    // a link where the file goes is refused, or replaced when asked to, but
+    // never followed
+    let out = dir.path().join("link");
+    std::os::unix::fs::symlink(&victim, &out).unwrap();
+    let file = df.find(b"hello.txt").unwrap();
+    let out_name = out.to_string_lossy().to_string();
+    assert!(df.save_to_file(file, out_name.clone(), &ExtractOptions::default()).is_err());
+    let always = ExtractOptions{overwrite: Overwrite::Always, ..ExtractOptions::default()};
+    df.save_to_file(file, out_name, &always).unwrap();
+    assert!(!std::fs::symlink_metadata(&out).unwrap().file_type().is_symlink());
+    assert_eq!(std::fs::read(&victim).unwrap(), b"untouched");
+
+    // and so is a link standing in for a directory
+    let mut df = df;
+    df.add_bytes(b"outside/hello.txt".to_vec(), b"escaped".to_vec()).unwrap();
+    let dest = dir.path().join("dest");
+    std::fs::create_dir_all(dir.path().join("elsewhere")).unwrap();
+    std::fs::create_dir(&dest).unwrap();
+    std::os::unix::fs::symlink(dir.path().join("elsewhere"), dest.join("outside")).unwrap();
+    let filter = Filter::new::<&str>(&[], &[]).unwrap();
+    let res = tree::extract_tree(&df, b"", &dest, &filter, &always, &mut |_, _, _| ());
+    assert!(matches!(res, Err(VaultError::NotADirectory(_))));
+    assert!(!dir.path().join("elsewhere/hello.txt").exists());
+}
+
+#[test]
+fn names_that_are_not_utf8_are_kept() {
+    use std::os::unix::ffi::OsStrExt;
+    let dir = tempfile::tempdir().unwrap();
+    let src = dir.path().join(std::ffi::OsStr::from_bytes(b"tr\xe9e"));
+    std::fs::create_dir(&src).unwrap();
+    std::fs::write(src.join(std::ffi::OsStr::from_bytes(b"caf\xe9")), b"latin-1").unwrap();
+
+    let (mut df, _) = create(dir.path(), "names.vault");
+    let filter = Filter::new::<&str>(&[], &[]).unwrap();
+    tree::add_tree(&mut df, &src, b"", &filter, false, &mut |_, _, _| ()).unwrap();
+    df.save_in_place().unwrap();
+    assert_eq!(df.read_entry(b"tr\xe9e/caf\xe9").unwrap(), b"latin-1");
+
+    let dest = dir.path().join("dest");
+    assert!(extract_all(&df, &dest, &ExtractOptions::default()).is_empty());
+    let out = dest.join(std::ffi::OsStr::from_bytes(b"tr\xe9e/caf\xe9"));
+    assert_eq!(std::fs::read(out).unwrap(), b"latin-1");
+}
@@ -0,0 +1,115 @@
+//! Archives written by earlier builds have to keep reading the same, whatever
+//! machine reads them
+mod common;
+
+use std::convert::TryInto;
+
+use common::*;
+use vault::header::{FORMAT_VERSION, HEADER_LEN, Header};
+use vault::record::{TRAILER_LEN, TRAILER_MAGIC};
+use vault::{CipherKind, Datafile, VaultError};
+
+
+#[test]
+fn current_fixture_reads_back_byte_for_byte() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = fixture(dir.path(), "v3.vault");
+    let df = Datafile::open_read_only(&path, PASSWORD).unwrap();
+    assert_eq!(df.header().version, FORMAT_VERSION);
+    assert_fixture_files(&df);
+    assert!(df.verify().unwrap().is_ok());
+}
+
+#[test]
+fn current_fixture_header_and_trailer_encode_the_same() {
+    let raw = std::fs::read(fixtures().join("v3.vault")).unwrap();
+    let header = Header::parse(&raw).unwrap();
+    assert_eq!(header.serialize(), raw[..HEADER_LEN].to_vec());
+
+    // the trailer is a little-endian u64 offset of a table record
+    let trailer = &raw[raw.len()-TRAILER_LEN..];
+    assert_eq!(&trailer[8..], TRAILER_MAGIC);
+    let table = u64::from_le_bytes(trailer[..8].try_into().unwrap()) as usize;
+    assert!(table >= HEADER_LEN && table < raw.len() - TRAILER_LEN);
+    assert_eq!(&raw[table..table+4], b"VREC");
+    assert_eq!(raw[table+4], 2);
+}
+
+#[test]
+fn current_fixture_rejects_wrong_password() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = fixture(dir.path(), "v3.vault");
+    assert!(matches!(Datafile::open_read_only(&path, "wrong"), Err(VaultError::Authentication)));
+}
+
+#[test]
+fn v0_fixture_migrates() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = fixture(dir.path(), "v0.vault");
+    assert!(matches!(Datafile::open_read_only(&path, PASSWORD), Err(VaultError::Unsupported(_))));
+
+    Datafile::migrate(path.clone(), PASSWORD.to_string(), cheap_kdf(), CipherKind::default()).unwrap();
+    let df = Datafile::open_read_only(&path, PASSWORD).unwrap();
+    assert_eq!(df.header().version, FORMAT_VERSION);
+    assert_fixture_files(&df);
+}
+
+#[test]
+fn v0_fixture_rejects_wrong_password() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = fixture(dir.path(), "v0.vault");
+    let res = Datafile::migrate(path.clone(), "wrong".to_string(), cheap_kdf(), CipherKind::default());
+    assert!(matches!(res, Err(VaultError::WrongPassword)));
+    assert_eq!(std::fs::read(&path).unwrap(), std::fs::read(fixtures().join("v0.vault")).unwrap());
+}
+
+#[test]
+fn v1_fixture_migrates() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = fixture(dir.path(), "v1.vault");
+    assert!(matches!(Datafile::open_read_only(&path, PASSWORD), Err(VaultError::Unsupported(_))));
+
+    Datafile::migrate(path.clone(), PASSWORD.to_string(), cheap_kdf(), CipherKind::default()).unwrap();
+    let df = Datafile::open_read_only(&path, PASSWORD).unwrap();
+    assert_eq!(df.header().version, FORMAT_VERSION);
+    assert_fixture_files(&df);
+    assert!(df.verify().unwrap().is_ok());
+}
+
+#[test]
+fn v2_fixture_migrates() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = fixture(dir.path(), "v2.vault");
+    assert!(matches!(Datafile::open_read_only(&path, PASSWORD), Err(VaultError::Unsupported(_))));
+
+    Datafile::migrate(path.clone(), PASSWORD.to_string(), cheap_kdf(), CipherKind::default()).unwrap();
+    let df = Datafile::open_read_only(&path, PASSWORD).unwrap();
+    assert_eq!(df.header().version, FORMAT_VERSION);
+    assert_fixture_files(&df);
+    assert!(df.verify().unwrap().is_ok());
+}
+
+#[test]
+fn migrate_passes_on_unknown_versions() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = fixture(dir.path(), "v3.vault");
+    let mut raw = std::fs::read(&path).unwrap();
+    raw[8] = 99;
+    std::fs::write(&path, &raw).unwrap();
+    let res = Datafile::migrate(path, PASSWORD.to_string(), cheap_kdf(), CipherKind::default());
+    assert!(matches!(res, Err(VaultError::Unsupported(_))));
+}
+
+#[test]
+fn new_archive_round_trips() {
+    let dir = tempfile::tempdir().unwrap();
+    let (mut df, path) = create(dir.path(), "new.vault");
+    for name in FILES.iter() {
+        df.add_bytes(name.as_bytes().to_vec(), fixture_file(name)).unwrap();
+    }
+    df.save_in_place().unwrap();
+    drop(df);
+
+    let df = Datafile::open_read_only(&path, PASSWORD).unwrap();
+    assert_fixture_files(&df);
+}
@@ -0,0 +1,90 @@
+//! The plaintext header has one encoding, whatever machine writes it
+use vault::header::{FORMAT_VERSION, HEADER_LEN, Header, SIGNATURE};
+use vault::kdf::{KDF_PARAMS_LEN, KdfParams};
+use vault::{CipherKind, VaultError};
+
+
+fn header() -> Header {
+    Header::new(CipherKind::ChaCha20Poly1305, KdfParams::Argon2id { m_cost: 65536, t_cost: 3, p_cost: 1 }, [0x11; 16], [0x22; 12])
+}
+
+#[test]
+fn header_encodes_little_endian() {
+    let mut want: Vec<u8> = SIGNATURE.to_vec();
+    want.extend_from_slice(&[FORMAT_VERSION as u8, 0]);
+    want.push(2);
+    want.extend_from_slice(&[1, 0x00, 0x00, 0x01, 0x00, 3, 0, 0, 0, 1, 0, 0, 0]);
+    want.extend_from_slice(&[0x11; 16]);
+    want.extend_from_slice(&[0x22; 12]);
+
+    assert_eq!(header().serialize(), want);
+    assert_eq!(want.len(), HEADER_LEN);
+}
+
+#[test]
+fn header_round_trips() {
+    let h = header();
+    assert_eq!(Header::parse(&h.serialize()).unwrap(), h);
+
+    let scrypt = Header{kdf: KdfParams::Scrypt { log_n: 17, r: 8, p: 1 }, ..h};
+    assert_eq!(Header::parse(&scrypt.serialize()).unwrap(), scrypt);
+}
+
+#[test]
+fn header_rejects_what_it_does_not_know() {
+    let raw = header().serialize();
+    assert!(matches!(Header::parse(b"not an archive at all, not even close"), Err(VaultError::Unsupported(_))));
+    assert!(matches!(Header::parse(&raw[..HEADER_LEN-1]), Err(VaultError::Corrupt(_))));
+
+    let mut newer = raw.clone();
+    newer[8] = FORMAT_VERSION as u8 + 1;
+    assert!(matches!(Header::parse(&newer), Err(VaultError::Unsupported(_))));
+
+    let mut cipher = raw.clone();
+    cipher[10] = 9;
+    assert!(matches!(Header::parse(&cipher), Err(VaultError::Unsupported(_))));
+}
+
+#[test]
+fn read_if_signed_tells_v0_apart() {
+    let raw = header().serialize();
+    assert_eq!(Header::read_if_signed(&mut &raw[..]).unwrap(), Some(header()));
+    assert_eq!(Header::read_if_signed(&mut &[0x5au8; 64][..]).unwrap(), None);
+
+    let mut newer = raw.clone();
+    newer[8] = 99;
+    assert!(Header::read_if_signed(&mut &newer[..]).is_err());
+}
+
+#[test]
+fn kdf_params_round_trip() {
+    for kdf in [KdfParams::default(), KdfParams::Scrypt { log_n: 17, r: 8, p: 1 }] {
+        let raw = kdf.serialize();
+        assert_eq!(raw.len(), KDF_PARAMS_LEN);
+        assert_eq!(KdfParams::parse(&raw).unwrap(), kdf);
+    }
+}
+
+#[test]
+fn kdf_params_refuse_costs_we_would_not_pay() {
+    let params = |id: u8, a: u32, b: u32, c: u32| {
+        let mut raw = vec![id];
+        raw.extend_from_slice(&a.to_le_bytes());
+        raw.extend_from_slice(&b.to_le_bytes());
+        raw.extend_from_slice(&c.to_le_bytes());
+        KdfParams::parse(&raw)
+    };
+    // these would overflow working out scrypt's memory, or ask for terabytes
+    assert!(matches!(params(2, 60, 8, 1), Err(VaultError::Kdf(_))));
+    assert!(matches!(params(2, 63, u32::MAX, 1), Err(VaultError::Kdf(_))));
+    assert!(matches!(params(2, 30, 32, 1), Err(VaultError::Kdf(_))));
+    assert!(matches!(params(2, 17, 0, 1), Err(VaultError::Kdf(_))));
+    assert!(matches!(params(1, u32::MAX, 3, 1), Err(VaultError::Kdf(_))));
+    assert!(matches!(params(1, 65536, u32::MAX, 1), Err(VaultError::Kdf(_))));
+    assert!(matches!(params(1, 65536, 3, u32::MAX), Err(VaultError::Kdf(_))));
+    assert!(params(2, 17, 8, 1).is_ok());
+
+    // and deriving with them directly doesn't get any further
+    let huge = KdfParams::Scrypt { log_n: 60, r: 8, p: 1 };
+    assert!(matches!(huge.derive_key("pw", &[0u8; 16]), Err(VaultError::Kdf(_))));
+}
@@ -0,0 +1,46 @@
+//! Metadata read from an archive is untrusted, so odd values have to come
+//! back as errors rather than panics
+use std::time::{Duration, UNIX_EPOCH};
+
+use vault::VaultError;
+use vault::meta::Timestamp;
+
+
+#[test]
+fn timestamps_round_trip() {
+    for t in [UNIX_EPOCH, UNIX_EPOCH + Duration::new(1_700_000_000, 5), UNIX_EPOCH - Duration::new(86_400, 250)] {
+        assert_eq!(Timestamp::from_system_time(t).to_system_time().unwrap(), t);
+    }
+    let before = Timestamp::from_system_time(UNIX_EPOCH - Duration::new(1, 250));
+    assert_eq!(before, Timestamp{secs: -2, nanos: 999_999_750});
+}
+
+#[test]
+fn extreme_timestamps_never_panic() {
+    // how far from the epoch a time can be depends on the platform, so
+    // anything past that has to be an error, and anything else exact
+    for secs in [i64::MAX, i64::MIN, i64::MAX - 1, i64::MIN + 1] {
+        let t = Timestamp{secs, nanos: 999_999_999};
+        match t.to_system_time() {
+            Ok(a) => assert_eq!(Timestamp::from_system_time(a), t),
+            Err(e) => assert!(matches!(e, VaultError::Corrupt(_)), "{}", e)
+        };
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn extreme_times_apply_without_panicking() {
+    use vault::{Metadata, Restore};
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("f");
+    std::fs::write(&path, b"x").unwrap();
+    for secs in [i64::MIN, i64::MAX] {
+        let meta = Metadata{mode: 0o644, mtime: Timestamp{secs, nanos: 0}, atime: Timestamp{secs, nanos: 0}, ..Metadata::default()};
+        let restore = Restore{times: true, ..Restore::default()};
+        // the system may refuse them or clamp them, but either way we
+        // get to carry on
+        let _ = meta.apply(&path, &restore);
+    }
+}
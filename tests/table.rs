@@ -0,0 +1,158 @@
+//! File tables have one encoding, whatever machine writes them, and the
+//! parser refuses anything malformed rather than panicking
+use vault::record::RECORD_HEADER_LEN;
+use vault::stream::sealed_len;
+use vault::table::{self, ATTR_CHUNKS, ATTR_DIGEST, ChunkRef, MAGIC_LEN, TableEntry, TableErrorKind};
+
+
+/// builds a table from the magic bytes, the entry count and the entries
+fn build(entries: &[Vec<u8>]) -> Vec<u8> {
+    let mut out = vec![0xab; MAGIC_LEN];
+    out.extend_from_slice(&table::encode_u64(entries.len()));
+    for e in entries.iter() {
+        out.extend_from_slice(e);
+    }
+    out
+}
+
+/// encodes an entry the way v0 to v2 tables did, without attributes
+fn plain(name: &[u8], size: u64, offset: u64) -> Vec<u8> {
+    let mut out = name.to_vec();
+    out.push(0);
+    out.extend_from_slice(&size.to_le_bytes());
+    out.extend_from_slice(&offset.to_le_bytes());
+    out
+}
+
+/// encodes a record table entry without attributes
+fn entry(name: &[u8], size: usize, offset: usize) -> Vec<u8> {
+    TableEntry{name: name.to_vec(), size, offset, attrs: Vec::new()}.serialize()
+}
+
+#[test]
+fn encode_u64_is_little_endian() {
+    assert_eq!(table::encode_u64(0x0102_0304), [4, 3, 2, 1, 0, 0, 0, 0]);
+}
+
+#[test]
+fn entry_encodes_little_endian() {
+    let e = TableEntry{name: b"a/b".to_vec(), size: 0x0102, offset: 0x0304, attrs: vec![(ATTR_DIGEST, vec![7u8; 32])]};
+    let mut want: Vec<u8> = b"a/b\0".to_vec();
+    want.extend_from_slice(&[0x02, 0x01, 0, 0, 0, 0, 0, 0]);
+    want.extend_from_slice(&[0x04, 0x03, 0, 0, 0, 0, 0, 0]);
+    want.extend_from_slice(&[37, 0, 0, 0]);
+    want.extend_from_slice(&[ATTR_DIGEST, 32, 0, 0, 0]);
+    want.extend_from_slice(&[7u8; 32]);
+    assert_eq!(e.serialize(), want);
+}
+
+#[test]
+fn chunk_ref_encodes_little_endian() {
+    let c = ChunkRef{offset: 0x0102, stored: 0x0304, size: 0x0506, codec: 2, hash: [9u8; 32]};
+    let raw = c.serialize();
+    assert_eq!(&raw[..17], &[0x02, 0x01, 0, 0, 0, 0, 0, 0, 0x04, 0x03, 0, 0, 0x06, 0x05, 0, 0, 2]);
+    assert_eq!(&raw[17..], &[9u8; 32]);
+}
+
+#[test]
+fn plain_table_decodes() {
+    // offsets count from the start of the payload, and the data follows
+    // the table
+    let start = (MAGIC_LEN + 8 + 2 * 20) as u64;
+    let raw = build(&[plain(b"one", 3, start), plain(b"two", 4, start + 3)]);
+    let entries = table::parse_table(&raw, raw.len() + 7).unwrap();
+    assert_eq!(entries, vec![
+        TableEntry{name: b"one".to_vec(), size: 3, offset: start as usize, attrs: Vec::new()},
+        TableEntry{name: b"two".to_vec(), size: 4, offset: start as usize + 3, attrs: Vec::new()}
+    ]);
+}
+
+#[test]
+fn record_table_round_trips() {
+    let record = RECORD_HEADER_LEN + sealed_len(10) as usize;
+    let chunk = ChunkRef{offset: 100 + record, stored: 10, size: 10, codec: 0, hash: [1u8; 32]};
+    let entries = vec![
+        TableEntry{name: b"one".to_vec(), size: 10, offset: 100, attrs: vec![(ATTR_DIGEST, vec![2u8; 32])]},
+        TableEntry{name: b"two".to_vec(), size: 10, offset: 0, attrs: vec![(ATTR_CHUNKS, chunk.serialize().to_vec())]}
+    ];
+    let raw = build(&entries.iter().map(|e| e.serialize()).collect::<Vec<_>>());
+    let parsed = table::parse_record_table(&raw, 100..100 + 2 * record).unwrap();
+    assert_eq!(parsed, entries);
+    assert_eq!(parsed[1].chunk_refs(None).unwrap(), Some(vec![chunk]));
+}
+
+#[test]
+fn record_tables_count_whole_records() {
+    // an entry's data is sealed in a record, which is longer than the data
+    let record = RECORD_HEADER_LEN + sealed_len(10) as usize;
+    let chunked = |name: &[u8], offset: usize| {
+        let chunk = ChunkRef{offset, stored: 10, size: 10, codec: 0, hash: [1u8; 32]};
+        TableEntry{name: name.to_vec(), size: 10, offset: 0, attrs: vec![(ATTR_CHUNKS, chunk.serialize().to_vec())]}.serialize()
+    };
+
+    let raw = build(&[entry(b"one", 10, 100)]);
+    assert!(table::parse_record_table(&raw, 100..100 + record).is_ok());
+    assert_eq!(table::parse_record_table(&raw, 100..110).unwrap_err().kind, TableErrorKind::OutOfBounds);
+
+    let raw = build(&[entry(b"one", 10, 100), entry(b"two", 10, 100 + record - 1)]);
+    assert_eq!(table::parse_record_table(&raw, 0..1000).unwrap_err().kind, TableErrorKind::Overlap(0));
+    let raw = build(&[entry(b"one", 10, 100), entry(b"two", 10, 100 + record)]);
+    assert!(table::parse_record_table(&raw, 0..1000).is_ok());
+
+    // and so is a chunk's
+    let raw = build(&[chunked(b"one", 100)]);
+    assert!(table::parse_record_table(&raw, 100..100 + record).is_ok());
+    assert_eq!(table::parse_record_table(&raw, 100..110).unwrap_err().kind, TableErrorKind::OutOfBounds);
+    let raw = build(&[chunked(b"one", 100), chunked(b"two", 100 + record - 1)]);
+    assert_eq!(table::parse_record_table(&raw, 0..1000).unwrap_err().kind, TableErrorKind::Overlap(0));
+}
+
+#[test]
+fn malformed_tables_are_refused() {
+    let kind = |raw: &[u8], len: usize| table::parse_table(raw, len).unwrap_err().kind;
+
+    // a count that promises more than is there
+    let mut raw = build(&[plain(b"one", 0, 0)]);
+    raw[MAGIC_LEN] = 200;
+    assert_eq!(kind(&raw, raw.len()), TableErrorKind::Truncated);
+
+    let raw = build(&[b"a name that never ends".to_vec()]);
+    assert_eq!(kind(&raw, 100), TableErrorKind::UnterminatedName);
+
+    let raw = build(&[plain(b"same", 0, 0), plain(b"same", 0, 0)]);
+    assert_eq!(kind(&raw, raw.len()), TableErrorKind::Duplicate(0));
+
+    let raw = build(&[plain(b"../up", 0, 0)]);
+    assert_eq!(kind(&raw, raw.len()), TableErrorKind::BadPath);
+
+    // data has to lie after the table, inside the payload
+    let raw = build(&[plain(b"big", 100, 0)]);
+    assert_eq!(kind(&raw, 1000), TableErrorKind::OutOfBounds);
+    let raw = build(&[plain(b"big", 100, 44)]);
+    assert_eq!(kind(&raw, 140), TableErrorKind::OutOfBounds);
+
+    let raw = build(&[plain(b"one", 4, 100), plain(b"two", 4, 102)]);
+    assert_eq!(kind(&raw, 1000), TableErrorKind::Overlap(0));
+
+    // and nothing short of a whole table gets anywhere
+    let raw = build(&[plain(b"one", 3, 0), plain(b"two", 4, 3)]);
+    for len in 0..raw.len() {
+        assert!(table::parse_table(&raw[..len], 1000).is_err());
+    }
+}
+
+#[test]
+fn many_entries_parse() {
+    // duplicates are looked up rather than compared pairwise, so a big
+    // table doesn't take quadratic time to open
+    // empty entries still have to point past the table
+    let names: Vec<String> = (0..50000).map(|i| format!("f{}", i)).collect();
+    let end = (MAGIC_LEN + 8 + names.iter().map(|n| n.len() + 17).sum::<usize>()) as u64;
+    let mut entries: Vec<Vec<u8>> = names.iter().map(|n| plain(n.as_bytes(), 0, end)).collect();
+    let raw = build(&entries);
+    assert_eq!(table::parse_table(&raw, raw.len()).unwrap().len(), 50000);
+
+    entries.push(plain(b"f123", 0, 0));
+    let raw = build(&entries);
+    assert_eq!(table::parse_table(&raw, raw.len()).unwrap_err().kind, TableErrorKind::Duplicate(123));
+}